@@ -0,0 +1,203 @@
+use crate::db::StoredMessage;
+use crate::message::{Message, MessageContent, Role};
+use crate::text;
+
+/// the newest messages that are never summarized, by default
+pub const DEFAULT_KEEP_RECENT: usize = 10;
+
+/// starts the message that stands in for summarized history
+pub const SUMMARY_PREFIX: &str = "summary of the earlier conversation:\n";
+
+pub const SUMMARY_SYSTEM_PROMPT: &str = "you summarize conversations between a user and their \
+    assistant. write a concise summary of the conversation you're given. keep facts about the \
+    user, decisions made, open questions, and anything the assistant said it would do. reply \
+    with the summary only.";
+
+const MAX_TRANSCRIPT_TOOL_CHARS: usize = 500;
+
+/// when the oldest messages of a session get replaced by a summary
+#[derive(Debug, Clone)]
+pub struct HistoryOptions {
+    /// summarize once the session holds more messages than this
+    pub max_messages: Option<usize>,
+    /// summarize once the session's estimated token count is above this
+    pub max_tokens: Option<usize>,
+    /// how many of the newest messages are kept as they are
+    pub keep_recent: usize,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> Self {
+        Self {
+            max_messages: None,
+            max_tokens: None,
+            keep_recent: DEFAULT_KEEP_RECENT,
+        }
+    }
+}
+
+impl HistoryOptions {
+    pub fn needs_summary(&self, messages: &[StoredMessage]) -> bool {
+        let too_many = self.max_messages.is_some_and(|max| messages.len() > max);
+        too_many
+            || self.max_tokens.is_some_and(|max| {
+                estimate_tokens(messages.iter().map(|stored| &stored.message)) > max
+            })
+    }
+}
+
+/// a rough token count, at about four chars per token
+pub fn estimate_tokens<'a>(messages: impl IntoIterator<Item = &'a Message>) -> usize {
+    let chars: usize = messages
+        .into_iter()
+        .flat_map(|message| &message.content)
+        .map(|content| match content {
+            MessageContent::Text { text } => text.chars().count(),
+            MessageContent::ToolUse { name, input, .. } => {
+                name.chars().count() + input.to_string().chars().count()
+            }
+            MessageContent::ToolResult { content, .. } => content.chars().count(),
+            MessageContent::Server(block) => block.to_string().chars().count(),
+        })
+        .sum();
+    chars.div_ceil(4)
+}
+
+/// how many of the oldest messages to summarize, keeping at least
+/// `keep_recent`. what's kept starts at a message the user wrote, so a tool
+/// result is never separated from its tool call.
+pub fn summary_split(messages: &[StoredMessage], keep_recent: usize) -> usize {
+    turn_split(messages.len(), keep_recent, |i| {
+        starts_turn(&messages[i].message)
+    })
+}
+
+/// like `summary_split`, for history that's already loaded
+pub fn trim_split(messages: &[Message], keep_recent: usize) -> usize {
+    turn_split(messages.len(), keep_recent, |i| starts_turn(&messages[i]))
+}
+
+fn turn_split(len: usize, keep_recent: usize, starts_turn: impl Fn(usize) -> bool) -> usize {
+    let mut split = len.saturating_sub(keep_recent);
+    while split < len && !starts_turn(split) {
+        split += 1;
+    }
+    split
+}
+
+fn starts_turn(message: &Message) -> bool {
+    message.role == Role::User
+        && !message
+            .content
+            .iter()
+            .any(|content| matches!(content, MessageContent::ToolResult { .. }))
+}
+
+/// renders messages as plain text for the summarizer
+pub fn transcript<'a>(messages: impl IntoIterator<Item = &'a Message>) -> String {
+    let mut lines = Vec::new();
+    for message in messages {
+        let speaker = match message.role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        for content in &message.content {
+            let line = match content {
+                MessageContent::Text { text } => format!("{speaker}: {text}"),
+                MessageContent::ToolUse { name, input, .. } => {
+                    format!("assistant called {name}: {input}")
+                }
+                MessageContent::ToolResult { content, .. } => format!(
+                    "tool result: {}",
+                    text::safe_prefix(content, MAX_TRANSCRIPT_TOOL_CHARS)
+                ),
+                MessageContent::Server(block) => format!(
+                    "{}: {}",
+                    block["type"].as_str().unwrap_or("server block"),
+                    text::safe_prefix(&block.to_string(), MAX_TRANSCRIPT_TOOL_CHARS)
+                ),
+            };
+            lines.push(line);
+        }
+    }
+    lines.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stored(messages: Vec<Message>) -> Vec<StoredMessage> {
+        messages
+            .into_iter()
+            .enumerate()
+            .map(|(i, message)| StoredMessage {
+                id: i as i64 + 1,
+                message,
+            })
+            .collect()
+    }
+
+    fn tool_round() -> Vec<Message> {
+        vec![
+            Message::assistant_with_content(vec![MessageContent::tool_use(
+                "call_1",
+                "whoami",
+                json!({}),
+            )]),
+            Message::user_with_content(vec![MessageContent::tool_result("call_1", "alex")]),
+        ]
+    }
+
+    #[test]
+    fn test_needs_summary_thresholds() {
+        let messages = stored(vec![
+            Message::user("a".repeat(40)),
+            Message::assistant("ok"),
+        ]);
+
+        assert!(!HistoryOptions::default().needs_summary(&messages));
+        let by_count = HistoryOptions {
+            max_messages: Some(1),
+            ..Default::default()
+        };
+        assert!(by_count.needs_summary(&messages));
+        let by_tokens = HistoryOptions {
+            max_tokens: Some(10),
+            ..Default::default()
+        };
+        assert!(by_tokens.needs_summary(&messages));
+        let under_tokens = HistoryOptions {
+            max_tokens: Some(20),
+            ..Default::default()
+        };
+        assert!(!under_tokens.needs_summary(&messages));
+    }
+
+    #[test]
+    fn test_summary_split_keeps_tool_results_with_their_calls() {
+        let mut messages = vec![Message::user("hi"), Message::assistant("hello")];
+        messages.push(Message::user("who am i?"));
+        messages.extend(tool_round());
+        messages.push(Message::assistant("you're alex"));
+        let messages = stored(messages);
+
+        // keeping 3 would start mid tool round, so the split moves on to
+        // the next message the user wrote, of which there is none
+        assert_eq!(summary_split(&messages, 3), messages.len());
+        assert_eq!(summary_split(&messages, 4), 2);
+        assert_eq!(summary_split(&messages, 10), 0);
+    }
+
+    #[test]
+    fn test_transcript() {
+        let mut messages = vec![Message::user("who am i?")];
+        messages.extend(tool_round());
+
+        assert_eq!(
+            transcript(&messages),
+            "user: who am i?\n\nassistant called whoami: {}\n\ntool result: alex"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+/// how readily a section is dropped when the prompt is over budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+struct Section {
+    header: String,
+    body: String,
+    priority: Priority,
+}
+
+impl Section {
+    fn render(&self) -> String {
+        format!("## {}\n\n{}", self.header, self.body)
+    }
+}
+
+/// assembles the system prompt from a base prompt and memory sections, like
+/// known facts and notes. sections keep the order they were added in.
+pub struct SystemPromptBuilder {
+    base: String,
+    sections: Vec<Section>,
+    max_chars: Option<usize>,
+}
+
+impl SystemPromptBuilder {
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            base: base.into(),
+            sections: Vec::new(),
+            max_chars: None,
+        }
+    }
+
+    /// caps the whole prompt. over budget, sections are dropped lowest
+    /// priority first, and the later one first among equals. the base prompt
+    /// is always kept.
+    pub fn with_max_chars(mut self, max_chars: Option<usize>) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// adds a `## header` section. empty sections are left out.
+    pub fn add_section(
+        &mut self,
+        header: impl Into<String>,
+        body: impl Into<String>,
+        priority: Priority,
+    ) -> &mut Self {
+        let body = body.into();
+        if !body.trim().is_empty() {
+            self.sections.push(Section {
+                header: header.into(),
+                body,
+                priority,
+            });
+        }
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let rendered: Vec<String> = self.sections.iter().map(Section::render).collect();
+        let mut kept = vec![true; rendered.len()];
+
+        if let Some(max_chars) = self.max_chars {
+            // the separator before each section counts too
+            let mut len = self.base.chars().count()
+                + rendered
+                    .iter()
+                    .map(|section| section.chars().count() + 2)
+                    .sum::<usize>();
+            let mut drop_order: Vec<usize> = (0..rendered.len()).collect();
+            drop_order.sort_by_key(|&i| (self.sections[i].priority, std::cmp::Reverse(i)));
+            for i in drop_order {
+                if len <= max_chars {
+                    break;
+                }
+                tracing::debug!(
+                    section = %self.sections[i].header,
+                    "system prompt over budget, dropping section"
+                );
+                kept[i] = false;
+                len -= rendered[i].chars().count() + 2;
+            }
+        }
+
+        let mut prompt = self.base.clone();
+        for (section, kept) in rendered.iter().zip(kept) {
+            if kept {
+                prompt.push_str("\n\n");
+                prompt.push_str(section);
+            }
+        }
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections_appear_in_order() {
+        let mut builder = SystemPromptBuilder::new("you are ava.");
+        builder
+            .add_section("known facts", "- name: alex", Priority::High)
+            .add_section("upcoming reminders", "  ", Priority::Normal)
+            .add_section(
+                "notes for this conversation",
+                "- topic: taxes",
+                Priority::Low,
+            );
+
+        assert_eq!(
+            builder.build(),
+            "you are ava.\n\n## known facts\n\n- name: alex\n\n\
+             ## notes for this conversation\n\n- topic: taxes"
+        );
+    }
+
+    #[test]
+    fn test_budget_drops_low_priority_sections_first() {
+        let mut builder = SystemPromptBuilder::new("base").with_max_chars(Some(60));
+        builder
+            .add_section("notes", "x".repeat(20), Priority::Low)
+            .add_section("facts", "y".repeat(20), Priority::High)
+            .add_section("reminders", "z".repeat(20), Priority::Normal);
+
+        let prompt = builder.build();
+
+        assert_eq!(prompt, format!("base\n\n## facts\n\n{}", "y".repeat(20)));
+        assert!(prompt.chars().count() <= 60);
+    }
+
+    #[test]
+    fn test_budget_keeps_everything_that_fits() {
+        let mut builder = SystemPromptBuilder::new("base").with_max_chars(Some(1000));
+        builder
+            .add_section("notes", "a", Priority::Low)
+            .add_section("facts", "b", Priority::High);
+
+        assert_eq!(builder.build(), "base\n\n## notes\n\na\n\n## facts\n\nb");
+    }
+}
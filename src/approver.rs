@@ -1,54 +1,166 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::sync::{Mutex, oneshot};
+use tokio::time::Instant;
 
+use crate::config::DEFAULT_APPROVAL_TIMEOUT_SECS;
 use crate::db::generate_pattern;
 use crate::error::Error;
-use crate::telegram::{InlineKeyboardButton, InlineKeyboardMarkup, TelegramBot};
-use crate::tool::{ApprovalDecision, Approver, ToolCall, references_sensitive_env};
+use crate::telegram::{
+    HttpTransport, InlineKeyboardButton, InlineKeyboardMarkup, TelegramBot, TelegramTransport,
+};
+use crate::tool::{
+    ApprovalDecision, Approver, EXEC_TOOL_NAME, ToolCall, describe_for_approval,
+    references_sensitive_env,
+};
 
-const APPROVAL_TIMEOUT_SECS: u64 = 300; // 5 minutes
-
-struct PendingApproval {
-    sender: oneshot::Sender<ApprovalDecision>,
-    message_id: i64,
+/// requests waiting on a decision from the user, keyed by nonce.
+/// holds the bookkeeping every interactive approver needs, so a channel only
+/// renders the request and routes the user's answer back to `resolve`.
+/// `M` is whatever the channel wants back on resolve, like a message ID.
+pub struct PendingApprovalStore<D, M = ()> {
+    map: Mutex<HashMap<String, Pending<D, M>>>,
 }
 
-/// shared state for pending approval requests.
-/// keyed by nonce — shared between the polling loop and spawned agent tasks.
-pub struct PendingApprovals {
-    map: Mutex<HashMap<String, PendingApproval>>,
+struct Pending<D, M> {
+    sender: oneshot::Sender<D>,
+    meta: M,
+    inserted_at: Instant,
 }
 
-impl PendingApprovals {
+impl<D, M> PendingApprovalStore<D, M> {
     pub fn new() -> Self {
         Self {
             map: Mutex::new(HashMap::new()),
         }
     }
+
+    /// a fresh nonce, to embed in the UI before the request is inserted
+    pub fn nonce() -> String {
+        format!("{:08x}", rand_u32())
+    }
+
+    /// registers a request. the decision arrives on the returned receiver.
+    pub async fn insert(&self, nonce: String, meta: M) -> oneshot::Receiver<D> {
+        let (sender, receiver) = oneshot::channel();
+        self.map.lock().await.insert(
+            nonce,
+            Pending {
+                sender,
+                meta,
+                inserted_at: Instant::now(),
+            },
+        );
+        receiver
+    }
+
+    /// how many requests are waiting on a decision
+    pub async fn len(&self) -> usize {
+        self.map.lock().await.len()
+    }
+
+    /// drops requests older than `max_age`, whose waiter will see a timeout.
+    /// catches requests whose waiter went away without cleaning up, e.g. a
+    /// cancelled turn. returns how many were dropped.
+    pub async fn clear_expired(&self, max_age: Duration) -> usize {
+        let mut map = self.map.lock().await;
+        let before = map.len();
+        map.retain(|_, pending| pending.inserted_at.elapsed() < max_age);
+        before - map.len()
+    }
+
+    /// hands `decision` to the request waiting under `nonce`. returns the
+    /// request's meta, or `None` if there's no such request, e.g. it expired.
+    pub async fn resolve(&self, nonce: &str, decision: D) -> Option<M> {
+        let pending = self.map.lock().await.remove(nonce)?;
+        // the waiter may have just timed out, which is fine
+        let _ = pending.sender.send(decision);
+        Some(pending.meta)
+    }
+
+    /// hands `decision` to every waiting request, and returns their meta
+    pub async fn resolve_all(&self, decision: D) -> Vec<M>
+    where
+        D: Clone,
+    {
+        let drained: Vec<_> = self.map.lock().await.drain().collect();
+        drained
+            .into_iter()
+            .map(|(_, pending)| {
+                let _ = pending.sender.send(decision.clone());
+                pending.meta
+            })
+            .collect()
+    }
+
+    /// waits up to `timeout` for the decision. a request that times out is
+    /// removed, so a late answer is treated as stale.
+    pub async fn wait(
+        &self,
+        nonce: &str,
+        receiver: oneshot::Receiver<D>,
+        timeout: Duration,
+    ) -> Result<D, Error> {
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(decision)) => Ok(decision),
+            // sender dropped (e.g. bot restart)
+            Ok(Err(_)) => Err(Error::ApprovalTimeout),
+            Err(_) => {
+                self.map.lock().await.remove(nonce);
+                Err(Error::ApprovalTimeout)
+            }
+        }
+    }
 }
 
-pub struct TelegramApprover {
-    bot: Arc<TelegramBot>,
+impl<D, M> Default for PendingApprovalStore<D, M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the telegram message showing an approval request's buttons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApprovalMessage {
+    pub chat_id: i64,
+    pub message_id: i64,
+}
+
+/// pending telegram approvals, remembering the message with the buttons.
+/// shared between the polling loop and spawned agent tasks.
+pub type PendingApprovals = PendingApprovalStore<ApprovalDecision, ApprovalMessage>;
+
+pub struct TelegramApprover<T = HttpTransport> {
+    bot: Arc<TelegramBot<T>>,
     chat_id: i64,
     pending: Arc<PendingApprovals>,
+    timeout: Duration,
 }
 
-impl TelegramApprover {
-    pub fn new(bot: Arc<TelegramBot>, chat_id: i64, pending: Arc<PendingApprovals>) -> Self {
+impl<T: TelegramTransport> TelegramApprover<T> {
+    pub fn new(bot: Arc<TelegramBot<T>>, chat_id: i64, pending: Arc<PendingApprovals>) -> Self {
         Self {
             bot,
             chat_id,
             pending,
+            timeout: Duration::from_secs(DEFAULT_APPROVAL_TIMEOUT_SECS),
         }
     }
 
+    /// how long a request waits for the user before it fails with
+    /// `Error::ApprovalTimeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     /// route a callback query to a pending approval request.
     /// returns true if the callback was handled.
     pub async fn handle_callback(
         pending: &PendingApprovals,
-        bot: &TelegramBot,
+        bot: &TelegramBot<T>,
         callback_query_id: &str,
         data: &str,
         chat_id: i64,
@@ -62,19 +174,6 @@ impl TelegramApprover {
         let nonce = parts[1];
         let action = parts[2];
 
-        let entry = {
-            let mut map = pending.map.lock().await;
-            map.remove(nonce)
-        };
-
-        let Some(approval) = entry else {
-            // stale button press
-            let _ = bot
-                .answer_callback_query(callback_query_id, Some("this approval request has expired"))
-                .await;
-            return true;
-        };
-
         let decision = match action {
             "allow_once" => ApprovalDecision::AllowOnce,
             "allow_always" => {
@@ -100,37 +199,57 @@ impl TelegramApprover {
             ApprovalDecision::AutoApproved => "auto-approved",
         };
 
+        let Some(message) = pending.resolve(nonce, decision).await else {
+            // stale button press
+            let _ = bot
+                .answer_callback_query(callback_query_id, Some("this approval request has expired"))
+                .await;
+            return true;
+        };
+
         // edit the message to show the decision
         let _ = bot
-            .edit_message_text(chat_id, approval.message_id, &format!("-> {decision_text}"))
+            .edit_message_text(chat_id, message.message_id, &format!("-> {decision_text}"))
             .await;
 
         let _ = bot.answer_callback_query(callback_query_id, None).await;
-        let _ = approval.sender.send(decision);
 
         true
     }
+
+    /// denies every pending request, in any chat, and marks their messages.
+    /// returns how many were denied.
+    pub async fn deny_all(pending: &PendingApprovals, bot: &TelegramBot<T>) -> usize {
+        let messages = pending.resolve_all(ApprovalDecision::Deny).await;
+        for message in &messages {
+            if let Err(e) = bot
+                .edit_message_text(message.chat_id, message.message_id, "-> denied (bulk)")
+                .await
+            {
+                tracing::warn!(%e, chat_id = message.chat_id, "failed to mark approval as denied");
+            }
+        }
+        messages.len()
+    }
 }
 
-impl Approver for TelegramApprover {
+impl<T: TelegramTransport> Approver for TelegramApprover<T> {
     async fn request_approval(&self, tool_call: &ToolCall) -> Result<ApprovalDecision, Error> {
-        let command = tool_call
-            .input
-            .get("command")
-            .and_then(|v| v.as_str())
-            .unwrap_or("<unknown command>");
+        // only exec calls carry a command, and only commands get saved rules
+        let command = (tool_call.name == EXEC_TOOL_NAME)
+            .then(|| tool_call.input.get("command").and_then(|v| v.as_str()))
+            .flatten();
 
-        // generate nonce
-        let nonce = format!("{:08x}", rand_u32());
+        let nonce = PendingApprovals::nonce();
 
         // build keyboard
-        let has_sensitive = references_sensitive_env(command);
+        let has_sensitive = command.is_some_and(references_sensitive_env);
         let mut buttons = vec![InlineKeyboardButton {
             text: "allow once".into(),
             callback_data: format!("exec:{nonce}:allow_once"),
         }];
 
-        if !has_sensitive {
+        if command.is_some() && !has_sensitive {
             buttons.push(InlineKeyboardButton {
                 text: "allow always".into(),
                 callback_data: format!("exec:{nonce}:allow_always"),
@@ -146,7 +265,7 @@ impl Approver for TelegramApprover {
             inline_keyboard: vec![buttons],
         };
 
-        let mut text = format!("command: {command}");
+        let mut text = describe_for_approval(tool_call);
         if has_sensitive {
             text.push_str("\n⚠ references sensitive environment variables");
         }
@@ -156,42 +275,19 @@ impl Approver for TelegramApprover {
             .send_message_with_keyboard(self.chat_id, &text, keyboard)
             .await?;
 
-        // create oneshot channel
-        let (tx, rx) = oneshot::channel();
-
-        {
-            let mut map = self.pending.map.lock().await;
-            map.insert(
-                nonce.clone(),
-                PendingApproval {
-                    sender: tx,
-                    message_id,
-                },
-            );
-        }
+        let message = ApprovalMessage {
+            chat_id: self.chat_id,
+            message_id,
+        };
+        let receiver = self.pending.insert(nonce.clone(), message).await;
+        let mut decision = self.pending.wait(&nonce, receiver, self.timeout).await?;
 
-        // await response with timeout
-        match tokio::time::timeout(std::time::Duration::from_secs(APPROVAL_TIMEOUT_SECS), rx).await
-        {
-            Ok(Ok(mut decision)) => {
-                // if allow_always, generate the actual pattern from the command
-                if matches!(decision, ApprovalDecision::AllowAlways { .. }) {
-                    let pattern = generate_pattern(command);
-                    decision = ApprovalDecision::AllowAlways { pattern };
-                }
-                Ok(decision)
-            }
-            Ok(Err(_)) => {
-                // sender dropped (e.g. bot restart)
-                Err(Error::ApprovalTimeout)
-            }
-            Err(_) => {
-                // timeout
-                let mut map = self.pending.map.lock().await;
-                map.remove(&nonce);
-                Err(Error::ApprovalTimeout)
-            }
+        // if allow_always, generate the actual pattern from the command
+        if let (ApprovalDecision::AllowAlways { .. }, Some(command)) = (&decision, command) {
+            let pattern = generate_pattern(command);
+            decision = ApprovalDecision::AllowAlways { pattern };
         }
+        Ok(decision)
     }
 }
 
@@ -204,3 +300,116 @@ fn rand_u32() -> u32 {
     hasher.write_u8(0);
     hasher.finish() as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::mock::MockTransport;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_store_resolves_waiting_request() {
+        let store = Arc::new(PendingApprovalStore::<&str, i64>::new());
+        let nonce = PendingApprovalStore::<&str, i64>::nonce();
+        let receiver = store.insert(nonce.clone(), 7).await;
+
+        let resolver = {
+            let store = Arc::clone(&store);
+            let nonce = nonce.clone();
+            tokio::spawn(async move { store.resolve(&nonce, "yes").await })
+        };
+
+        let decision = store
+            .wait(&nonce, receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(decision, "yes");
+        assert_eq!(resolver.await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_store_resolve_unknown_nonce() {
+        let store = PendingApprovalStore::<&str>::new();
+        assert_eq!(store.resolve("missing", "yes").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_store_timeout_expires_request() {
+        let store = PendingApprovalStore::<&str>::new();
+        let receiver = store.insert("abc".into(), ()).await;
+
+        let result = store.wait("abc", receiver, Duration::from_millis(10)).await;
+
+        assert!(matches!(result, Err(Error::ApprovalTimeout)));
+        // a late answer finds nothing to resolve
+        assert_eq!(store.resolve("abc", "yes").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clear_expired_drops_only_old_requests() {
+        let store = PendingApprovalStore::<&str>::new();
+        let old = store.insert("old".into(), ()).await;
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let _fresh = store.insert("fresh".into(), ()).await;
+        assert_eq!(store.len().await, 2);
+
+        let cleared = store.clear_expired(Duration::from_secs(30)).await;
+
+        assert_eq!(cleared, 1);
+        assert_eq!(store.len().await, 1);
+        // the old request's waiter is told it's gone
+        assert!(old.await.is_err());
+        assert_eq!(store.resolve("old", "yes").await, None);
+        assert_eq!(store.resolve("fresh", "yes").await, Some(()));
+    }
+
+    #[tokio::test]
+    async fn test_deny_all_resolves_every_pending_request() {
+        let bot = TelegramBot::with_transport(MockTransport::new());
+        let pending = PendingApprovals::new();
+        let mut receivers = Vec::new();
+        for (chat_id, message_id) in [(1, 10), (2, 20)] {
+            let message = ApprovalMessage {
+                chat_id,
+                message_id,
+            };
+            receivers.push(pending.insert(PendingApprovals::nonce(), message).await);
+        }
+
+        let denied = TelegramApprover::deny_all(&pending, &bot).await;
+
+        assert_eq!(denied, 2);
+        for receiver in receivers {
+            assert_eq!(receiver.await.unwrap(), ApprovalDecision::Deny);
+        }
+        {
+            let edits = bot.transport().edits.lock().unwrap();
+            assert_eq!(edits.len(), 2);
+            assert!(edits.iter().all(|edit| edit.text == "-> denied (bulk)"));
+        }
+        assert_eq!(TelegramApprover::deny_all(&pending, &bot).await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_approver_times_out_after_configured_timeout() {
+        let bot = Arc::new(TelegramBot::with_transport(MockTransport::new()));
+        let pending = Arc::new(PendingApprovals::new());
+        let approver = TelegramApprover::new(Arc::clone(&bot), 42, Arc::clone(&pending))
+            .with_timeout(Duration::from_secs(30));
+        let call = ToolCall {
+            id: "call_1".into(),
+            name: EXEC_TOOL_NAME.into(),
+            input: json!({"command": "ls"}),
+        };
+
+        let started = tokio::time::Instant::now();
+        let result = approver.request_approval(&call).await;
+
+        assert!(matches!(result, Err(Error::ApprovalTimeout)));
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+        assert!(pending.map.lock().await.is_empty());
+        let sent = bot.transport().sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].has_keyboard);
+    }
+}
@@ -1,12 +1,192 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+
 use crate::channel::Channel;
 use crate::error::Error;
 use crate::message::OutboundMessage;
+use crate::tool::ToolCall;
+
+/// how much the CLI prints, separate from the log level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// nothing, for when only the exit code matters
+    Quiet,
+    /// the reply
+    #[default]
+    Normal,
+    /// the reply, and each tool call as it's made
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: bool) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, true) => Self::Verbose,
+            (false, false) => Self::Normal,
+        }
+    }
+}
+
+pub struct CliChannel<W = io::Stdout> {
+    verbosity: Verbosity,
+    out: Mutex<W>,
+    /// text printed by `stream` since the last round or reply
+    streamed: Mutex<String>,
+}
+
+impl CliChannel {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self::with_writer(verbosity, io::stdout())
+    }
+}
 
-pub struct CliChannel;
+impl<W: Write> CliChannel<W> {
+    pub fn with_writer(verbosity: Verbosity, out: W) -> Self {
+        Self {
+            verbosity,
+            out: Mutex::new(out),
+            streamed: Mutex::new(String::new()),
+        }
+    }
+
+    /// prints a piece of the reply as soon as it arrives. write errors are
+    /// left for `send` to run into.
+    pub fn stream(&self, text: &str) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        let mut out = self.out.lock().unwrap();
+        if write!(out, "{text}").and_then(|_| out.flush()).is_ok() {
+            self.streamed.lock().unwrap().push_str(text);
+        }
+    }
 
-impl Channel for CliChannel {
+    /// the text streamed so far belonged to a tool round rather than the
+    /// reply, so the reply starts on a new line
+    pub fn end_round(&self) {
+        let mut streamed = self.streamed.lock().unwrap();
+        if !streamed.is_empty() {
+            let _ = writeln!(self.out.lock().unwrap());
+            streamed.clear();
+        }
+    }
+}
+
+impl<W: Write> Channel for CliChannel<W> {
     fn send(&self, message: OutboundMessage) -> Result<(), Error> {
-        println!("{}", message.content);
+        if self.verbosity == Verbosity::Quiet {
+            return Ok(());
+        }
+        let streamed = std::mem::take(&mut *self.streamed.lock().unwrap());
+        let mut out = self.out.lock().unwrap();
+        // only what wasn't streamed yet, e.g. a footer added to the reply.
+        // where the reply differs from what was streamed, it goes on a new
+        // line rather than being printed again from the start.
+        let shown = common_prefix_len(&streamed, &message.content);
+        let rest = &message.content[shown..];
+        if shown < streamed.len() {
+            writeln!(out)?;
+        }
+        writeln!(out, "{rest}")?;
         Ok(())
     }
 }
+
+/// the length in bytes of the longest prefix `a` and `b` share, on a char
+/// boundary
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((i, _), _)| i)
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
+
+/// the line --verbose prints for a tool call, e.g.
+/// `> web_search {"query":"rust"}`
+pub fn describe_invocation(call: &ToolCall) -> String {
+    format!("> {} {}", call.name, call.input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reply(channel: &CliChannel<Vec<u8>>) -> String {
+        String::from_utf8(channel.out.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn test_quiet_suppresses_the_reply() {
+        let message = || OutboundMessage {
+            content: "hello".into(),
+        };
+
+        let quiet = CliChannel::with_writer(Verbosity::from_flags(true, false), Vec::new());
+        assert!(quiet.send(message()).is_ok());
+        assert_eq!(reply(&quiet), "");
+
+        let normal = CliChannel::with_writer(Verbosity::default(), Vec::new());
+        normal.send(message()).unwrap();
+        assert_eq!(reply(&normal), "hello\n");
+
+        let quiet = CliChannel::with_writer(Verbosity::Quiet, Vec::new());
+        quiet.stream("hel");
+        quiet.send(message()).unwrap();
+        assert_eq!(reply(&quiet), "");
+
+        let call = ToolCall {
+            id: "call_1".into(),
+            name: "web_search".into(),
+            input: json!({"query": "rust"}),
+        };
+        assert_eq!(
+            describe_invocation(&call),
+            r#"> web_search {"query":"rust"}"#
+        );
+    }
+
+    #[test]
+    fn test_streamed_reply_is_printed_once() {
+        let content = "first line\nsecond line\n\n_ran: exec_";
+        let channel = CliChannel::with_writer(Verbosity::Normal, Vec::new());
+        // a tool round's narration, then the reply in pieces
+        channel.stream("let me check");
+        channel.end_round();
+        for piece in ["first ", "line\nsec", "ond line"] {
+            channel.stream(piece);
+        }
+        channel
+            .send(OutboundMessage {
+                content: content.into(),
+            })
+            .unwrap();
+
+        assert_eq!(reply(&channel), format!("let me check\n{content}\n"));
+
+        // text that doesn't lead up to the reply is followed by all of it
+        let channel = CliChannel::with_writer(Verbosity::Normal, Vec::new());
+        channel.stream("partial");
+        channel
+            .send(OutboundMessage {
+                content: "(no response)".into(),
+            })
+            .unwrap();
+        assert_eq!(reply(&channel), "partial\n(no response)\n");
+
+        // a reply that parts ways with what was streamed isn't repeated
+        let channel = CliChannel::with_writer(Verbosity::Normal, Vec::new());
+        channel.stream("searching…\n\nthe answer");
+        channel
+            .send(OutboundMessage {
+                content: "searching…\nthe answer is 42".into(),
+            })
+            .unwrap();
+        assert_eq!(
+            reply(&channel),
+            "searching…\n\nthe answer\nthe answer is 42\n"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 mod cli;
 pub mod telegram;
 
-pub use cli::CliChannel;
+pub use cli::{CliChannel, Verbosity, describe_invocation};
 
 use crate::error::Error;
 use crate::message::OutboundMessage;
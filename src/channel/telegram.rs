@@ -1,3 +1,16 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::OwnedMutexGuard;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::EditedMessages;
+use crate::text;
+
+/// telegram caps a message at 4096 chars. leaves room for a part header.
+const MAX_OUTPUT_MESSAGE_CHARS: usize = 4000;
+
 /// escape text for telegram HTML mode
 /// escapes <, >, and & characters
 #[allow(dead_code)]
@@ -7,10 +20,269 @@ pub fn escape_html(text: &str) -> String {
         .replace('>', "&gt;")
 }
 
+/// returns true if `text` is the bot command `/{command}`, optionally
+/// addressed to a bot as in `/help@some_bot`
+pub fn is_command(text: &str, command: &str) -> bool {
+    let Some(first) = text.split_whitespace().next() else {
+        return false;
+    };
+    let Some(name) = first.strip_prefix('/') else {
+        return false;
+    };
+    name.split('@').next() == Some(command)
+}
+
+/// the text to run as a turn for an incoming message, or `None` to skip it.
+/// edits are only run under `EditedMessages::Reprocess`, marked so the
+/// model knows it's a correction.
+pub fn turn_text(text: &str, edited: bool, edits: EditedMessages) -> Option<String> {
+    match (edited, edits) {
+        (false, _) => Some(text.to_string()),
+        (true, EditedMessages::Reprocess) => Some(format!("(edited) {text}")),
+        (true, EditedMessages::Ignore) => None,
+    }
+}
+
+/// whether a message sent at `date` was already too old when the bot started
+/// at `started_at`, both in unix seconds. messages sent after the start are
+/// never stale, however long they wait.
+pub fn is_stale(date: i64, started_at: i64, max_age: Option<Duration>) -> bool {
+    max_age.is_some_and(|max_age| date < started_at.saturating_sub(max_age.as_secs() as i64))
+}
+
+/// a command's output as telegram messages, split into numbered parts when
+/// it doesn't fit in one
+pub fn output_messages(output: &str) -> Vec<String> {
+    let output = output.trim_end();
+    let chunks = text::split_chunks(output, MAX_OUTPUT_MESSAGE_CHARS);
+    if chunks.len() == 1 {
+        return vec![format!("command output:\n{output}")];
+    }
+    let total = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("command output ({}/{total}):\n{chunk}", i + 1))
+        .collect()
+}
+
+/// the reply to an edit that won't be picked up
+pub const IGNORED_EDIT_NOTE: &str =
+    "i don't pick up edited messages, send the corrected message again";
+
+/// the reply to `/help`
+pub fn help_text(assistant_name: &str) -> String {
+    format!(
+        "i'm {assistant_name}, your personal assistant. just send me a message.\n\n\
+         i can remember facts about you, search and fetch web pages, and run commands \
+         (commands need your approval first).\n\n\
+         /cancel stops what i'm working on\n\
+         /denyall denies every command waiting for approval\n\
+         /pending shows how many commands are waiting for approval\n\
+         /help shows this message"
+    )
+}
+
+/// per-chat locks so each chat runs at most one agent turn at a time.
+/// different chats don't block each other.
+#[derive(Default)]
+pub struct ChatLocks {
+    locks: Mutex<HashMap<i64, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ChatLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn chat_lock(&self, chat_id: i64) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        Arc::clone(locks.entry(chat_id).or_default())
+    }
+
+    /// take the chat's lock without waiting, if nothing is running
+    pub fn try_lock(&self, chat_id: i64) -> Option<OwnedMutexGuard<()>> {
+        self.chat_lock(chat_id).try_lock_owned().ok()
+    }
+
+    /// wait for the chat's running turn (if any) to finish
+    pub async fn lock(&self, chat_id: i64) -> OwnedMutexGuard<()> {
+        self.chat_lock(chat_id).lock_owned().await
+    }
+}
+
+/// the cancellation token of each chat's running turn, so `/cancel` can stop it
+#[derive(Default)]
+pub struct CancelTokens {
+    tokens: Mutex<HashMap<i64, CancellationToken>>,
+}
+
+impl CancelTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a fresh token for the chat's turn that is about to run
+    pub fn start(&self, chat_id: i64) -> CancellationToken {
+        let token = CancellationToken::new();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.insert(chat_id, token.clone());
+        token
+    }
+
+    /// cancels the chat's running turn. returns false if nothing was running.
+    pub fn cancel(&self, chat_id: i64) -> bool {
+        let tokens = self.tokens.lock().unwrap();
+        match tokens.get(&chat_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn finish(&self, chat_id: i64) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.remove(&chat_id);
+    }
+}
+
+/// tracks the bot's most recent reply per chat, so it can be edited in place
+#[derive(Default)]
+pub struct LastReplies {
+    replies: Mutex<HashMap<i64, (i64, Instant)>>,
+}
+
+impl LastReplies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, chat_id: i64, message_id: i64) {
+        let mut replies = self.replies.lock().unwrap();
+        replies.insert(chat_id, (message_id, Instant::now()));
+    }
+
+    /// returns the last reply's message ID if it was sent less than max_age ago
+    pub fn recent(&self, chat_id: i64, max_age: Duration) -> Option<i64> {
+        let replies = self.replies.lock().unwrap();
+        replies
+            .get(&chat_id)
+            .filter(|(_, sent_at)| sent_at.elapsed() < max_age)
+            .map(|(message_id, _)| *message_id)
+    }
+}
+
+/// identical error replies to a chat are sent at most once per this window
+pub const ERROR_REPLY_WINDOW: Duration = Duration::from_secs(60);
+
+/// the last error sent to each chat, so a provider outage doesn't answer
+/// every message with the same error
+#[derive(Default)]
+pub struct RecentErrors {
+    errors: Mutex<HashMap<i64, SentError>>,
+}
+
+struct SentError {
+    message: String,
+    sent_at: Instant,
+    suppressed: usize,
+}
+
+impl RecentErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the text to send for this error, or `None` if the same error went to
+    /// the chat less than the window ago. errors held back in the meantime
+    /// are counted in the next one that goes out.
+    pub fn reply(&self, chat_id: i64, message: &str) -> Option<String> {
+        self.reply_at(chat_id, message, Instant::now())
+    }
+
+    fn reply_at(&self, chat_id: i64, message: &str, now: Instant) -> Option<String> {
+        let mut errors = self.errors.lock().unwrap();
+        let mut suppressed = 0;
+        if let Some(last) = errors
+            .get_mut(&chat_id)
+            .filter(|last| last.message == message)
+        {
+            if now.duration_since(last.sent_at) < ERROR_REPLY_WINDOW {
+                last.suppressed += 1;
+                return None;
+            }
+            suppressed = last.suppressed;
+        }
+        errors.insert(
+            chat_id,
+            SentError {
+                message: message.to_string(),
+                sent_at: now,
+                suppressed: 0,
+            },
+        );
+        Some(match suppressed {
+            0 => message.to_string(),
+            n => format!("{message} (repeated {n}x)"),
+        })
+    }
+
+    /// forgets the chat's last error, e.g. once a turn succeeds again
+    pub fn clear(&self, chat_id: i64) {
+        let mut errors = self.errors.lock().unwrap();
+        errors.remove(&chat_id);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_turn_text_for_edits() {
+        assert_eq!(
+            turn_text("hi", false, EditedMessages::Ignore).as_deref(),
+            Some("hi")
+        );
+        assert_eq!(turn_text("hi", true, EditedMessages::Ignore), None);
+        assert_eq!(
+            turn_text("hi", true, EditedMessages::Reprocess).as_deref(),
+            Some("(edited) hi")
+        );
+    }
+
+    #[test]
+    fn test_is_command() {
+        assert!(is_command("/help", "help"));
+        assert!(is_command("/help@ava_bot", "help"));
+        assert!(is_command("  /help me", "help"));
+        assert!(!is_command("help", "help"));
+        assert!(!is_command("/helpme", "help"));
+        assert!(!is_command("", "help"));
+    }
+
+    #[test]
+    fn test_help_text_uses_assistant_name() {
+        assert!(help_text("nova").starts_with("i'm nova,"));
+    }
+
+    #[test]
+    fn test_output_messages_split_long_output() {
+        let output: String = (0..1200).map(|i| format!("line {i:04}\n")).collect();
+        assert_eq!(output.chars().count(), 12_000);
+
+        let messages = output_messages(&output);
+
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with("command output (1/3):\nline 0000\n"));
+        assert!(messages[1].starts_with("command output (2/3):\nline 0400\n"));
+        assert!(messages[2].ends_with("line 1199"));
+        assert!(messages.iter().all(|m| m.chars().count() <= 4096));
+        assert_eq!(output_messages("ok\n"), vec!["command output:\nok"]);
+    }
+
     #[test]
     fn test_escape_html() {
         assert_eq!(escape_html("hello"), "hello");
@@ -18,4 +290,129 @@ mod tests {
         assert_eq!(escape_html("a & b"), "a &amp; b");
         assert_eq!(escape_html("1 < 2 > 0"), "1 &lt; 2 &gt; 0");
     }
+
+    #[tokio::test]
+    async fn test_chat_locks_serialize_same_chat() {
+        let locks = Arc::new(ChatLocks::new());
+        let first = locks.lock(1).await;
+
+        assert!(locks.try_lock(1).is_none());
+
+        let waiter = {
+            let locks = Arc::clone(&locks);
+            tokio::spawn(async move {
+                let _guard = locks.lock(1).await;
+            })
+        };
+
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        waiter.await.unwrap();
+        assert!(locks.try_lock(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_chat_locks_different_chats_run_in_parallel() {
+        let locks = ChatLocks::new();
+        let _first = locks.lock(1).await;
+
+        assert!(locks.try_lock(2).is_some());
+    }
+
+    #[test]
+    fn test_last_replies_tracks_latest_per_chat() {
+        let replies = LastReplies::new();
+        let window = Duration::from_secs(60);
+
+        assert_eq!(replies.recent(1, window), None);
+
+        replies.record(1, 100);
+        replies.record(1, 101);
+        replies.record(2, 200);
+
+        assert_eq!(replies.recent(1, window), Some(101));
+        assert_eq!(replies.recent(2, window), Some(200));
+    }
+
+    #[test]
+    fn test_last_replies_ignores_old_replies() {
+        let replies = LastReplies::new();
+        replies.record(1, 100);
+
+        assert_eq!(replies.recent(1, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_cancel_tokens() {
+        let tokens = CancelTokens::new();
+        assert!(!tokens.cancel(1));
+
+        let token = tokens.start(1);
+        let other = tokens.start(2);
+        assert!(tokens.cancel(1));
+        assert!(token.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        tokens.finish(1);
+        assert!(!tokens.cancel(1));
+    }
+
+    #[test]
+    fn test_backlog_older_than_max_age_is_stale() {
+        let started_at = 1_700_000_000;
+        let max_age = Some(Duration::from_secs(300));
+
+        assert!(is_stale(started_at - 301, started_at, max_age));
+        assert!(!is_stale(started_at - 299, started_at, max_age));
+        assert!(!is_stale(started_at + 60, started_at, max_age));
+        assert!(!is_stale(0, started_at, None));
+    }
+
+    #[test]
+    fn test_identical_errors_are_debounced() {
+        let errors = RecentErrors::new();
+        let start = Instant::now();
+
+        assert_eq!(
+            errors.reply_at(1, "provider down", start).as_deref(),
+            Some("provider down")
+        );
+        assert_eq!(
+            errors.reply_at(1, "provider down", start + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(
+            errors.reply_at(1, "provider down", start + Duration::from_secs(30)),
+            None
+        );
+        // other chats and other errors aren't held back
+        assert!(errors.reply_at(2, "provider down", start).is_some());
+        assert!(
+            errors
+                .reply_at(1, "rate limited", start + Duration::from_secs(31))
+                .is_some()
+        );
+
+        let later = start + Duration::from_secs(31) + ERROR_REPLY_WINDOW;
+        assert!(errors.reply_at(1, "provider down", later).is_some());
+        assert_eq!(
+            errors.reply_at(1, "provider down", later + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            errors
+                .reply_at(1, "provider down", later + ERROR_REPLY_WINDOW)
+                .as_deref(),
+            Some("provider down (repeated 1x)")
+        );
+
+        errors.clear(1);
+        assert!(
+            errors
+                .reply_at(1, "provider down", later + ERROR_REPLY_WINDOW)
+                .is_some()
+        );
+    }
 }
@@ -0,0 +1,51 @@
+use clap::Command;
+pub use clap_complete::Shell;
+
+/// a completion script for `shell`, generated from the command itself so it
+/// covers every subcommand and flag
+pub fn generate(shell: Shell, mut command: Command) -> String {
+    let name = command.get_name().to_string();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn command() -> Command {
+        Command::new("ava")
+            .subcommand(Command::new("version").about("show version info"))
+            .subcommand(
+                Command::new("message").about("send a message").arg(
+                    Arg::new("no_tools")
+                        .long("no-tools")
+                        .action(ArgAction::SetTrue)
+                        .help("answer without any tools"),
+                ),
+            )
+            .subcommand(
+                Command::new("facts")
+                    .about("inspect stored facts")
+                    .subcommand(Command::new("list").about("list facts")),
+            )
+    }
+
+    #[test]
+    fn test_completions_for_each_shell() {
+        let bash = generate(Shell::Bash, command());
+        assert!(bash.contains("complete -F _ava"));
+        assert!(bash.contains("ava__subcmd__facts__subcmd__list"));
+        assert!(bash.contains("--no-tools"));
+
+        let zsh = generate(Shell::Zsh, command());
+        assert!(zsh.starts_with("#compdef ava"));
+        assert!(zsh.contains("--no-tools[answer without any tools]"));
+
+        let fish = generate(Shell::Fish, command());
+        assert!(fish.contains("-l no-tools -d 'answer without any tools'"));
+        assert!(fish.contains("-a \"message\" -d 'send a message'"));
+    }
+}
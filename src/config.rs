@@ -1,4 +1,335 @@
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::db::Fact;
+use crate::error::Error;
+use crate::message::ChannelKind;
+use crate::tool::tool_definitions;
+
+pub const DEFAULT_ASSISTANT_NAME: &str = "ava";
+pub const DEFAULT_MODEL: &str = "claude-sonnet-4-5";
+pub const DEFAULT_MAX_TOKENS: u32 = 8192;
+/// default cap on characters returned by a tool to the model
+pub const DEFAULT_MAX_TOOL_OUTPUT: usize = 4000;
+/// default number of exec commands that may run at once
+pub const DEFAULT_MAX_CONCURRENT_EXEC: usize = 2;
+/// default caps on the length of a stored fact's fields, in chars
+pub const DEFAULT_MAX_FACT_CATEGORY_CHARS: usize = 64;
+pub const DEFAULT_MAX_FACT_KEY_CHARS: usize = 128;
+pub const DEFAULT_MAX_FACT_VALUE_CHARS: usize = 2000;
+/// default time to wait for the user to approve a tool call
+pub const DEFAULT_APPROVAL_TIMEOUT_SECS: u64 = 300;
+/// default time a whole turn may take, approvals included
+pub const DEFAULT_TURN_TIMEOUT_SECS: u64 = 600;
+/// default time a tool call may take before it's abandoned
+pub const DEFAULT_TOOL_TIMEOUT_SECS: u64 = 60;
+/// default age past which telegram messages waiting at startup are dropped
+pub const DEFAULT_TELEGRAM_STARTUP_MAX_AGE_SECS: u64 = 300;
+/// jina's hosted reader, which web_fetch goes through by default
+pub const DEFAULT_JINA_BASE_URL: &str = "https://r.jina.ai/";
+/// the embedding model asked for when AVA_EMBEDDING_MODEL is unset
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+/// the context window assumed for models missing from the table below
+pub const DEFAULT_CONTEXT_WINDOW: usize = 32_000;
+/// context windows in tokens, by model name prefix. the longest matching
+/// prefix wins.
+const MODEL_CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("claude-", 200_000),
+    ("claude-2.0", 100_000),
+    ("claude-instant", 100_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-3.5-turbo", 16_385),
+];
+/// how much of the context window history may fill before it's summarized,
+/// leaving room for the system prompt, tools and the reply
+const HISTORY_SHARE_PERCENT: usize = 50;
+/// default minutes of quiet after which a chat starts a new session
+pub const DEFAULT_SESSION_IDLE_MINUTES: u64 = 30;
+
+/// the effective configuration, resolved from env vars and defaults.
+/// secrets are only recorded as present or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub assistant_name: String,
+    pub response_language: Option<String>,
+    pub model: String,
+    pub max_tokens: u32,
+    pub anthropic_beta: Vec<String>,
+    pub context_window: usize,
+    pub data_dir: PathBuf,
+    pub db_path: PathBuf,
+    pub timezone: Option<String>,
+    pub enabled_tools: Vec<String>,
+    pub cli_tools: Option<Vec<String>>,
+    pub telegram_tools: Option<Vec<String>>,
+    pub max_tool_output: usize,
+    pub max_concurrent_exec: usize,
+    pub approval_timeout: Duration,
+    pub turn_timeout: Option<Duration>,
+    pub tool_timeouts: ToolTimeouts,
+    pub telegram_startup_max_age: Option<Duration>,
+    pub telegram_edit_last: bool,
+    pub telegram_progress: bool,
+    pub telegram_exec_output: bool,
+    pub show_actions: bool,
+    pub telegram_edits: EditedMessages,
+    pub telegram_parse_mode: TelegramParseMode,
+    pub confirm_memory: bool,
+    pub safe_mode: bool,
+    pub max_facts: Option<usize>,
+    pub fact_limits: FactLimits,
+    pub fact_category_priority: Vec<String>,
+    pub fact_prompt_max_chars: Option<usize>,
+    pub system_prompt_max_chars: Option<usize>,
+    pub session_idle_timeout: Option<Duration>,
+    pub summarize_after_messages: Option<usize>,
+    pub summarize_after_tokens: Option<usize>,
+    pub store_fetches: bool,
+    pub collapse_repeats: bool,
+    pub search_highlight: bool,
+    pub fetch_route: FetchRoute,
+    pub embeddings: Option<EmbeddingSettings>,
+    pub tool_trace_file: Option<PathBuf>,
+    pub system_facts_file: Option<PathBuf>,
+    pub approval_policy_file: Option<PathBuf>,
+    pub sandbox_root: Option<PathBuf>,
+    pub fact_webhook: bool,
+    pub http_proxy: Option<HttpProxy>,
+    pub exec_shell: ExecShell,
+    pub log_level: tracing::Level,
+    pub log_format: LogFormat,
+    pub secrets: Secrets,
+}
+
+/// which secrets are set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secrets {
+    pub anthropic_api_key: bool,
+    pub telegram_token: bool,
+    pub brave_search_api_key: bool,
+    pub jina_api_key: bool,
+    pub embedding_api_key: bool,
+}
+
+impl Config {
+    pub fn resolve() -> Self {
+        let db_path = default_db_path();
+        let data_dir = match db_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        Self {
+            assistant_name: assistant_name(),
+            response_language: response_language(),
+            model: model(),
+            max_tokens: max_tokens(),
+            anthropic_beta: anthropic_beta(),
+            context_window: context_window(),
+            data_dir,
+            db_path,
+            timezone: non_empty_env("TZ"),
+            enabled_tools: tool_definitions()
+                .iter()
+                .map(|def| def.name.to_string())
+                .collect(),
+            cli_tools: channel_tools(ChannelKind::Cli),
+            telegram_tools: channel_tools(ChannelKind::Telegram),
+            max_tool_output: max_tool_output(),
+            max_concurrent_exec: max_concurrent_exec(),
+            approval_timeout: approval_timeout(),
+            turn_timeout: turn_timeout(),
+            tool_timeouts: tool_timeouts(),
+            telegram_startup_max_age: telegram_startup_max_age(),
+            telegram_edit_last: telegram_edit_last(),
+            telegram_progress: telegram_progress(),
+            telegram_exec_output: telegram_exec_output(),
+            show_actions: show_actions(),
+            telegram_edits: telegram_edits(),
+            telegram_parse_mode: telegram_parse_mode(),
+            confirm_memory: confirm_memory(),
+            safe_mode: safe_mode(),
+            max_facts: max_facts(),
+            fact_limits: fact_limits(),
+            fact_category_priority: fact_category_priority(),
+            fact_prompt_max_chars: fact_prompt_max_chars(),
+            system_prompt_max_chars: system_prompt_max_chars(),
+            session_idle_timeout: session_idle_timeout(),
+            summarize_after_messages: summarize_after_messages(),
+            summarize_after_tokens: summarize_after_tokens(),
+            store_fetches: store_fetches(),
+            collapse_repeats: collapse_repeats(),
+            search_highlight: search_highlight(),
+            fetch_route: fetch_route(),
+            embeddings: embedding_settings(),
+            tool_trace_file: tool_trace_file(),
+            system_facts_file: system_facts_file(),
+            approval_policy_file: approval_policy_file(),
+            sandbox_root: sandbox_root(),
+            fact_webhook: fact_webhook().is_some(),
+            http_proxy: http_proxy(),
+            exec_shell: exec_shell(),
+            log_level: log_level(),
+            log_format: log_format(),
+            secrets: Secrets {
+                anthropic_api_key: non_empty_env("ANTHROPIC_API_KEY").is_some()
+                    || non_empty_env("ANTHROPIC_API_KEYS").is_some(),
+                telegram_token: non_empty_env("TELOXIDE_TOKEN").is_some(),
+                brave_search_api_key: non_empty_env("BRAVE_SEARCH_API_KEY").is_some(),
+                jina_api_key: non_empty_env("JINA_API_KEY").is_some(),
+                embedding_api_key: non_empty_env("AVA_EMBEDDING_API_KEY").is_some(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let present = |set: bool| if set { "set" } else { "not set" };
+
+        writeln!(f, "assistant name: {}", self.assistant_name)?;
+        writeln!(
+            f,
+            "response language: {}",
+            self.response_language
+                .as_deref()
+                .unwrap_or("whatever the model picks")
+        )?;
+        writeln!(f, "model: {}", self.model)?;
+        writeln!(f, "max_tokens: {}", self.max_tokens)?;
+        writeln!(f, "context window: {} tokens", self.context_window)?;
+        if self.anthropic_beta.is_empty() {
+            writeln!(f, "anthropic beta: none")?;
+        } else {
+            writeln!(f, "anthropic beta: {}", self.anthropic_beta.join(", "))?;
+        }
+        writeln!(f, "data dir: {}", self.data_dir.display())?;
+        writeln!(f, "db: {}", self.db_path.display())?;
+        writeln!(
+            f,
+            "timezone: {}",
+            self.timezone.as_deref().unwrap_or("system default")
+        )?;
+        writeln!(f, "enabled tools: {}", self.enabled_tools.join(", "))?;
+        let allowed = |tools: &Option<Vec<String>>| match tools {
+            Some(tools) => tools.join(", "),
+            None => "all".to_string(),
+        };
+        writeln!(f, "cli tools: {}", allowed(&self.cli_tools))?;
+        writeln!(f, "telegram tools: {}", allowed(&self.telegram_tools))?;
+        writeln!(f, "max tool output: {} chars", self.max_tool_output)?;
+        writeln!(f, "max concurrent exec: {}", self.max_concurrent_exec)?;
+        writeln!(f, "approval timeout: {}s", self.approval_timeout.as_secs())?;
+        match self.turn_timeout {
+            Some(timeout) => writeln!(f, "turn timeout: {}s", timeout.as_secs())?,
+            None => writeln!(f, "turn timeout: none")?,
+        }
+        writeln!(f, "tool timeout: {}", self.tool_timeouts)?;
+        match self.telegram_startup_max_age {
+            Some(age) => writeln!(f, "telegram startup max age: {}s", age.as_secs())?,
+            None => writeln!(f, "telegram startup max age: none")?,
+        }
+        writeln!(f, "telegram edit last: {}", self.telegram_edit_last)?;
+        writeln!(f, "telegram progress: {}", self.telegram_progress)?;
+        writeln!(f, "telegram exec output: {}", self.telegram_exec_output)?;
+        writeln!(f, "show actions: {}", self.show_actions)?;
+        writeln!(f, "telegram edits: {}", self.telegram_edits)?;
+        writeln!(f, "telegram parse mode: {}", self.telegram_parse_mode)?;
+        writeln!(f, "confirm memory: {}", self.confirm_memory)?;
+        writeln!(f, "safe mode: {}", self.safe_mode)?;
+        match self.max_facts {
+            Some(max) => writeln!(f, "max facts: {max}")?,
+            None => writeln!(f, "max facts: unlimited")?,
+        }
+        writeln!(f, "fact limits: {}", self.fact_limits)?;
+        writeln!(
+            f,
+            "fact category priority: {}",
+            self.fact_category_priority.join(", ")
+        )?;
+        match self.fact_prompt_max_chars {
+            Some(max) => writeln!(f, "fact prompt budget: {max} chars")?,
+            None => writeln!(f, "fact prompt budget: unlimited")?,
+        }
+        match self.system_prompt_max_chars {
+            Some(max) => writeln!(f, "system prompt budget: {max} chars")?,
+            None => writeln!(f, "system prompt budget: unlimited")?,
+        }
+        match self.session_idle_timeout {
+            Some(idle) => writeln!(f, "new session after: {} idle minutes", idle.as_secs() / 60)?,
+            None => writeln!(f, "new session after: never")?,
+        }
+        match self.summarize_after_messages {
+            Some(max) => writeln!(f, "summarize history after: {max} messages")?,
+            None => writeln!(f, "summarize history after: never (by message count)")?,
+        }
+        match self.summarize_after_tokens {
+            Some(max) => writeln!(f, "summarize history after: ~{max} tokens")?,
+            None => writeln!(f, "summarize history after: never (by tokens)")?,
+        }
+        writeln!(f, "store fetches: {}", self.store_fetches)?;
+        writeln!(f, "collapse repeated lines: {}", self.collapse_repeats)?;
+        writeln!(f, "search highlight: {}", self.search_highlight)?;
+        writeln!(f, "web fetch: {}", self.fetch_route)?;
+        match &self.embeddings {
+            Some(settings) => writeln!(f, "embeddings: {} at {}", settings.model, settings.url)?,
+            None => writeln!(f, "embeddings: off")?,
+        }
+        match &self.tool_trace_file {
+            Some(path) => writeln!(f, "tool trace file: {}", path.display())?,
+            None => writeln!(f, "tool trace file: off")?,
+        }
+        match &self.system_facts_file {
+            Some(path) => writeln!(f, "system facts file: {}", path.display())?,
+            None => writeln!(f, "system facts file: none")?,
+        }
+        match &self.approval_policy_file {
+            Some(path) => writeln!(f, "approval policy file: {}", path.display())?,
+            None => writeln!(f, "approval policy file: none")?,
+        }
+        match &self.sandbox_root {
+            Some(path) => writeln!(f, "sandbox root: {}", path.display())?,
+            None => writeln!(f, "sandbox root: none (search_files off)")?,
+        }
+        // the URL may carry a token, so it isn't shown
+        writeln!(
+            f,
+            "fact webhook: {}",
+            if self.fact_webhook { "on" } else { "off" }
+        )?;
+        match &self.http_proxy {
+            Some(proxy) => writeln!(f, "http proxy: {proxy}")?,
+            None => writeln!(f, "http proxy: none")?,
+        }
+        writeln!(f, "exec shell: {}", self.exec_shell)?;
+        writeln!(f, "log level: {}", self.log_level.as_str().to_lowercase())?;
+        writeln!(f, "log format: {}", self.log_format)?;
+        writeln!(
+            f,
+            "ANTHROPIC_API_KEY: {}",
+            present(self.secrets.anthropic_api_key)
+        )?;
+        writeln!(
+            f,
+            "TELOXIDE_TOKEN: {}",
+            present(self.secrets.telegram_token)
+        )?;
+        writeln!(
+            f,
+            "BRAVE_SEARCH_API_KEY: {}",
+            present(self.secrets.brave_search_api_key)
+        )?;
+        writeln!(f, "JINA_API_KEY: {}", present(self.secrets.jina_api_key))?;
+        write!(
+            f,
+            "AVA_EMBEDDING_API_KEY: {}",
+            present(self.secrets.embedding_api_key)
+        )
+    }
+}
 
 /// returns path to the sqlite database.
 /// defaults to ./ava.db in the current directory.
@@ -10,6 +341,784 @@ pub fn default_db_path() -> PathBuf {
     PathBuf::from("ava.db")
 }
 
+/// returns the name the assistant goes by.
+/// override with AVA_ASSISTANT_NAME env var.
+pub fn assistant_name() -> String {
+    non_empty_env("AVA_ASSISTANT_NAME")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|| DEFAULT_ASSISTANT_NAME.to_string())
+}
+
+/// returns the language replies should be in, if one is set: a language
+/// like `dutch`, or `auto` to answer in the language the user wrote in.
+/// set with AVA_RESPONSE_LANGUAGE.
+pub fn response_language() -> Option<String> {
+    non_empty_env("AVA_RESPONSE_LANGUAGE").map(|language| language.trim().to_string())
+}
+
+/// returns the anthropic model to use.
+/// override with AVA_MODEL env var.
+pub fn model() -> String {
+    non_empty_env("AVA_MODEL").unwrap_or_else(|| DEFAULT_MODEL.to_string())
+}
+
+/// returns the beta features to opt into on the anthropic API, sent as the
+/// `anthropic-beta` header. set with ANTHROPIC_BETA, comma-separated.
+pub fn anthropic_beta() -> Vec<String> {
+    non_empty_env("ANTHROPIC_BETA")
+        .map(|v| {
+            v.split(',')
+                .map(|flag| flag.trim().to_string())
+                .filter(|flag| !flag.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// returns the context window of `model` in tokens, from a table of known
+/// model name prefixes
+pub fn context_window_for(model: &str) -> usize {
+    MODEL_CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|&(_, window)| window)
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+/// returns the context window of the configured model in tokens. override
+/// with AVA_CONTEXT_WINDOW for models the table doesn't know.
+pub fn context_window() -> usize {
+    non_empty_env("AVA_CONTEXT_WINDOW")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| context_window_for(&model()))
+}
+
+/// returns the max tokens per completion.
+/// override with AVA_MAX_TOKENS env var.
+pub fn max_tokens() -> u32 {
+    non_empty_env("AVA_MAX_TOKENS")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_TOKENS)
+}
+
+/// returns the default cap on tool output characters.
+/// override with AVA_MAX_TOOL_OUTPUT env var.
+pub fn max_tool_output() -> usize {
+    non_empty_env("AVA_MAX_TOOL_OUTPUT")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_TOOL_OUTPUT)
+}
+
+/// returns how many exec commands may run at once. the rest wait their turn.
+/// override with AVA_MAX_CONCURRENT_EXEC, 2 by default.
+pub fn max_concurrent_exec() -> usize {
+    non_empty_env("AVA_MAX_CONCURRENT_EXEC")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_EXEC)
+}
+
+/// returns how long an approval request waits for the user.
+/// set in seconds with AVA_APPROVAL_TIMEOUT, 300 by default.
+pub fn approval_timeout() -> Duration {
+    let secs = non_empty_env("AVA_APPROVAL_TIMEOUT")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_APPROVAL_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// returns how long a whole turn may run, waiting for approvals included,
+/// before it's abandoned. set in seconds with AVA_TURN_TIMEOUT, 600 by
+/// default, 0 turns it off.
+pub fn turn_timeout() -> Option<Duration> {
+    let secs = non_empty_env("AVA_TURN_TIMEOUT")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_TURN_TIMEOUT_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// how long a tool call may take before its result is replaced by a timeout
+/// note. exec isn't covered, it has a timeout per command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolTimeouts {
+    pub default: Duration,
+    /// by tool name, in the order they were configured
+    pub overrides: Vec<(String, Duration)>,
+}
+
+impl Default for ToolTimeouts {
+    fn default() -> Self {
+        Self {
+            default: Duration::from_secs(DEFAULT_TOOL_TIMEOUT_SECS),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl ToolTimeouts {
+    pub fn for_tool(&self, name: &str) -> Duration {
+        self.overrides
+            .iter()
+            .find(|(tool, _)| tool == name)
+            .map_or(self.default, |(_, timeout)| *timeout)
+    }
+}
+
+impl fmt::Display for ToolTimeouts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}s", self.default.as_secs())?;
+        for (tool, timeout) in &self.overrides {
+            write!(f, ", {tool} {}s", timeout.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// returns how long tool calls may take. set in seconds with
+/// AVA_TOOL_TIMEOUT, 60 by default, and per tool with AVA_TOOL_TIMEOUTS,
+/// e.g. `web_fetch=20,web_search=10`. entries that don't parse are skipped.
+pub fn tool_timeouts() -> ToolTimeouts {
+    let secs = |value: &str| {
+        value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&n: &u64| n > 0)
+            .map(Duration::from_secs)
+    };
+    let default = non_empty_env("AVA_TOOL_TIMEOUT")
+        .and_then(|v| secs(&v))
+        .unwrap_or(Duration::from_secs(DEFAULT_TOOL_TIMEOUT_SECS));
+    let overrides = non_empty_env("AVA_TOOL_TIMEOUTS")
+        .map(|v| {
+            v.split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .filter_map(|entry| {
+                    let parsed = entry
+                        .split_once('=')
+                        .and_then(|(tool, value)| Some((tool.trim().to_string(), secs(value)?)));
+                    if parsed.is_none() {
+                        tracing::warn!(entry, "skipping invalid AVA_TOOL_TIMEOUTS entry");
+                    }
+                    parsed
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ToolTimeouts { default, overrides }
+}
+
+/// returns how old a message that was waiting when the bot started may be and
+/// still get a turn. older ones are dropped, so a backlog from while the bot
+/// was offline doesn't run all at once. set in seconds with
+/// AVA_TELEGRAM_STARTUP_MAX_AGE, 300 by default, 0 keeps everything.
+pub fn telegram_startup_max_age() -> Option<Duration> {
+    let secs = non_empty_env("AVA_TELEGRAM_STARTUP_MAX_AGE")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_TELEGRAM_STARTUP_MAX_AGE_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// when set, a reply to a chat edits the bot's previous message if it's recent,
+/// instead of sending a new one. enable with AVA_TELEGRAM_EDIT_LAST=1.
+pub fn telegram_edit_last() -> bool {
+    env_flag("AVA_TELEGRAM_EDIT_LAST")
+}
+
+/// when set, tools that change anything (exec, remembering facts) are
+/// turned off, leaving only read-only ones. enable with AVA_SAFE_MODE=1.
+pub fn safe_mode() -> bool {
+    env_flag("AVA_SAFE_MODE")
+}
+
+/// when set, telegram users see a message saying which tools are running
+/// while a turn is in progress. enable with AVA_TELEGRAM_PROGRESS=1.
+pub fn telegram_progress() -> bool {
+    env_flag("AVA_TELEGRAM_PROGRESS")
+}
+
+/// when set, telegram users are sent the full output of each command exec
+/// runs, split over several messages if needed, while the model still sees a
+/// truncated copy. enable with AVA_TELEGRAM_EXEC_OUTPUT=1.
+pub fn telegram_exec_output() -> bool {
+    env_flag("AVA_TELEGRAM_EXEC_OUTPUT")
+}
+
+/// when set, replies end with a short footer listing what the turn changed,
+/// like facts remembered and commands run. enable with AVA_SHOW_ACTIONS=1.
+pub fn show_actions() -> bool {
+    env_flag("AVA_SHOW_ACTIONS")
+}
+
+/// when set, storing a fact needs the user's approval, like exec does.
+/// enable with AVA_CONFIRM_MEMORY=1.
+pub fn confirm_memory() -> bool {
+    env_flag("AVA_CONFIRM_MEMORY")
+}
+
+/// returns the cap on stored facts, if any.
+/// set with AVA_MAX_FACTS, unlimited by default.
+pub fn max_facts() -> Option<usize> {
+    non_empty_env("AVA_MAX_FACTS")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// the longest category, key and value a stored fact may have, in chars
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FactLimits {
+    pub category: usize,
+    pub key: usize,
+    pub value: usize,
+}
+
+impl Default for FactLimits {
+    fn default() -> Self {
+        Self {
+            category: DEFAULT_MAX_FACT_CATEGORY_CHARS,
+            key: DEFAULT_MAX_FACT_KEY_CHARS,
+            value: DEFAULT_MAX_FACT_VALUE_CHARS,
+        }
+    }
+}
+
+impl fmt::Display for FactLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "category {}, key {}, value {} chars",
+            self.category, self.key, self.value
+        )
+    }
+}
+
+/// returns how long a fact's fields may be. set with
+/// AVA_MAX_FACT_CATEGORY_CHARS, AVA_MAX_FACT_KEY_CHARS and
+/// AVA_MAX_FACT_VALUE_CHARS.
+pub fn fact_limits() -> FactLimits {
+    let limit = |name: &str| {
+        non_empty_env(name)
+            .and_then(|v| v.trim().parse().ok())
+            .filter(|&n: &usize| n > 0)
+    };
+    let defaults = FactLimits::default();
+    FactLimits {
+        category: limit("AVA_MAX_FACT_CATEGORY_CHARS").unwrap_or(defaults.category),
+        key: limit("AVA_MAX_FACT_KEY_CHARS").unwrap_or(defaults.key),
+        value: limit("AVA_MAX_FACT_VALUE_CHARS").unwrap_or(defaults.value),
+    }
+}
+
+/// what to do when a telegram user edits a message they already sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EditedMessages {
+    /// reply that edits aren't picked up
+    #[default]
+    Ignore,
+    /// run the edited text as a new turn
+    Reprocess,
+}
+
+impl fmt::Display for EditedMessages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ignore => write!(f, "ignore"),
+            Self::Reprocess => write!(f, "reprocess"),
+        }
+    }
+}
+
+/// returns how edited telegram messages are handled.
+/// set AVA_TELEGRAM_EDITS=reprocess to treat edits as new messages.
+pub fn telegram_edits() -> EditedMessages {
+    match non_empty_env("AVA_TELEGRAM_EDITS")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("reprocess") => EditedMessages::Reprocess,
+        _ => EditedMessages::Ignore,
+    }
+}
+
+/// how telegram formats the replies the bot sends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TelegramParseMode {
+    /// HTML tags, falling back to plain text if telegram rejects them
+    #[default]
+    Html,
+    /// telegram's legacy markdown, with the same fallback
+    Markdown,
+    /// plain text, sent once as is
+    Plain,
+}
+
+impl TelegramParseMode {
+    /// the `parse_mode` telegram expects, `None` for plain text
+    pub fn api_value(self) -> Option<&'static str> {
+        match self {
+            Self::Html => Some("HTML"),
+            Self::Markdown => Some("Markdown"),
+            Self::Plain => None,
+        }
+    }
+}
+
+impl fmt::Display for TelegramParseMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Html => write!(f, "html"),
+            Self::Markdown => write!(f, "markdown"),
+            Self::Plain => write!(f, "none"),
+        }
+    }
+}
+
+/// returns how telegram replies are formatted. set AVA_TELEGRAM_PARSE_MODE
+/// to html (the default), markdown or none.
+pub fn telegram_parse_mode() -> TelegramParseMode {
+    match non_empty_env("AVA_TELEGRAM_PARSE_MODE")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("markdown") => TelegramParseMode::Markdown,
+        Some("none" | "plain") => TelegramParseMode::Plain,
+        _ => TelegramParseMode::Html,
+    }
+}
+
+/// how log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// human-readable lines
+    #[default]
+    Text,
+    /// one JSON object per line, for log ingestion
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// returns the log line format. set AVA_LOG_FORMAT=json for JSON lines.
+pub fn log_format() -> LogFormat {
+    match non_empty_env("AVA_LOG_FORMAT")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+/// returns the minimum level that gets logged.
+/// set with AVA_LOG_LEVEL, info by default.
+pub fn log_level() -> tracing::Level {
+    non_empty_env("AVA_LOG_LEVEL")
+        .and_then(|v| parse_log_level(&v))
+        .unwrap_or(tracing::Level::INFO)
+}
+
+/// parses a level name like `debug` or `WARN`. `warning` is accepted too.
+fn parse_log_level(value: &str) -> Option<tracing::Level> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "warning" => Some(tracing::Level::WARN),
+        level => level.parse().ok(),
+    }
+}
+
+/// returns the fact categories to put first in the system prompt.
+/// set with AVA_FACT_CATEGORY_PRIORITY, comma-separated.
+pub fn fact_category_priority() -> Vec<String> {
+    non_empty_env("AVA_FACT_CATEGORY_PRIORITY")
+        .map(|v| {
+            v.split(',')
+                .map(|category| category.trim().to_string())
+                .filter(|category| !category.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// returns the only tools a channel may use, if it's limited.
+/// set with AVA_CLI_TOOLS or AVA_TELEGRAM_TOOLS, comma-separated.
+pub fn channel_tools(channel: ChannelKind) -> Option<Vec<String>> {
+    let name = match channel {
+        ChannelKind::Cli => "AVA_CLI_TOOLS",
+        ChannelKind::Telegram => "AVA_TELEGRAM_TOOLS",
+    };
+    non_empty_env(name).map(|v| {
+        v.split(',')
+            .map(|tool| tool.trim().to_string())
+            .filter(|tool| !tool.is_empty())
+            .collect()
+    })
+}
+
+/// returns the char budget for known facts in the system prompt, if any.
+/// set with AVA_FACT_PROMPT_MAX_CHARS, unlimited by default.
+pub fn fact_prompt_max_chars() -> Option<usize> {
+    non_empty_env("AVA_FACT_PROMPT_MAX_CHARS")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// returns the char budget for the whole system prompt, if any. over it,
+/// sections like conversation notes are dropped before known facts.
+/// set with AVA_SYSTEM_PROMPT_MAX_CHARS, unlimited by default.
+pub fn system_prompt_max_chars() -> Option<usize> {
+    non_empty_env("AVA_SYSTEM_PROMPT_MAX_CHARS")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// when set, a fetched page longer than the tool output limit is stored in
+/// the database, and the model gets a preview plus an ID to page through it
+/// with read_stored. enable with AVA_STORE_FETCHES=1.
+pub fn store_fetches() -> bool {
+    env_flag("AVA_STORE_FETCHES")
+}
+
+/// when set, runs of identical lines in exec and web_fetch output are folded
+/// into one line with a count before truncating. enable with
+/// AVA_COLLAPSE_REPEATS=1.
+pub fn collapse_repeats() -> bool {
+    env_flag("AVA_COLLAPSE_REPEATS")
+}
+
+/// when set, web_search marks the query's words in result descriptions,
+/// like `*rust*`. enable with AVA_SEARCH_HIGHLIGHT=1.
+pub fn search_highlight() -> bool {
+    env_flag("AVA_SEARCH_HIGHLIGHT")
+}
+
+/// how web_fetch reaches a page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchRoute {
+    /// requests the page itself
+    Direct,
+    /// goes through a jina reader instance, which returns the page as text
+    Jina {
+        /// prefixed to the page URL, ends with a slash
+        base_url: String,
+        /// sent as `X-Return-Format`. jina picks when unset.
+        return_format: Option<JinaFormat>,
+    },
+}
+
+/// what jina turns a page into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JinaFormat {
+    Markdown,
+    Text,
+}
+
+impl JinaFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Markdown => "markdown",
+            Self::Text => "text",
+        }
+    }
+}
+
+impl fmt::Display for FetchRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::Jina {
+                base_url,
+                return_format,
+            } => {
+                write!(f, "via {base_url}")?;
+                match return_format {
+                    Some(format) => write!(f, " ({})", format.as_str()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// returns how web_fetch reaches pages. goes through jina's reader unless
+/// AVA_JINA_DISABLED=1. AVA_JINA_BASE_URL points at a self-hosted reader and
+/// AVA_JINA_RETURN_FORMAT picks markdown or text.
+pub fn fetch_route() -> FetchRoute {
+    if env_flag("AVA_JINA_DISABLED") {
+        return FetchRoute::Direct;
+    }
+    let mut base_url = non_empty_env("AVA_JINA_BASE_URL")
+        .map(|url| url.trim().to_string())
+        .unwrap_or_else(|| DEFAULT_JINA_BASE_URL.to_string());
+    if !base_url.ends_with('/') {
+        base_url.push('/');
+    }
+    let return_format = match non_empty_env("AVA_JINA_RETURN_FORMAT")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("markdown") => Some(JinaFormat::Markdown),
+        Some("text") => Some(JinaFormat::Text),
+        _ => None,
+    };
+    FetchRoute::Jina {
+        base_url,
+        return_format,
+    }
+}
+
+/// where fact embeddings come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingSettings {
+    /// an openai style `/embeddings` endpoint, e.g.
+    /// `https://api.openai.com/v1/embeddings` or ollama's
+    /// `http://localhost:11434/v1/embeddings`
+    pub url: String,
+    pub model: String,
+}
+
+/// returns the embedding endpoint facts are embedded with, set with
+/// AVA_EMBEDDING_URL and AVA_EMBEDDING_MODEL. off by default, which leaves
+/// fact recall to keyword matching.
+pub fn embedding_settings() -> Option<EmbeddingSettings> {
+    let url = non_empty_env("AVA_EMBEDDING_URL")?.trim().to_string();
+    let model = non_empty_env("AVA_EMBEDDING_MODEL")
+        .map(|model| model.trim().to_string())
+        .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string());
+    Some(EmbeddingSettings { url, model })
+}
+
+/// returns the file every tool call and its result are appended to, as JSON
+/// lines for `ava replay`. set with AVA_TOOL_TRACE_FILE, off by default.
+pub fn tool_trace_file() -> Option<PathBuf> {
+    non_empty_env("AVA_TOOL_TRACE_FILE").map(|path| PathBuf::from(path.trim()))
+}
+
+/// returns the file fixed facts are read from, set with
+/// AVA_SYSTEM_FACTS_FILE. none by default.
+pub fn system_facts_file() -> Option<PathBuf> {
+    non_empty_env("AVA_SYSTEM_FACTS_FILE").map(|path| PathBuf::from(path.trim()))
+}
+
+/// returns the file exec commands are allowed or denied by before anyone is
+/// asked, set with AVA_APPROVAL_POLICY_FILE. none by default.
+pub fn approval_policy_file() -> Option<PathBuf> {
+    non_empty_env("AVA_APPROVAL_POLICY_FILE").map(|path| PathBuf::from(path.trim()))
+}
+
+/// returns the directory search_files is confined to, set with
+/// AVA_SANDBOX_ROOT. none by default, which turns search_files off, since
+/// anyone who can chat with ava could otherwise read whatever directory it
+/// happens to run in.
+pub fn sandbox_root() -> Option<PathBuf> {
+    non_empty_env("AVA_SANDBOX_ROOT").map(|path| PathBuf::from(path.trim()))
+}
+
+/// the proxy all outbound HTTP goes through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpProxy {
+    pub url: String,
+    /// username and password, from AVA_HTTP_PROXY_USER and
+    /// AVA_HTTP_PROXY_PASSWORD
+    pub auth: Option<(String, String)>,
+}
+
+impl fmt::Display for HttpProxy {
+    // credentials in the URL aren't shown
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) => {
+                write!(f, "{}://{}", url.scheme(), url.host_str().unwrap_or(""))?;
+                if let Some(port) = url.port() {
+                    write!(f, ":{port}")?;
+                }
+            }
+            Err(_) => write!(f, "invalid url")?,
+        }
+        if self.auth.is_some() {
+            write!(f, " (with auth)")?;
+        }
+        Ok(())
+    }
+}
+
+/// returns the proxy every HTTP client goes through, set with
+/// AVA_HTTP_PROXY or else HTTPS_PROXY. none by default.
+pub fn http_proxy() -> Option<HttpProxy> {
+    let url = non_empty_env("AVA_HTTP_PROXY")
+        .or_else(|| non_empty_env("HTTPS_PROXY"))
+        .or_else(|| non_empty_env("https_proxy"))?
+        .trim()
+        .to_string();
+    let auth = non_empty_env("AVA_HTTP_PROXY_USER").map(|user| {
+        let password = std::env::var("AVA_HTTP_PROXY_PASSWORD").unwrap_or_default();
+        (user.trim().to_string(), password)
+    });
+    Some(HttpProxy { url, auth })
+}
+
+/// returns the URL every fact change is posted to, set with
+/// AVA_FACT_WEBHOOK. off by default.
+pub fn fact_webhook() -> Option<String> {
+    non_empty_env("AVA_FACT_WEBHOOK").map(|url| url.trim().to_string())
+}
+
+/// loads the facts in AVA_SYSTEM_FACTS_FILE, if it's set. these are always
+/// in the system prompt and the model can't change them. the file maps
+/// categories to keys to values:
+///
+/// `{"business": {"hours": "mon-fri 9:00-17:00", "address": "1 main st"}}`
+pub fn system_facts() -> Result<Vec<Fact>, Error> {
+    match system_facts_file() {
+        Some(path) => load_system_facts(&path),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn load_system_facts(path: &Path) -> Result<Vec<Fact>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_system_facts(&contents)
+        .map_err(|e| Error::InvalidConfig(format!("system facts in {}: {e}", path.display())))
+}
+
+fn parse_system_facts(contents: &str) -> Result<Vec<Fact>, serde_json::Error> {
+    let categories: serde_json::Map<String, serde_json::Value> = serde_json::from_str(contents)?;
+    let mut facts = Vec::new();
+    for (category, keys) in categories {
+        let keys: serde_json::Map<String, serde_json::Value> = serde_json::from_value(keys)?;
+        for (key, value) in keys {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                other => other.to_string(),
+            };
+            facts.push(Fact {
+                category: category.clone(),
+                key,
+                value,
+                private: false,
+                updated_at: None,
+            });
+        }
+    }
+    Ok(facts)
+}
+
+/// returns how long a session may sit idle before the next message starts a
+/// new one. set in minutes with AVA_SESSION_IDLE_MINUTES, 0 never rolls over.
+pub fn session_idle_timeout() -> Option<Duration> {
+    let minutes = non_empty_env("AVA_SESSION_IDLE_MINUTES")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_SESSION_IDLE_MINUTES);
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+/// returns how many stored messages a session may hold before its oldest
+/// ones are summarized. set with AVA_SUMMARIZE_AFTER_MESSAGES, off by default.
+pub fn summarize_after_messages() -> Option<usize> {
+    non_empty_env("AVA_SUMMARIZE_AFTER_MESSAGES")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// returns the estimated token count past which a session's oldest messages
+/// are summarized. set with AVA_SUMMARIZE_AFTER_TOKENS, off by default.
+/// "auto" uses half the model's context window.
+pub fn summarize_after_tokens() -> Option<usize> {
+    let value = non_empty_env("AVA_SUMMARIZE_AFTER_TOKENS")?;
+    if value.trim().eq_ignore_ascii_case("auto") {
+        return Some(context_window() * HISTORY_SHARE_PERCENT / 100);
+    }
+    value.trim().parse().ok().filter(|&n| n > 0)
+}
+
+/// the interpreter exec runs commands with, e.g. `sh -c` or `pwsh -Command`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecShell {
+    pub program: String,
+    pub flag: String,
+}
+
+impl ExecShell {
+    /// parses "bash" or "bash -c". without a flag, one is picked from the
+    /// program name: -Command for powershell, /C for cmd, -c otherwise.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split_whitespace();
+        let program = parts.next()?.to_string();
+        let flag = match parts.next() {
+            Some(flag) => flag.to_string(),
+            None => default_shell_flag(&program).to_string(),
+        };
+        Some(Self { program, flag })
+    }
+}
+
+impl Default for ExecShell {
+    /// `sh -c`, or `cmd /C` on windows
+    fn default() -> Self {
+        let (program, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        Self {
+            program: program.into(),
+            flag: flag.into(),
+        }
+    }
+}
+
+impl fmt::Display for ExecShell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.program, self.flag)
+    }
+}
+
+fn default_shell_flag(program: &str) -> &'static str {
+    let name = Path::new(program)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match name.as_str() {
+        "pwsh" | "powershell" => "-Command",
+        "cmd" => "/C",
+        _ => "-c",
+    }
+}
+
+/// returns the shell exec runs commands with.
+/// override with AVA_EXEC_SHELL, e.g. `bash` or `pwsh -Command`.
+pub fn exec_shell() -> ExecShell {
+    non_empty_env("AVA_EXEC_SHELL")
+        .and_then(|value| ExecShell::parse(&value))
+        .unwrap_or_default()
+}
+
+fn non_empty_env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// reads a boolean env var. accepts 1/true/yes/on, case-insensitive.
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| {
+            matches!(
+                v.trim().to_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -18,6 +1127,22 @@ mod tests {
     // mutex to serialize tests that modify env vars
     static ENV_MUTEX: Mutex<()> = Mutex::new(());
 
+    #[test]
+    fn test_parse_system_facts() {
+        let facts =
+            parse_system_facts(r#"{"business": {"hours": "9-17", "tables": 12}, "owner": {}}"#)
+                .unwrap();
+        let entries: Vec<(&str, &str, &str)> = facts
+            .iter()
+            .map(|f| (f.category.as_str(), f.key.as_str(), f.value.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [("business", "hours", "9-17"), ("business", "tables", "12")]
+        );
+        assert!(parse_system_facts(r#"{"business": "open"}"#).is_err());
+    }
+
     #[test]
     fn test_default_db_path_from_env() {
         let _guard = ENV_MUTEX.lock().unwrap();
@@ -51,4 +1176,372 @@ mod tests {
         // should be ava.db in current directory
         assert_eq!(result, PathBuf::from("ava.db"));
     }
+
+    #[test]
+    fn test_assistant_name_from_env() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_ASSISTANT_NAME", " nova ");
+        }
+        assert_eq!(assistant_name(), "nova");
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_ASSISTANT_NAME");
+        }
+        assert_eq!(assistant_name(), DEFAULT_ASSISTANT_NAME);
+    }
+
+    #[test]
+    fn test_context_window_by_model_prefix() {
+        assert_eq!(context_window_for("claude-sonnet-4-5"), 200_000);
+        assert_eq!(context_window_for("claude-3-5-haiku-latest"), 200_000);
+        assert_eq!(context_window_for("claude-2.0"), 100_000);
+        assert_eq!(context_window_for("gpt-4o-mini"), 128_000);
+        assert_eq!(context_window_for("llama3.2"), DEFAULT_CONTEXT_WINDOW);
+    }
+
+    #[test]
+    fn test_summarize_threshold_follows_context_window() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_CONTEXT_WINDOW", "8000");
+            std::env::remove_var("AVA_SUMMARIZE_AFTER_TOKENS");
+        }
+        assert_eq!(context_window(), 8000);
+        assert_eq!(summarize_after_tokens(), None);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_SUMMARIZE_AFTER_TOKENS", "auto");
+        }
+        assert_eq!(summarize_after_tokens(), Some(4000));
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_SUMMARIZE_AFTER_TOKENS", "3000");
+        }
+        assert_eq!(summarize_after_tokens(), Some(3000));
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_SUMMARIZE_AFTER_TOKENS", "0");
+        }
+        assert_eq!(summarize_after_tokens(), None);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_CONTEXT_WINDOW");
+            std::env::remove_var("AVA_SUMMARIZE_AFTER_TOKENS");
+        }
+    }
+
+    #[test]
+    fn test_tool_timeouts_from_env() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_TOOL_TIMEOUT");
+            std::env::remove_var("AVA_TOOL_TIMEOUTS");
+        }
+        assert_eq!(tool_timeouts(), ToolTimeouts::default());
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_TOOL_TIMEOUT", "45");
+            std::env::set_var("AVA_TOOL_TIMEOUTS", "web_fetch=20, web_search=abc, weather");
+        }
+        let timeouts = tool_timeouts();
+        assert_eq!(timeouts.for_tool("web_fetch"), Duration::from_secs(20));
+        assert_eq!(timeouts.for_tool("web_search"), Duration::from_secs(45));
+        assert_eq!(timeouts.to_string(), "45s, web_fetch 20s");
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_TOOL_TIMEOUT");
+            std::env::remove_var("AVA_TOOL_TIMEOUTS");
+        }
+    }
+
+    #[test]
+    fn test_sandbox_root_is_opt_in() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_SANDBOX_ROOT");
+        }
+        assert_eq!(sandbox_root(), None);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_SANDBOX_ROOT", " /srv/notes ");
+        }
+        assert_eq!(sandbox_root(), Some(PathBuf::from("/srv/notes")));
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_SANDBOX_ROOT");
+        }
+    }
+
+    #[test]
+    fn test_max_tool_output_from_env() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_MAX_TOOL_OUTPUT", "12000");
+        }
+        assert_eq!(max_tool_output(), 12000);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_MAX_TOOL_OUTPUT", "not a number");
+        }
+        assert_eq!(max_tool_output(), DEFAULT_MAX_TOOL_OUTPUT);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_MAX_TOOL_OUTPUT", "  ");
+        }
+        assert_eq!(max_tool_output(), DEFAULT_MAX_TOOL_OUTPUT);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_MAX_TOOL_OUTPUT");
+        }
+        assert_eq!(max_tool_output(), DEFAULT_MAX_TOOL_OUTPUT);
+    }
+
+    #[test]
+    fn test_exec_shell_parse() {
+        let shell = |program: &str, flag: &str| ExecShell {
+            program: program.into(),
+            flag: flag.into(),
+        };
+
+        assert_eq!(ExecShell::parse("bash"), Some(shell("bash", "-c")));
+        assert_eq!(
+            ExecShell::parse("/bin/zsh -c"),
+            Some(shell("/bin/zsh", "-c"))
+        );
+        assert_eq!(ExecShell::parse("pwsh"), Some(shell("pwsh", "-Command")));
+        assert_eq!(
+            ExecShell::parse("powershell.exe"),
+            Some(shell("powershell.exe", "-Command"))
+        );
+        assert_eq!(ExecShell::parse("cmd"), Some(shell("cmd", "/C")));
+        assert_eq!(ExecShell::parse("   "), None);
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        assert_eq!(parse_log_level("debug"), Some(tracing::Level::DEBUG));
+        assert_eq!(parse_log_level(" WARN "), Some(tracing::Level::WARN));
+        assert_eq!(parse_log_level("warning"), Some(tracing::Level::WARN));
+        assert_eq!(parse_log_level("trace"), Some(tracing::Level::TRACE));
+        assert_eq!(parse_log_level("loud"), None);
+        assert_eq!(parse_log_level(""), None);
+    }
+
+    #[test]
+    fn test_env_flag() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        for (value, expected) in [("1", true), ("TRUE", true), ("yes", true), ("0", false)] {
+            // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+            unsafe {
+                std::env::set_var("AVA_TEST_FLAG", value);
+            }
+            assert_eq!(env_flag("AVA_TEST_FLAG"), expected, "value: {value}");
+        }
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_TEST_FLAG");
+        }
+        assert!(!env_flag("AVA_TEST_FLAG"));
+    }
+
+    #[test]
+    fn test_http_proxy_configures_client() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_HTTP_PROXY", " http://proxy.corp:3128 ");
+            std::env::set_var("AVA_HTTP_PROXY_USER", "ava");
+            std::env::set_var("AVA_HTTP_PROXY_PASSWORD", "hunter2");
+        }
+        let proxy = http_proxy().unwrap();
+        assert_eq!(
+            proxy,
+            HttpProxy {
+                url: "http://proxy.corp:3128".into(),
+                auth: Some(("ava".into(), "hunter2".into())),
+            }
+        );
+        assert_eq!(proxy.to_string(), "http://proxy.corp:3128 (with auth)");
+
+        // a bad proxy is an error rather than a direct connection
+        let invalid = HttpProxy {
+            url: "http://[::1".into(),
+            auth: None,
+        };
+        assert!(matches!(
+            crate::http::client_builder(Some(&invalid)),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_HTTP_PROXY");
+            std::env::remove_var("AVA_HTTP_PROXY_USER");
+            std::env::remove_var("AVA_HTTP_PROXY_PASSWORD");
+        }
+    }
+
+    /// accepts one connection and returns the request line it was sent
+    async fn first_request_line(listener: tokio::net::TcpListener) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let read = socket.read(&mut request).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let request = String::from_utf8_lossy(&request[..read]).into_owned();
+        request.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn test_http_proxy_is_used_except_for_no_proxy_hosts() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("NO_PROXY", "localhost");
+        }
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+            let site = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let site_port = site.local_addr().unwrap().port();
+            let client = crate::http::client_builder(Some(&HttpProxy {
+                url: proxy_url,
+                auth: None,
+            }))
+            .unwrap()
+            .build()
+            .unwrap();
+
+            let proxied = tokio::spawn(first_request_line(proxy));
+            client
+                .get(format!("http://example.test:{site_port}/page"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                proxied.await.unwrap(),
+                format!("GET http://example.test:{site_port}/page HTTP/1.1")
+            );
+
+            let direct = tokio::spawn(first_request_line(site));
+            client
+                .get(format!("http://localhost:{site_port}/page"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(direct.await.unwrap(), "GET /page HTTP/1.1");
+        });
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("NO_PROXY");
+        }
+    }
+
+    #[test]
+    fn test_fetch_route_from_env() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_JINA_BASE_URL", "http://reader.local:3000");
+            std::env::set_var("AVA_JINA_RETURN_FORMAT", "Text");
+        }
+        assert_eq!(
+            fetch_route(),
+            FetchRoute::Jina {
+                base_url: "http://reader.local:3000/".into(),
+                return_format: Some(JinaFormat::Text),
+            }
+        );
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_JINA_DISABLED", "1");
+        }
+        assert_eq!(fetch_route(), FetchRoute::Direct);
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_JINA_BASE_URL");
+            std::env::remove_var("AVA_JINA_RETURN_FORMAT");
+            std::env::remove_var("AVA_JINA_DISABLED");
+        }
+        assert_eq!(
+            fetch_route(),
+            FetchRoute::Jina {
+                base_url: DEFAULT_JINA_BASE_URL.into(),
+                return_format: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_config_resolve_reflects_env_overrides() {
+        let _guard = ENV_MUTEX.lock().unwrap();
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::set_var("AVA_MODEL", "claude-opus-4-1");
+            std::env::set_var("AVA_MAX_TOKENS", "1024");
+            std::env::set_var("AVA_DB_PATH", "/var/lib/ava/ava.db");
+            std::env::set_var("JINA_API_KEY", "secret-jina-key");
+        }
+
+        let config = Config::resolve();
+
+        assert_eq!(config.model, "claude-opus-4-1");
+        assert_eq!(config.max_tokens, 1024);
+        assert_eq!(config.db_path, PathBuf::from("/var/lib/ava/ava.db"));
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/ava"));
+        assert!(config.secrets.jina_api_key);
+
+        let shown = config.to_string();
+        assert!(shown.contains("JINA_API_KEY: set"));
+        assert!(!shown.contains("secret-jina-key"));
+
+        // SAFETY: we hold ENV_MUTEX to ensure no concurrent env var access
+        unsafe {
+            std::env::remove_var("AVA_MODEL");
+            std::env::remove_var("AVA_MAX_TOKENS");
+            std::env::remove_var("AVA_DB_PATH");
+            std::env::remove_var("JINA_API_KEY");
+        }
+
+        let config = Config::resolve();
+        assert_eq!(config.model, DEFAULT_MODEL);
+        assert_eq!(config.max_tokens, DEFAULT_MAX_TOKENS);
+        assert_eq!(config.data_dir, PathBuf::from("."));
+    }
 }
@@ -47,9 +47,81 @@ const MIGRATIONS: &[&str] = &[
         created_at TEXT NOT NULL DEFAULT (datetime('now'))
     );
     "#,
+    // v4: optional fact expiry
+    r#"
+    ALTER TABLE facts ADD COLUMN expires_at TEXT;
+
+    CREATE INDEX IF NOT EXISTS idx_facts_expires ON facts(expires_at) WHERE expires_at IS NOT NULL;
+    "#,
+    // v5: sessions belong to a channel, e.g. "cli" or "telegram:123"
+    r#"
+    ALTER TABLE sessions ADD COLUMN channel TEXT;
+
+    CREATE INDEX IF NOT EXISTS idx_sessions_channel ON sessions(channel, updated_at DESC);
+    "#,
+    // v6: content kept out of the conversation, read back on demand
+    r#"
+    CREATE TABLE IF NOT EXISTS blobs (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at TEXT NOT NULL DEFAULT (datetime('now'))
+    );
+    "#,
+    // v7: scratch facts that only last as long as their session
+    r#"
+    CREATE TABLE IF NOT EXISTS session_facts (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+        UNIQUE(session_id, key)
+    );
+    "#,
+    // v8: earlier values of facts, so an overwrite can be undone
+    r#"
+    CREATE TABLE IF NOT EXISTS fact_history (
+        id INTEGER PRIMARY KEY,
+        category TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        replaced_at TEXT NOT NULL DEFAULT (datetime('now'))
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_fact_history_fact ON fact_history(category, key, id DESC);
+    "#,
+    // v9: embeddings of fact values, for recall by similarity
+    r#"
+    CREATE TABLE IF NOT EXISTS fact_embeddings (
+        fact_id INTEGER PRIMARY KEY REFERENCES facts(id) ON DELETE CASCADE,
+        value TEXT NOT NULL,
+        vector TEXT NOT NULL,
+        created_at TEXT NOT NULL DEFAULT (datetime('now'))
+    );
+    "#,
+    // v10: facts only shown to the owner
+    r#"
+    ALTER TABLE facts ADD COLUMN private INTEGER NOT NULL DEFAULT 0;
+    "#,
+    // v11: the command an approval rule was first allowed for
+    r#"
+    ALTER TABLE approval_rules ADD COLUMN example_command TEXT;
+    "#,
 ];
 
+/// the version the schema has once every migration ran
+pub fn latest_version() -> i32 {
+    MIGRATIONS.len() as i32
+}
+
 pub fn migrate(conn: &Connection) -> Result<(), Error> {
+    migrate_to(conn, latest_version())
+}
+
+/// runs the migrations up to and including `target`. migrations only go
+/// forward, so a target below the current version is an error.
+pub fn migrate_to(conn: &Connection, target: i32) -> Result<(), Error> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY)",
         [],
@@ -62,10 +134,20 @@ pub fn migrate(conn: &Connection) -> Result<(), Error> {
             |r| r.get(0),
         )
         .unwrap_or(0);
+    if !(current..=latest_version()).contains(&target) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "can't migrate from version {current} to {target}, only to {current} through {}",
+                latest_version()
+            ),
+        )
+        .into());
+    }
 
     for (i, migration) in MIGRATIONS.iter().enumerate() {
         let version = (i + 1) as i32;
-        if version > current {
+        if version > current && version <= target {
             conn.execute_batch(migration)?;
             conn.execute("INSERT INTO schema_version (version) VALUES (?)", [version])?;
         }
@@ -74,7 +156,6 @@ pub fn migrate(conn: &Connection) -> Result<(), Error> {
     Ok(())
 }
 
-#[allow(dead_code)]
 pub fn schema_version(conn: &Connection) -> Result<i32, Error> {
     let version = conn
         .query_row(
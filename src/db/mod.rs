@@ -1,108 +1,630 @@
 mod migrations;
 
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use rusqlite::Connection;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
 
-use crate::config::default_db_path;
+use crate::config::{self, default_db_path};
 use crate::error::Error;
+use crate::message::{Message, MessageContent, Role};
+use crate::webhook::WebhookObserver;
+
+pub use migrations::latest_version as latest_schema_version;
+
+/// how many facts are injected into the system prompt
+const RECENT_FACTS_LIMIT: usize = 50;
+
+/// how many earlier values are kept per fact for undo
+const FACT_HISTORY_LIMIT: usize = 5;
+
+/// how many pages a backup copies before letting writers in again
+const BACKUP_PAGES_PER_STEP: i32 = 100;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Fact {
     pub category: String,
     pub key: String,
     pub value: String,
+    /// only shown on the CLI, and kept out of traces
+    pub private: bool,
+    /// when the fact was last remembered, in unix seconds. remembering the
+    /// same value again counts too, since it confirms the fact still holds.
+    /// only set on facts listed from the database.
+    pub updated_at: Option<i64>,
 }
 
-#[allow(dead_code)]
+/// a change to a stored fact, as seen by a `FactObserver`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FactChange {
+    /// stored, updated or made private or public, with the fact as it is now
+    Remembered(Fact),
+    /// forgotten, expired or evicted to make room
+    Forgotten { category: String, key: String },
+}
+
+/// told about every fact change once it's committed. it's called on the
+/// writer's thread, so anything slow should be spawned.
+pub trait FactObserver: Send + Sync {
+    fn fact_changed(&self, change: &FactChange);
+}
+
+/// a fact and the embedding of its current value, if it has one
+pub type EmbeddedFact = (Fact, Option<Vec<f32>>);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ApprovalRule {
     pub id: i64,
     pub pattern: String,
+    /// the command the rule was saved for. rules saved before this was
+    /// recorded have none.
+    pub example_command: Option<String>,
+    /// sqlite's UTC `YYYY-MM-DD HH:MM:SS`
+    pub created_at: String,
+}
+
+/// a note that only lasts as long as its session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionFact {
+    pub key: String,
+    pub value: String,
+}
+
+/// a conversation, as listed or exported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: i64,
+    /// sessions from before channels were recorded have none
+    pub channel: Option<String>,
+    /// sqlite's UTC `YYYY-MM-DD HH:MM:SS`
+    pub created_at: String,
+}
+
+/// a message as stored in a session
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub message: Message,
+}
+
+/// persistence operations used by the agent and tools.
+/// `Database` is the sqlite-backed implementation.
+pub trait Store: Send + Sync {
+    /// stores or updates a fact. with `expires_in_secs` set the fact is
+    /// forgotten after that long, without it the fact doesn't expire.
+    /// `private` marks it private, but never makes an existing fact public.
+    fn remember_fact(
+        &self,
+        category: &str,
+        key: &str,
+        value: &str,
+        expires_in_secs: Option<u64>,
+        private: bool,
+    ) -> Result<(), Error>;
+
+    /// stores several facts at once, all or nothing
+    fn remember_facts(&self, facts: &[Fact]) -> Result<(), Error>;
+
+    fn recent_facts(&self) -> Result<Vec<Fact>, Error>;
+
+    /// marks a fact private or not, returns false if there's no such fact
+    fn set_fact_private(&self, category: &str, key: &str, private: bool) -> Result<bool, Error>;
+
+    fn get_fact(&self, category: &str, key: &str) -> Result<Option<String>, Error>;
+
+    /// current facts, each with its embedding if it has one. an embedding
+    /// made for an older value doesn't count. stores that don't keep
+    /// embeddings return none.
+    fn facts_with_embeddings(&self) -> Result<Vec<EmbeddedFact>, Error> {
+        Ok(self
+            .recent_facts()?
+            .into_iter()
+            .map(|fact| (fact, None))
+            .collect())
+    }
+
+    /// every private fact, e.g. to keep their values out of traces
+    fn private_facts(&self) -> Result<Vec<Fact>, Error> {
+        Ok(self
+            .recent_facts()?
+            .into_iter()
+            .filter(|fact| fact.private)
+            .collect())
+    }
+
+    /// stores the embedding of a fact's current value, replacing any earlier
+    /// one. does nothing if the fact is gone, or if the store doesn't keep
+    /// embeddings.
+    fn save_fact_embedding(&self, _fact: &Fact, _vector: &[f32]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// stores or updates a fact that's dropped when the session ends
+    fn remember_session_fact(&self, session_id: i64, key: &str, value: &str) -> Result<(), Error>;
+
+    /// the session's facts, most recently updated first
+    fn recent_session_facts(&self, session_id: i64) -> Result<Vec<SessionFact>, Error>;
+
+    /// saves `pattern` as always allowed, along with the command it was
+    /// first allowed for
+    fn save_approval_rule(&self, pattern: &str, example_command: &str) -> Result<(), Error>;
+
+    #[allow(dead_code)]
+    fn find_matching_rule(&self, command: &str) -> Result<Option<i64>, Error>;
+
+    /// a session's messages, oldest first
+    fn session_messages(&self, session_id: i64) -> Result<Vec<StoredMessage>, Error>;
+
+    fn append_messages(&self, session_id: i64, messages: &[Message]) -> Result<(), Error>;
+
+    /// keeps content like a long fetched page out of the conversation.
+    /// returns the blob's ID. `source` records where it came from, e.g. a URL.
+    fn store_blob(&self, source: &str, content: &str) -> Result<i64, Error>;
+
+    fn get_blob(&self, id: i64) -> Result<Option<String>, Error>;
+
+    /// replaces the messages with these IDs by a single `summary` message,
+    /// which takes the place of the first of them
+    fn replace_messages(
+        &self,
+        session_id: i64,
+        ids: &[i64],
+        summary: &Message,
+    ) -> Result<(), Error>;
 }
 
+/// clones share the same connection
+#[derive(Clone)]
 pub struct Database {
-    conn: Mutex<Connection>,
+    conn: Arc<Mutex<Connection>>,
+    max_facts: Option<usize>,
+    observer: Option<Arc<dyn FactObserver>>,
 }
 
 impl Database {
     /// open database at the default location, run migrations
     pub fn open() -> Result<Self, Error> {
-        Self::open_at(default_db_path())
+        let db = Self::open_at(default_db_path())?.with_max_facts(config::max_facts());
+        Ok(match WebhookObserver::from_env() {
+            Some(webhook) => db.with_fact_observer(webhook),
+            None => db,
+        })
     }
 
     /// open database at a specific path
     pub fn open_at(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let conn = Connection::open(path)?;
-        migrations::migrate(&conn)?;
-        Ok(Self {
-            conn: Mutex::new(conn),
-        })
+        Self::from_connection(Connection::open(path)?)
     }
 
     /// in-memory database for testing
     #[allow(dead_code)]
     pub fn open_in_memory() -> Result<Self, Error> {
-        let conn = Connection::open_in_memory()?;
-        migrations::migrate(&conn)?;
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    /// open a database without running migrations, to inspect or migrate
+    /// it by hand. `:memory:` opens a fresh in-memory one.
+    pub fn open_unmigrated_at(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::unmigrated(Connection::open(path)?)
+    }
+
+    /// open a database that must already exist, without running
+    /// migrations, so the file is left as it is, e.g. to back it up
+    pub fn open_existing_at(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no database at {}", path.display()),
+            )
+            .into());
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        Self::unmigrated(Connection::open_with_flags(path, flags)?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self, Error> {
+        let db = Self::unmigrated(conn)?;
+        migrations::migrate(&db.conn.lock().unwrap())?;
+        Ok(db)
+    }
+
+    fn unmigrated(conn: Connection) -> Result<Self, Error> {
+        // sqlite leaves foreign keys off by default, which would make the
+        // schema's ON DELETE CASCADE a no-op. with them on, deleting a session
+        // takes its messages and session facts along, forgetting or evicting
+        // a fact takes its embedding along, and a message can't be written
+        // to a session that doesn't exist. approval rules and fact history
+        // have no foreign keys, so they're unaffected.
+        conn.pragma_update(None, "foreign_keys", true)?;
         Ok(Self {
-            conn: Mutex::new(conn),
+            conn: Arc::new(Mutex::new(conn)),
+            max_facts: None,
+            observer: None,
         })
     }
 
-    #[allow(dead_code)]
+    /// runs the migrations up to `target`, or all of them. returns the schema
+    /// version before and after.
+    pub fn migrate(&self, target: Option<i32>) -> Result<(i32, i32), Error> {
+        let conn = self.conn.lock().unwrap();
+        let before = migrations::schema_version(&conn)?;
+        migrations::migrate_to(&conn, target.unwrap_or_else(migrations::latest_version))?;
+        let after = migrations::schema_version(&conn)?;
+        if after != before {
+            tracing::info!(before, after, "migrated database");
+        }
+        Ok((before, after))
+    }
+
+    /// cap the number of stored facts. past the cap, remembering a fact evicts
+    /// the least recently updated agent facts. user facts are never evicted.
+    pub fn with_max_facts(mut self, max_facts: Option<usize>) -> Self {
+        self.max_facts = max_facts;
+        self
+    }
+
+    /// tells `observer` about every fact that's remembered or forgotten
+    pub fn with_fact_observer(mut self, observer: impl FactObserver + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn notify(&self, changes: &[FactChange]) {
+        if let Some(observer) = &self.observer {
+            for change in changes {
+                observer.fact_changed(change);
+            }
+        }
+    }
+
+    /// runs `f` in a transaction. commits if `f` succeeds, rolls back if it
+    /// returns an error.
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        // dropping the transaction without committing rolls it back
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// copies the database to a new file at `path` with sqlite's online
+    /// backup, which gives a consistent snapshot even while another process
+    /// is writing. refuses to overwrite an existing file. returns how many
+    /// pages were copied.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )
+            .into());
+        }
+        let conn = self.conn.lock().unwrap();
+        let mut target = Connection::open(path)?;
+        let backup = Backup::new(&conn, &mut target)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, Duration::ZERO, None)?;
+        let pages = backup.progress().pagecount as usize;
+        tracing::info!(path = %path.display(), pages, "backed up database");
+        Ok(pages)
+    }
+
+    pub fn fact_count(&self) -> Result<usize, Error> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM facts", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    pub fn session_count(&self) -> Result<usize, Error> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// whether `table` exists, e.g. to tell which tables an old schema has
+    pub fn has_table(&self, table: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
     pub fn schema_version(&self) -> Result<i32, Error> {
         let conn = self.conn.lock().unwrap();
         migrations::schema_version(&conn)
     }
 
-    pub fn remember_fact(&self, category: &str, key: &str, value: &str) -> Result<(), Error> {
-        tracing::debug!(category, key, "remembering fact");
+    /// facts ordered by most recently updated, skipping the first `offset`
+    pub fn recent_facts_limited(&self, limit: usize, offset: usize) -> Result<Vec<Fact>, Error> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT INTO facts (category, key, value, source)
-            VALUES (?1, ?2, ?3, 'agent')
-            ON CONFLICT(category, key) DO UPDATE SET
-                value = excluded.value,
-                source = excluded.source,
-                updated_at = datetime('now')",
-            [category, key, value],
+        let mut stmt = conn.prepare(
+            "SELECT category, key, value, private, unixepoch(updated_at)
+            FROM facts
+            WHERE expires_at IS NULL OR expires_at > datetime('now')
+            ORDER BY updated_at DESC, id DESC
+            LIMIT ?1 OFFSET ?2",
         )?;
-        Ok(())
+
+        let facts = stmt
+            .query_map(params![limit as i64, offset as i64], |row| {
+                Ok(Fact {
+                    category: row.get(0)?,
+                    key: row.get(1)?,
+                    value: row.get(2)?,
+                    private: row.get(3)?,
+                    updated_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(facts)
     }
 
-    pub fn save_approval_rule(&self, pattern: &str) -> Result<(), Error> {
-        tracing::debug!(pattern, "saving approval rule");
+    /// facts updated after `timestamp`, most recent first. `timestamp` is UTC
+    /// in sqlite's `YYYY-MM-DD HH:MM:SS` format, like the stored timestamps.
+    #[allow(dead_code)]
+    pub fn facts_updated_since(&self, timestamp: &str) -> Result<Vec<Fact>, Error> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR IGNORE INTO approval_rules (pattern) VALUES (?1)",
-            [pattern],
+        let mut stmt = conn.prepare(
+            "SELECT category, key, value, private, unixepoch(updated_at)
+            FROM facts
+            WHERE updated_at > ?1
+                AND (expires_at IS NULL OR expires_at > datetime('now'))
+            ORDER BY updated_at DESC, id DESC",
         )?;
-        Ok(())
+
+        let facts = stmt
+            .query_map([timestamp], |row| {
+                Ok(Fact {
+                    category: row.get(0)?,
+                    key: row.get(1)?,
+                    value: row.get(2)?,
+                    private: row.get(3)?,
+                    updated_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(facts)
     }
 
+    /// deletes facts past their expiry, returns how many were removed
     #[allow(dead_code)]
-    pub fn find_matching_rule(&self, command: &str) -> Result<Option<i64>, Error> {
-        let rules = self.list_approval_rules()?;
-        for rule in rules {
-            if matches_rule(&rule.pattern, command) {
-                return Ok(Some(rule.id));
+    pub fn delete_expired_facts(&self) -> Result<usize, Error> {
+        let expired = {
+            let conn = self.conn.lock().unwrap();
+            delete_expired_facts(&conn)?
+        };
+        self.notify(&expired);
+        Ok(expired.len())
+    }
+
+    /// restores the value a fact had before its last change, returns the
+    /// restored value. `None` if there's nothing to undo.
+    pub fn undo_fact(&self, category: &str, key: &str) -> Result<Option<String>, Error> {
+        tracing::debug!(category, key, "undoing fact change");
+        self.transaction(|conn| {
+            let previous: Option<(i64, String)> = conn
+                .query_row(
+                    "SELECT id, value FROM fact_history
+                    WHERE category = ?1 AND key = ?2
+                    ORDER BY id DESC LIMIT 1",
+                    [category, key],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let Some((history_id, value)) = previous else {
+                return Ok(None);
+            };
+
+            conn.execute("DELETE FROM fact_history WHERE id = ?1", [history_id])?;
+            // the fact may have expired or been evicted since, so bring it
+            // back if needed
+            let id = conn.query_row(
+                "INSERT INTO facts (category, key, value, source)
+                VALUES (?1, ?2, ?3, 'agent')
+                ON CONFLICT(category, key) DO UPDATE SET
+                    value = excluded.value,
+                    expires_at = NULL,
+                    updated_at = datetime('now')
+                RETURNING id",
+                params![category, key, value],
+                |row| row.get(0),
+            )?;
+            Ok(Some((value, fact_by_id(conn, id)?)))
+        })
+        .map(|restored| {
+            restored.map(|(value, fact)| {
+                self.notify(&[FactChange::Remembered(fact)]);
+                value
+            })
+        })
+    }
+
+    /// deletes a fact, returns false if there was no such fact
+    pub fn forget_fact(&self, category: &str, key: &str) -> Result<bool, Error> {
+        tracing::debug!(category, key, "forgetting fact");
+        let rows = {
+            let conn = self.conn.lock().unwrap();
+            conn.execute(
+                "DELETE FROM facts WHERE category = ?1 AND key = ?2",
+                [category, key],
+            )?
+        };
+        if rows > 0 {
+            self.notify(&[FactChange::Forgotten {
+                category: category.to_string(),
+                key: key.to_string(),
+            }]);
+        }
+        Ok(rows > 0)
+    }
+
+    /// starts a new session for a channel, returns its ID. this ends the
+    /// channel's earlier sessions, which drops their session facts.
+    pub fn create_session(&self, channel: &str) -> Result<i64, Error> {
+        let id = self.transaction(|conn| {
+            let ended = conn.execute(
+                "DELETE FROM session_facts
+                WHERE session_id IN (SELECT id FROM sessions WHERE channel = ?1)",
+                [channel],
+            )?;
+            if ended > 0 {
+                tracing::debug!(channel, count = ended, "dropped session facts");
             }
+            let id = conn.query_row(
+                "INSERT INTO sessions (channel, model) VALUES (?1, ?2) RETURNING id",
+                params![channel, config::model()],
+                |row| row.get(0),
+            )?;
+            Ok(id)
+        })?;
+        tracing::debug!(id, channel, "created session");
+        Ok(id)
+    }
+
+    /// the session with this ID, if there is one
+    pub fn get_session(&self, id: i64) -> Result<Option<Session>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let session = conn
+            .query_row(
+                "SELECT id, channel, created_at FROM sessions WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(Session {
+                        id: row.get(0)?,
+                        channel: row.get(1)?,
+                        created_at: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(session)
+    }
+
+    /// the channel's most recently active session, if it has one
+    pub fn latest_session(&self, channel: &str) -> Result<Option<i64>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let id = conn
+            .query_row(
+                "SELECT id FROM sessions WHERE channel = ?1
+                ORDER BY updated_at DESC, id DESC
+                LIMIT 1",
+                [channel],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// the channel's most recently active session, or a new one. with
+    /// `idle_timeout` set, a session that's been quiet for longer than that
+    /// is left behind for a new one.
+    pub fn resume_or_create_session(
+        &self,
+        channel: &str,
+        idle_timeout: Option<Duration>,
+    ) -> Result<i64, Error> {
+        let Some(id) = self.latest_session(channel)? else {
+            return self.create_session(channel);
+        };
+        if let Some(idle) = idle_timeout
+            && self.is_idle(id, idle)?
+        {
+            tracing::info!(
+                session = id,
+                channel,
+                "session went idle, starting a new one"
+            );
+            return self.create_session(channel);
         }
-        Ok(None)
+        Ok(id)
+    }
+
+    /// when the session's last message was stored, as sqlite's UTC
+    /// `YYYY-MM-DD HH:MM:SS`, or `None` if it has no messages
+    pub fn last_message_time(&self, session_id: i64) -> Result<Option<String>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let time = conn.query_row(
+            "SELECT MAX(created_at) FROM messages WHERE session_id = ?1",
+            [session_id],
+            |row| row.get(0),
+        )?;
+        Ok(time)
+    }
+
+    /// whether nothing was said in the session for longer than `idle`. a
+    /// session without messages counts from when it was created.
+    fn is_idle(&self, session_id: i64, idle: Duration) -> Result<bool, Error> {
+        let last = match self.last_message_time(session_id)? {
+            Some(time) => time,
+            None => {
+                let conn = self.conn.lock().unwrap();
+                conn.query_row(
+                    "SELECT created_at FROM sessions WHERE id = ?1",
+                    [session_id],
+                    |row| row.get(0),
+                )?
+            }
+        };
+        let conn = self.conn.lock().unwrap();
+        let idle = conn.query_row(
+            "SELECT ?1 < datetime('now', ?2)",
+            params![last, format!("-{} seconds", idle.as_secs())],
+            |row| row.get(0),
+        )?;
+        Ok(idle)
+    }
+
+    /// deletes sessions without messages and, given `max_age`, sessions
+    /// without a title created longer ago than that. their messages and
+    /// session facts go with them. each channel's latest session is kept, so
+    /// a conversation that was just started isn't swapped for an older one.
+    /// returns how many sessions were deleted.
+    pub fn prune_empty_sessions(&self, max_age: Option<Duration>) -> Result<usize, Error> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute(
+            "DELETE FROM sessions
+            WHERE id NOT IN (SELECT MAX(id) FROM sessions GROUP BY channel)
+            AND (
+                NOT EXISTS (SELECT 1 FROM messages WHERE messages.session_id = sessions.id)
+                OR (
+                    ?1 IS NOT NULL
+                    AND title IS NULL
+                    AND created_at < datetime('now', '-' || ?1 || ' seconds')
+                )
+            )",
+            [max_age.map(|age| age.as_secs() as i64)],
+        )?;
+        tracing::debug!(count = deleted, "pruned sessions");
+        Ok(deleted)
     }
 
-    #[allow(dead_code)]
     pub fn list_approval_rules(&self) -> Result<Vec<ApprovalRule>, Error> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT id, pattern FROM approval_rules ORDER BY id")?;
+        let mut stmt = conn.prepare(
+            "SELECT id, pattern, example_command, created_at FROM approval_rules ORDER BY id",
+        )?;
 
         let rules = stmt
             .query_map([], |row| {
                 Ok(ApprovalRule {
                     id: row.get(0)?,
                     pattern: row.get(1)?,
+                    example_command: row.get(2)?,
+                    created_at: row.get(3)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
@@ -116,14 +638,106 @@ impl Database {
         let rows = conn.execute("DELETE FROM approval_rules WHERE id = ?1", [id])?;
         Ok(rows > 0)
     }
+}
+
+impl Store for Database {
+    fn remember_fact(
+        &self,
+        category: &str,
+        key: &str,
+        value: &str,
+        expires_in_secs: Option<u64>,
+        private: bool,
+    ) -> Result<(), Error> {
+        tracing::debug!(category, key, expires_in_secs, private, "remembering fact");
+
+        let changes = self.transaction(|conn| {
+            // writes are rare, so this is a good moment to clean up
+            let mut changes = delete_expired_facts(conn)?;
+            let id = upsert_fact(conn, category, key, value, expires_in_secs)?;
+            // private in the same write, so no one sees the value as public
+            if private {
+                conn.execute("UPDATE facts SET private = 1 WHERE id = ?1", [id])?;
+            }
+            if let Some(max) = self.max_facts {
+                changes.extend(evict_facts(conn, max, &[id])?);
+            }
+            changes.push(FactChange::Remembered(fact_by_id(conn, id)?));
+            Ok(changes)
+        })?;
+        self.notify(&changes);
+        Ok(())
+    }
+
+    fn remember_facts(&self, facts: &[Fact]) -> Result<(), Error> {
+        tracing::debug!(count = facts.len(), "remembering facts");
+        let changes = self.transaction(|conn| {
+            let mut changes = delete_expired_facts(conn)?;
+            let mut ids = Vec::with_capacity(facts.len());
+            for fact in facts {
+                let id = upsert_fact(conn, &fact.category, &fact.key, &fact.value, None)?;
+                // updating a fact never makes it public again
+                if fact.private {
+                    conn.execute("UPDATE facts SET private = 1 WHERE id = ?1", [id])?;
+                }
+                ids.push(id);
+            }
+            for &id in &ids {
+                changes.push(FactChange::Remembered(fact_by_id(conn, id)?));
+            }
+            if let Some(max) = self.max_facts {
+                changes.extend(evict_facts(conn, max, &ids)?);
+            }
+            Ok(changes)
+        })?;
+        self.notify(&changes);
+        Ok(())
+    }
+
+    fn recent_facts(&self) -> Result<Vec<Fact>, Error> {
+        self.recent_facts_limited(RECENT_FACTS_LIMIT, 0)
+    }
+
+    fn set_fact_private(&self, category: &str, key: &str, private: bool) -> Result<bool, Error> {
+        tracing::debug!(category, key, private, "setting fact privacy");
+        let fact = self.transaction(|conn| {
+            let id: Option<i64> = conn
+                .query_row(
+                    "UPDATE facts SET private = ?3 WHERE category = ?1 AND key = ?2
+                    RETURNING id",
+                    params![category, key, private],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            id.map(|id| fact_by_id(conn, id)).transpose()
+        })?;
+        let Some(fact) = fact else {
+            return Ok(false);
+        };
+        self.notify(&[FactChange::Remembered(fact)]);
+        Ok(true)
+    }
 
-    pub fn recent_facts(&self) -> Result<Vec<Fact>, Error> {
+    fn get_fact(&self, category: &str, key: &str) -> Result<Option<String>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .query_row(
+                "SELECT value FROM facts
+                WHERE category = ?1 AND key = ?2
+                    AND (expires_at IS NULL OR expires_at > datetime('now'))",
+                [category, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn private_facts(&self) -> Result<Vec<Fact>, Error> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT category, key, value
+            "SELECT category, key, value, private, unixepoch(updated_at)
             FROM facts
-            ORDER BY updated_at DESC
-            LIMIT 50",
+            WHERE private = 1",
         )?;
 
         let facts = stmt
@@ -132,20 +746,374 @@ impl Database {
                     category: row.get(0)?,
                     key: row.get(1)?,
                     value: row.get(2)?,
+                    private: row.get(3)?,
+                    updated_at: row.get(4)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(facts)
+        Ok(facts)
+    }
+
+    fn facts_with_embeddings(&self) -> Result<Vec<EmbeddedFact>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT f.category, f.key, f.value, f.private, unixepoch(f.updated_at), e.vector
+            FROM facts f
+            LEFT JOIN fact_embeddings e ON e.fact_id = f.id AND e.value = f.value
+            WHERE f.expires_at IS NULL OR f.expires_at > datetime('now')
+            ORDER BY f.updated_at DESC, f.id DESC",
+        )?;
+
+        let facts = stmt
+            .query_map([], |row| {
+                let fact = Fact {
+                    category: row.get(0)?,
+                    key: row.get(1)?,
+                    value: row.get(2)?,
+                    private: row.get(3)?,
+                    updated_at: row.get(4)?,
+                };
+                let vector: Option<String> = row.get(5)?;
+                Ok((fact, vector))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        facts
+            .into_iter()
+            .map(|(fact, vector)| {
+                let vector = vector
+                    .map(|vector| serde_json::from_str(&vector))
+                    .transpose()
+                    .map_err(std::io::Error::from)?;
+                Ok((fact, vector))
+            })
+            .collect()
+    }
+
+    fn save_fact_embedding(&self, fact: &Fact, vector: &[f32]) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+        let vector = serde_json::to_string(vector).map_err(std::io::Error::from)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO fact_embeddings (fact_id, value, vector)
+            SELECT id, ?3, ?4 FROM facts WHERE category = ?1 AND key = ?2",
+            params![fact.category, fact.key, fact.value, vector],
+        )?;
+        Ok(())
+    }
+
+    fn remember_session_fact(&self, session_id: i64, key: &str, value: &str) -> Result<(), Error> {
+        tracing::debug!(session_id, key, "remembering session fact");
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO session_facts (session_id, key, value) VALUES (?1, ?2, ?3)
+            ON CONFLICT(session_id, key)
+                DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+            params![session_id, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn recent_session_facts(&self, session_id: i64) -> Result<Vec<SessionFact>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT key, value FROM session_facts
+            WHERE session_id = ?1
+            ORDER BY updated_at DESC, id DESC
+            LIMIT ?2",
+        )?;
+
+        let facts = stmt
+            .query_map(params![session_id, RECENT_FACTS_LIMIT], |row| {
+                Ok(SessionFact {
+                    key: row.get(0)?,
+                    value: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(facts)
+    }
+
+    fn save_approval_rule(&self, pattern: &str, example_command: &str) -> Result<(), Error> {
+        tracing::debug!(pattern, example_command, "saving approval rule");
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO approval_rules (pattern, example_command) VALUES (?1, ?2)",
+            [pattern, example_command],
+        )?;
+        Ok(())
+    }
+
+    fn find_matching_rule(&self, command: &str) -> Result<Option<i64>, Error> {
+        let rules = self.list_approval_rules()?;
+        for rule in rules {
+            if matches_rule(&rule.pattern, command) {
+                return Ok(Some(rule.id));
+            }
+        }
+        Ok(None)
+    }
+
+    fn store_blob(&self, source: &str, content: &str) -> Result<i64, Error> {
+        let conn = self.conn.lock().unwrap();
+        let id = conn.query_row(
+            "INSERT INTO blobs (source, content) VALUES (?1, ?2) RETURNING id",
+            [source, content],
+            |row| row.get(0),
+        )?;
+        tracing::debug!(id, source, chars = content.chars().count(), "stored blob");
+        Ok(id)
+    }
+
+    fn get_blob(&self, id: i64) -> Result<Option<String>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let content = conn
+            .query_row("SELECT content FROM blobs WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(content)
+    }
+
+    fn session_messages(&self, session_id: i64) -> Result<Vec<StoredMessage>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, role, content FROM messages
+            WHERE session_id = ?1
+            ORDER BY id",
+        )?;
+
+        let messages = stmt
+            .query_map([session_id], |row| {
+                let role: String = row.get(1)?;
+                let content: String = row.get(2)?;
+                Ok(StoredMessage {
+                    id: row.get(0)?,
+                    message: decode_message(&role, &content)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(messages)
+    }
+
+    fn append_messages(&self, session_id: i64, messages: &[Message]) -> Result<(), Error> {
+        self.transaction(|conn| {
+            for message in messages {
+                conn.execute(
+                    "INSERT INTO messages (session_id, role, content) VALUES (?1, ?2, ?3)",
+                    params![
+                        session_id,
+                        role_name(message.role),
+                        encode_content(&message.content)
+                    ],
+                )?;
+            }
+            conn.execute(
+                "UPDATE sessions SET updated_at = datetime('now') WHERE id = ?1",
+                [session_id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn replace_messages(
+        &self,
+        session_id: i64,
+        ids: &[i64],
+        summary: &Message,
+    ) -> Result<(), Error> {
+        let Some(&first) = ids.iter().min() else {
+            return Ok(());
+        };
+        let ids = serde_json::to_string(ids).expect("IDs serialize");
+
+        self.transaction(|conn| {
+            conn.execute(
+                "UPDATE messages SET role = ?1, content = ?2
+                WHERE id = ?3 AND session_id = ?4",
+                params![
+                    role_name(summary.role),
+                    encode_content(&summary.content),
+                    first,
+                    session_id
+                ],
+            )?;
+            conn.execute(
+                "DELETE FROM messages
+                WHERE session_id = ?1 AND id != ?2
+                    AND id IN (SELECT value FROM json_each(?3))",
+                params![session_id, first, ids],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    }
+}
+
+fn encode_content(content: &[MessageContent]) -> String {
+    serde_json::to_string(content).expect("message content serializes")
+}
+
+fn decode_message(role: &str, content: &str) -> rusqlite::Result<Message> {
+    let content: Vec<MessageContent> = serde_json::from_str(content).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok(match role {
+        "assistant" => Message::assistant_with_content(content),
+        _ => Message::user_with_content(content),
+    })
+}
+
+/// deletes facts past their expiry, returns them as forgotten
+fn delete_expired_facts(conn: &Connection) -> Result<Vec<FactChange>, Error> {
+    let expired = deleted_facts(
+        conn,
+        "DELETE FROM facts WHERE expires_at IS NOT NULL AND expires_at <= datetime('now')
+        RETURNING category, key",
+        [],
+    )?;
+    if !expired.is_empty() {
+        tracing::debug!(expired = expired.len(), "deleted expired facts");
+    }
+    Ok(expired)
+}
+
+/// runs a `DELETE ... RETURNING category, key`, returns what it deleted as
+/// forgotten
+fn deleted_facts(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<FactChange>, Error> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok(FactChange::Forgotten {
+            category: row.get(0)?,
+            key: row.get(1)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// inserts or updates a fact, returns its row ID
+fn upsert_fact(
+    conn: &Connection,
+    category: &str,
+    key: &str,
+    value: &str,
+    expires_in_secs: Option<u64>,
+) -> Result<i64, Error> {
+    let expires_in_secs = expires_in_secs.map(|secs| secs.min(i64::MAX as u64) as i64);
+    record_fact_history(conn, category, key, value)?;
+    let id = conn.query_row(
+        "INSERT INTO facts (category, key, value, source, expires_at)
+        VALUES (
+            ?1, ?2, ?3, 'agent',
+            CASE WHEN ?4 IS NULL THEN NULL ELSE datetime('now', '+' || ?4 || ' seconds') END
+        )
+        ON CONFLICT(category, key) DO UPDATE SET
+            value = excluded.value,
+            source = excluded.source,
+            expires_at = excluded.expires_at,
+            updated_at = datetime('now')
+        RETURNING id",
+        params![category, key, value, expires_in_secs],
+        |row| row.get(0),
+    )?;
+    Ok(id)
+}
+
+fn fact_by_id(conn: &Connection, id: i64) -> Result<Fact, Error> {
+    let fact = conn.query_row(
+        "SELECT category, key, value, private FROM facts WHERE id = ?1",
+        [id],
+        |row| {
+            Ok(Fact {
+                category: row.get(0)?,
+                key: row.get(1)?,
+                value: row.get(2)?,
+                private: row.get(3)?,
+                updated_at: None,
+            })
+        },
+    )?;
+    Ok(fact)
+}
+
+/// keeps the current value of a fact before it's overwritten with a
+/// different one, trimming the history to `FACT_HISTORY_LIMIT` entries
+fn record_fact_history(
+    conn: &Connection,
+    category: &str,
+    key: &str,
+    new_value: &str,
+) -> Result<(), Error> {
+    let recorded = conn.execute(
+        "INSERT INTO fact_history (category, key, value)
+        SELECT category, key, value FROM facts
+        WHERE category = ?1 AND key = ?2 AND value != ?3",
+        [category, key, new_value],
+    )?;
+    if recorded > 0 {
+        conn.execute(
+            "DELETE FROM fact_history
+            WHERE category = ?1 AND key = ?2 AND id NOT IN (
+                SELECT id FROM fact_history
+                WHERE category = ?1 AND key = ?2
+                ORDER BY id DESC
+                LIMIT ?3
+            )",
+            params![category, key, FACT_HISTORY_LIMIT as i64],
+        )?;
+    }
+    Ok(())
+}
+
+/// evicts the least recently updated agent facts until at most `max` remain,
+/// sparing the facts with the `keep` IDs. returns the evicted facts as
+/// forgotten.
+fn evict_facts(conn: &Connection, max: usize, keep: &[i64]) -> Result<Vec<FactChange>, Error> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM facts", [], |row| row.get(0))?;
+    let excess = (count as usize).saturating_sub(max);
+    if excess == 0 {
+        return Ok(Vec::new());
+    }
+
+    let keep = serde_json::to_string(keep).expect("IDs serialize");
+    let evicted = deleted_facts(
+        conn,
+        "DELETE FROM facts WHERE id IN (
+            SELECT id FROM facts
+            WHERE source = 'agent' AND id NOT IN (SELECT value FROM json_each(?1))
+            ORDER BY updated_at ASC, id ASC
+            LIMIT ?2
+        )
+        RETURNING category, key",
+        params![keep, excess as i64],
+    )?;
+    if !evicted.is_empty() {
+        tracing::info!(
+            evicted = evicted.len(),
+            max,
+            "evicted least recently updated facts"
+        );
     }
+    Ok(evicted)
 }
 
 /// matches a command against a rule pattern.
 /// tokens are space-separated. `*` as trailing wildcard matches any remaining args.
 /// `*` in a middle position matches exactly one token.
 /// for commands with pipes/chains (|, &&, ||, ;), each sub-command must match.
-#[allow(dead_code)]
-fn matches_rule(pattern: &str, command: &str) -> bool {
+pub fn matches_rule(pattern: &str, command: &str) -> bool {
     let sub_commands = split_subcommands(command);
 
     // every sub-command must match the pattern
@@ -154,6 +1122,15 @@ fn matches_rule(pattern: &str, command: &str) -> bool {
         .all(|sub| matches_single(pattern, sub.trim()))
 }
 
+/// like `matches_rule`, but a chained command matches when any one of its
+/// sub-commands does. for deny lists, where `ls && rm -rf ~` must still be
+/// caught by `rm *`.
+pub fn matches_any_subcommand(pattern: &str, command: &str) -> bool {
+    split_subcommands(command)
+        .iter()
+        .any(|sub| matches_single(pattern, sub.trim()))
+}
+
 #[allow(dead_code)]
 fn split_subcommands(command: &str) -> Vec<&str> {
     let mut parts = Vec::new();
@@ -254,7 +1231,23 @@ mod tests {
     fn test_migrations_run_cleanly() {
         let db = Database::open_in_memory().unwrap();
         let version = db.schema_version().unwrap();
-        assert_eq!(version, 3);
+        assert_eq!(version, 11);
+    }
+
+    #[test]
+    fn test_migrate_by_hand() {
+        let db = Database::open_unmigrated_at(":memory:").unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        assert_eq!(db.migrate(Some(3)).unwrap(), (0, 3));
+        // migrations only go forward
+        assert!(db.migrate(Some(2)).is_err());
+        assert!(db.migrate(Some(migrations::latest_version() + 1)).is_err());
+
+        assert_eq!(db.migrate(None).unwrap(), (3, 11));
+        assert_eq!(db.migrate(None).unwrap(), (11, 11));
+        assert_eq!(db.fact_count().unwrap(), 0);
+        assert_eq!(db.session_count().unwrap(), 0);
     }
 
     #[test]
@@ -265,14 +1258,16 @@ mod tests {
             migrations::migrate(&conn).unwrap();
         }
         let version = db.schema_version().unwrap();
-        assert_eq!(version, 3);
+        assert_eq!(version, 11);
     }
 
     #[test]
     fn test_remember_fact_upserts() {
         let db = Database::open_in_memory().unwrap();
-        db.remember_fact("user", "name", "alex").unwrap();
-        db.remember_fact("user", "name", "alex2").unwrap();
+        db.remember_fact("user", "name", "alex", None, false)
+            .unwrap();
+        db.remember_fact("user", "name", "alex2", None, false)
+            .unwrap();
 
         let conn = db.conn.lock().unwrap();
         let value: String = conn
@@ -286,6 +1281,696 @@ mod tests {
         assert_eq!(value, "alex2");
     }
 
+    /// records every change it's told about
+    #[derive(Clone, Default)]
+    struct RecordingObserver {
+        changes: Arc<Mutex<Vec<FactChange>>>,
+    }
+
+    impl FactObserver for RecordingObserver {
+        fn fact_changed(&self, change: &FactChange) {
+            self.changes.lock().unwrap().push(change.clone());
+        }
+    }
+
+    #[test]
+    fn test_fact_observer_sees_changes() {
+        let observer = RecordingObserver::default();
+        let db = Database::open_in_memory()
+            .unwrap()
+            .with_fact_observer(observer.clone());
+        let fact = |value: &str| Fact {
+            category: "calendar".into(),
+            key: "dentist".into(),
+            value: value.into(),
+            private: false,
+            updated_at: None,
+        };
+
+        db.remember_fact("calendar", "dentist", "tuesday 10:00", None, false)
+            .unwrap();
+        db.remember_facts(&[fact("wednesday 9:00")]).unwrap();
+        db.undo_fact("calendar", "dentist").unwrap();
+        assert!(db.forget_fact("calendar", "dentist").unwrap());
+        assert!(!db.forget_fact("calendar", "dentist").unwrap());
+
+        assert_eq!(
+            *observer.changes.lock().unwrap(),
+            [
+                FactChange::Remembered(fact("tuesday 10:00")),
+                FactChange::Remembered(fact("wednesday 9:00")),
+                FactChange::Remembered(fact("tuesday 10:00")),
+                FactChange::Forgotten {
+                    category: "calendar".into(),
+                    key: "dentist".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fact_observer_sees_privacy_expiry_and_eviction() {
+        let observer = RecordingObserver::default();
+        let db = Database::open_in_memory()
+            .unwrap()
+            .with_max_facts(Some(2))
+            .with_fact_observer(observer.clone());
+        let forgotten = |key: &str| FactChange::Forgotten {
+            category: "status".into(),
+            key: key.into(),
+        };
+
+        // private from the first notification on
+        db.remember_fact("health", "condition", "asthma", None, true)
+            .unwrap();
+        db.set_fact_private("health", "condition", false).unwrap();
+        db.remember_fact("status", "mood", "focused", Some(0), false)
+            .unwrap();
+        // the expired mood is cleaned up, then the oldest fact is evicted
+        db.remember_fact("status", "a", "1", None, false).unwrap();
+        db.remember_fact("status", "b", "2", None, false).unwrap();
+
+        let changes = observer.changes.lock().unwrap();
+        let FactChange::Remembered(first) = &changes[0] else {
+            panic!("expected the fact to be remembered");
+        };
+        assert!(first.private);
+        let FactChange::Remembered(made_public) = &changes[1] else {
+            panic!("expected the privacy change");
+        };
+        assert!(!made_public.private);
+        assert!(changes.contains(&forgotten("mood")));
+        assert_eq!(
+            changes
+                .iter()
+                .filter(|change| matches!(change, FactChange::Forgotten { .. }))
+                .count(),
+            2
+        );
+        assert_eq!(db.fact_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_undo_fact_restores_previous_value() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "city", "amsterdam", None, false)
+            .unwrap();
+        db.remember_fact("user", "city", "somewhere", None, false)
+            .unwrap();
+
+        assert_eq!(
+            db.undo_fact("user", "city").unwrap().as_deref(),
+            Some("amsterdam")
+        );
+        assert_eq!(
+            db.get_fact("user", "city").unwrap().as_deref(),
+            Some("amsterdam")
+        );
+        // nothing earlier than the first value
+        assert_eq!(db.undo_fact("user", "city").unwrap(), None);
+        assert_eq!(db.undo_fact("user", "missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_fact_history_is_capped() {
+        let db = Database::open_in_memory().unwrap();
+        for i in 0..FACT_HISTORY_LIMIT + 3 {
+            db.remember_fact("user", "mood", &format!("mood {i}"), None, false)
+                .unwrap();
+        }
+        // remembering the same value again isn't a change
+        let latest = format!("mood {}", FACT_HISTORY_LIMIT + 2);
+        db.remember_fact("user", "mood", &latest, None, false)
+            .unwrap();
+
+        let mut undone = 0;
+        while db.undo_fact("user", "mood").unwrap().is_some() {
+            undone += 1;
+        }
+        assert_eq!(undone, FACT_HISTORY_LIMIT);
+        assert_eq!(
+            db.get_fact("user", "mood").unwrap(),
+            Some("mood 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_private_facts_stay_private_when_updated() {
+        let db = Database::open_in_memory().unwrap();
+        let fact = |value: &str, private| Fact {
+            category: "finances".into(),
+            key: "salary".into(),
+            value: value.into(),
+            private,
+            updated_at: None,
+        };
+        db.remember_facts(&[fact("100k", true)]).unwrap();
+        db.remember_fact("finances", "salary", "110k", None, false)
+            .unwrap();
+        db.remember_facts(&[fact("120k", false)]).unwrap();
+
+        let facts = db.recent_facts().unwrap();
+        assert!(facts[0].updated_at.is_some());
+        assert_eq!(
+            facts,
+            vec![Fact {
+                updated_at: facts[0].updated_at,
+                ..fact("120k", true)
+            }]
+        );
+
+        assert!(db.set_fact_private("finances", "salary", false).unwrap());
+        assert!(!db.recent_facts().unwrap()[0].private);
+        assert!(!db.set_fact_private("finances", "missing", true).unwrap());
+    }
+
+    #[test]
+    fn test_get_fact() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "name", "alex", None, false)
+            .unwrap();
+
+        assert_eq!(
+            db.get_fact("user", "name").unwrap().as_deref(),
+            Some("alex")
+        );
+        assert_eq!(db.get_fact("user", "missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_expired_facts_are_excluded_and_cleaned_up() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("status", "project", "ava", Some(0), false)
+            .unwrap();
+        db.remember_fact("user", "name", "alex", None, false)
+            .unwrap();
+        db.remember_fact("status", "mood", "focused", Some(3600), false)
+            .unwrap();
+
+        let keys: Vec<_> = db
+            .recent_facts()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.key)
+            .collect();
+        assert!(!keys.contains(&"project".to_string()));
+        assert!(keys.contains(&"name".to_string()));
+        assert!(keys.contains(&"mood".to_string()));
+        assert_eq!(db.get_fact("status", "project").unwrap(), None);
+
+        // the expired row is gone after the next write
+        assert_eq!(db.delete_expired_facts().unwrap(), 0);
+        let conn = db.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM facts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_remembering_without_ttl_clears_expiry() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "city", "berlin", Some(0), false)
+            .unwrap();
+        db.remember_fact("user", "city", "berlin", None, false)
+            .unwrap();
+
+        assert_eq!(
+            db.get_fact("user", "city").unwrap().as_deref(),
+            Some("berlin")
+        );
+    }
+
+    #[test]
+    fn test_remember_facts_batch() {
+        let db = Database::open_in_memory().unwrap();
+        let fact = |key: &str, value: &str| Fact {
+            category: "user".into(),
+            key: key.into(),
+            value: value.into(),
+            private: false,
+            updated_at: None,
+        };
+
+        db.remember_facts(&[
+            fact("name", "alex"),
+            fact("city", "amsterdam"),
+            fact("language", "dutch"),
+        ])
+        .unwrap();
+
+        assert_eq!(db.fact_count().unwrap(), 3);
+        assert_eq!(
+            db.get_fact("user", "name").unwrap().as_deref(),
+            Some("alex")
+        );
+        assert_eq!(
+            db.get_fact("user", "city").unwrap().as_deref(),
+            Some("amsterdam")
+        );
+        assert_eq!(
+            db.get_fact("user", "language").unwrap().as_deref(),
+            Some("dutch")
+        );
+    }
+
+    #[test]
+    fn test_remember_facts_batch_is_atomic() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            // make the third insert fail partway through the batch
+            conn.execute_batch(
+                "CREATE TRIGGER reject_bad BEFORE INSERT ON facts
+                WHEN NEW.key = 'bad'
+                BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+            )
+            .unwrap();
+        }
+        let fact = |key: &str| Fact {
+            category: "user".into(),
+            key: key.into(),
+            value: "v".into(),
+            private: false,
+            updated_at: None,
+        };
+
+        let result = db.remember_facts(&[fact("a"), fact("b"), fact("bad")]);
+
+        assert!(result.is_err());
+        assert_eq!(db.fact_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_error() {
+        let db = Database::open_in_memory().unwrap();
+
+        let result: Result<(), Error> = db.transaction(|conn| {
+            upsert_fact(conn, "user", "name", "alex", None)?;
+            upsert_fact(conn, "user", "city", "amsterdam", None)?;
+            Err(Error::Provider("something failed midway".into()))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(db.fact_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_transaction_commits_on_success() {
+        let db = Database::open_in_memory().unwrap();
+
+        let id = db
+            .transaction(|conn| upsert_fact(conn, "user", "name", "alex", None))
+            .unwrap();
+
+        assert!(id > 0);
+        assert_eq!(
+            db.get_fact("user", "name").unwrap().as_deref(),
+            Some("alex")
+        );
+    }
+
+    #[test]
+    fn test_backup_keeps_facts() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "name", "alex", None, false)
+            .unwrap();
+        db.remember_fact("calendar", "dentist", "tuesday 10:00", None, false)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("ava-backup-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let pages = db.backup_to(&path).unwrap();
+        assert!(pages > 0);
+        // an existing file is never overwritten
+        assert!(matches!(db.backup_to(&path), Err(Error::Io(_))));
+
+        let restored = Database::open_at(&path).unwrap();
+        assert_eq!(
+            restored.recent_facts_limited(10, 0).unwrap(),
+            db.recent_facts_limited(10, 0).unwrap()
+        );
+        assert_eq!(restored.fact_count().unwrap(), 2);
+        drop(restored);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_leaves_an_old_schema_alone() {
+        let dir = std::env::temp_dir();
+        let source = dir.join(format!("ava-backup-source-{}.db", std::process::id()));
+        let copy = dir.join(format!("ava-backup-copy-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&source);
+        let _ = std::fs::remove_file(&copy);
+        Database::open_unmigrated_at(&source)
+            .unwrap()
+            .migrate(Some(3))
+            .unwrap();
+
+        let db = Database::open_existing_at(&source).unwrap();
+        db.backup_to(&copy).unwrap();
+        drop(db);
+
+        let source_version = Database::open_existing_at(&source)
+            .unwrap()
+            .schema_version()
+            .unwrap();
+        assert_eq!(source_version, 3);
+        let copy_version = Database::open_existing_at(&copy)
+            .unwrap()
+            .schema_version()
+            .unwrap();
+        assert_eq!(copy_version, 3);
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&copy).unwrap();
+
+        // a missing database is an error, not a new empty file
+        assert!(matches!(
+            Database::open_existing_at(&source),
+            Err(Error::Io(_))
+        ));
+        assert!(!source.exists());
+    }
+
+    #[test]
+    fn test_has_table_on_an_old_schema() {
+        let db = Database::open_unmigrated_at(":memory:").unwrap();
+        assert!(!db.has_table("facts").unwrap());
+
+        // v1 has sessions but facts only arrive in v2
+        db.migrate(Some(1)).unwrap();
+        assert!(db.has_table("sessions").unwrap());
+        assert_eq!(db.session_count().unwrap(), 0);
+        assert!(!db.has_table("facts").unwrap());
+
+        db.migrate(None).unwrap();
+        assert!(db.has_table("facts").unwrap());
+    }
+
+    #[test]
+    fn test_get_session() {
+        let db = Database::open_in_memory().unwrap();
+        let id = db.create_session("telegram:1").unwrap();
+
+        let session = db.get_session(id).unwrap().unwrap();
+        assert_eq!(session.id, id);
+        assert_eq!(session.channel.as_deref(), Some("telegram:1"));
+        assert!(!session.created_at.is_empty());
+        assert_eq!(db.get_session(id + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_session_messages_round_trip() {
+        let db = Database::open_in_memory().unwrap();
+        let session = db.create_session("cli").unwrap();
+        let other = db.create_session("telegram:1").unwrap();
+
+        db.append_messages(
+            session,
+            &[
+                Message::user("hi"),
+                Message::assistant_with_content(vec![MessageContent::tool_use(
+                    "call_1",
+                    "whoami",
+                    serde_json::json!({}),
+                )]),
+                Message::user_with_content(vec![MessageContent::tool_result("call_1", "alex")]),
+            ],
+        )
+        .unwrap();
+
+        let messages = db.session_messages(session).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].message.role, Role::User);
+        assert_eq!(messages[1].message.role, Role::Assistant);
+        assert!(matches!(
+            &messages[2].message.content[0],
+            MessageContent::ToolResult { content, .. } if content == "alex"
+        ));
+        assert!(db.session_messages(other).unwrap().is_empty());
+
+        assert_eq!(db.latest_session("cli").unwrap(), Some(session));
+        assert_eq!(db.resume_or_create_session("cli", None).unwrap(), session);
+        assert_eq!(db.latest_session("telegram:2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_foreign_keys_cascade_deletes() {
+        let db = Database::open_in_memory().unwrap();
+        let count = |table: &str| -> i64 {
+            let conn = db.conn.lock().unwrap();
+            conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        // forgetting a fact drops its embedding
+        db.remember_fact("user", "city", "berlin", None, false)
+            .unwrap();
+        let fact = db.recent_facts().unwrap().remove(0);
+        db.save_fact_embedding(&fact, &[0.1, 0.2]).unwrap();
+        assert_eq!(count("fact_embeddings"), 1);
+        assert!(db.forget_fact("user", "city").unwrap());
+        assert_eq!(count("fact_embeddings"), 0);
+
+        // deleting a session drops its messages and session facts
+        let pruned = db.create_session("cli").unwrap();
+        db.create_session("cli").unwrap();
+        db.remember_session_fact(pruned, "task", "plan the trip")
+            .unwrap();
+        assert_eq!(count("session_facts"), 1);
+        assert_eq!(db.prune_empty_sessions(None).unwrap(), 1);
+        assert_eq!(count("session_facts"), 0);
+        assert!(db.append_messages(pruned, &[Message::user("hi")]).is_err());
+
+        // approval rules aren't tied to anything
+        db.save_approval_rule("ls *", "ls -la").unwrap();
+        let rule = db.list_approval_rules().unwrap()[0].id;
+        assert!(db.delete_approval_rule(rule).unwrap());
+        assert_eq!(count("approval_rules"), 0);
+    }
+
+    #[test]
+    fn test_prune_empty_sessions() {
+        let db = Database::open_in_memory().unwrap();
+        let empty = db.create_session("cli").unwrap();
+        let old = db.create_session("cli").unwrap();
+        db.append_messages(old, &[Message::user("last week")])
+            .unwrap();
+        let kept = db.create_session("cli").unwrap();
+        db.append_messages(kept, &[Message::user("hi")]).unwrap();
+        // the only session on its channel, so it's kept despite being empty
+        let latest = db.create_session("telegram:1").unwrap();
+
+        assert_eq!(db.prune_empty_sessions(None).unwrap(), 1);
+        assert_eq!(db.get_session(empty).unwrap(), None);
+        assert!(db.get_session(old).unwrap().is_some());
+        assert!(db.get_session(latest).unwrap().is_some());
+
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute(
+                "UPDATE sessions SET created_at = datetime('now', '-8 days') WHERE id = ?1",
+                [old],
+            )
+            .unwrap();
+        }
+        let week = Some(Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(db.prune_empty_sessions(week).unwrap(), 1);
+        assert_eq!(db.get_session(old).unwrap(), None);
+        // its messages went with it
+        assert!(db.session_messages(old).unwrap().is_empty());
+        assert_eq!(db.session_messages(kept).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_idle_session_rolls_over() {
+        let db = Database::open_in_memory().unwrap();
+        let idle = Some(Duration::from_secs(30 * 60));
+        let stale = db.create_session("telegram:1").unwrap();
+        let fresh = db.create_session("telegram:2").unwrap();
+        db.append_messages(stale, &[Message::user("yesterday's question")])
+            .unwrap();
+        db.append_messages(fresh, &[Message::user("just now")])
+            .unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute(
+                "UPDATE messages SET created_at = datetime('now', '-1 day') WHERE session_id = ?1",
+                [stale],
+            )
+            .unwrap();
+        }
+
+        assert!(db.last_message_time(stale).unwrap().is_some());
+        assert_eq!(
+            db.last_message_time(db.create_session("cli").unwrap())
+                .unwrap(),
+            None
+        );
+
+        let next = db.resume_or_create_session("telegram:1", idle).unwrap();
+        assert_ne!(next, stale);
+        // the new session hasn't seen a message yet, but it's not idle
+        assert_eq!(
+            db.resume_or_create_session("telegram:1", idle).unwrap(),
+            next
+        );
+        assert_eq!(
+            db.resume_or_create_session("telegram:2", idle).unwrap(),
+            fresh
+        );
+    }
+
+    #[test]
+    fn test_replace_messages_keeps_position() {
+        let db = Database::open_in_memory().unwrap();
+        let session = db.create_session("cli").unwrap();
+        db.append_messages(
+            session,
+            &[
+                Message::user("one"),
+                Message::assistant("two"),
+                Message::user("three"),
+                Message::assistant("four"),
+            ],
+        )
+        .unwrap();
+        let ids: Vec<i64> = db
+            .session_messages(session)
+            .unwrap()
+            .iter()
+            .take(3)
+            .map(|stored| stored.id)
+            .collect();
+
+        db.replace_messages(session, &ids, &Message::user("summary"))
+            .unwrap();
+
+        let messages = db.session_messages(session).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            &messages[0].message.content[0],
+            MessageContent::Text { text } if text == "summary"
+        ));
+        assert!(matches!(
+            &messages[1].message.content[0],
+            MessageContent::Text { text } if text == "four"
+        ));
+    }
+
+    #[test]
+    fn test_facts_updated_since() {
+        let db = Database::open_in_memory().unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            for (key, updated_at) in [
+                ("old", "2024-01-01 00:00:00"),
+                ("cutoff", "2024-06-01 12:00:00"),
+                ("newer", "2024-06-01 12:00:01"),
+                ("newest", "2025-01-01 00:00:00"),
+            ] {
+                conn.execute(
+                    "INSERT INTO facts (category, key, value, updated_at) VALUES ('user', ?1, 'x', ?2)",
+                    [key, updated_at],
+                )
+                .unwrap();
+            }
+        }
+
+        let facts = db.facts_updated_since("2024-06-01 12:00:00").unwrap();
+
+        let keys: Vec<&str> = facts.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, vec!["newest", "newer"]);
+        assert!(
+            db.facts_updated_since("2025-01-01 00:00:00")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_session_facts_stay_in_their_session() {
+        let db = Database::open_in_memory().unwrap();
+        let cli = db.create_session("cli").unwrap();
+        let telegram = db.create_session("telegram:1").unwrap();
+
+        db.remember_session_fact(cli, "plan", "compare flights")
+            .unwrap();
+        db.remember_session_fact(cli, "plan", "compare trains")
+            .unwrap();
+        db.remember_session_fact(telegram, "mood", "busy").unwrap();
+
+        let facts = db.recent_session_facts(cli).unwrap();
+        assert_eq!(
+            facts,
+            vec![SessionFact {
+                key: "plan".into(),
+                value: "compare trains".into(),
+            }]
+        );
+        // never permanent facts
+        assert!(db.recent_facts().unwrap().is_empty());
+
+        // a new cli session ends the old one, other channels keep theirs
+        let next = db.create_session("cli").unwrap();
+        assert!(db.recent_session_facts(next).unwrap().is_empty());
+        assert!(db.recent_session_facts(cli).unwrap().is_empty());
+        assert_eq!(db.recent_session_facts(telegram).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_blobs_round_trip() {
+        let db = Database::open_in_memory().unwrap();
+
+        let id = db
+            .store_blob("https://example.com", "a long article")
+            .unwrap();
+
+        assert_eq!(db.get_blob(id).unwrap().as_deref(), Some("a long article"));
+        assert_eq!(db.get_blob(id + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_max_facts_evicts_oldest_agent_fact() {
+        let db = Database::open_in_memory().unwrap().with_max_facts(Some(3));
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO facts (category, key, value, source, updated_at)
+                VALUES ('user', 'name', 'alex', 'user', '2020-01-01 00:00:00')",
+                [],
+            )
+            .unwrap();
+        }
+        db.remember_fact("status", "a", "1", None, false).unwrap();
+        db.remember_fact("status", "b", "2", None, false).unwrap();
+        assert_eq!(db.fact_count().unwrap(), 3);
+
+        db.remember_fact("status", "c", "3", None, false).unwrap();
+
+        assert_eq!(db.fact_count().unwrap(), 3);
+        assert_eq!(db.get_fact("status", "a").unwrap(), None);
+        assert_eq!(db.get_fact("status", "b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get_fact("status", "c").unwrap().as_deref(), Some("3"));
+        // the oldest fact overall, but set by the user
+        assert_eq!(
+            db.get_fact("user", "name").unwrap().as_deref(),
+            Some("alex")
+        );
+    }
+
+    #[test]
+    fn test_no_fact_cap_by_default() {
+        let db = Database::open_in_memory().unwrap();
+        for i in 0..10 {
+            db.remember_fact("status", &format!("k{i}"), "v", None, false)
+                .unwrap();
+        }
+        assert_eq!(db.fact_count().unwrap(), 10);
+    }
+
     #[test]
     fn test_recent_facts_limit_and_order() {
         let db = Database::open_in_memory().unwrap();
@@ -311,11 +1996,42 @@ mod tests {
         assert_eq!(facts.last().unwrap().key, "k05");
     }
 
+    #[test]
+    fn test_recent_facts_limited_pages() {
+        let db = Database::open_in_memory().unwrap();
+
+        {
+            let conn = db.conn.lock().unwrap();
+            for i in 0..60 {
+                let key = format!("k{i:02}");
+                let updated_at = format!("2024-01-01 00:00:{i:02}");
+                conn.execute(
+                    "INSERT INTO facts (category, key, value, updated_at)
+                    VALUES ('user', ?1, 'v', ?2)",
+                    [&key, &updated_at],
+                )
+                .unwrap();
+            }
+        }
+
+        let first = db.recent_facts_limited(25, 0).unwrap();
+        assert_eq!(first.len(), 25);
+        assert_eq!(first[0].key, "k59");
+        assert_eq!(first[24].key, "k35");
+
+        let third = db.recent_facts_limited(25, 50).unwrap();
+        assert_eq!(third.len(), 10);
+        assert_eq!(third[0].key, "k09");
+        assert_eq!(third[9].key, "k00");
+
+        assert!(db.recent_facts_limited(25, 75).unwrap().is_empty());
+    }
+
     #[test]
     fn test_save_and_list_approval_rules() {
         let db = Database::open_in_memory().unwrap();
-        db.save_approval_rule("ls *").unwrap();
-        db.save_approval_rule("cargo *").unwrap();
+        db.save_approval_rule("ls *", "ls -la").unwrap();
+        db.save_approval_rule("cargo *", "cargo build").unwrap();
 
         let rules = db.list_approval_rules().unwrap();
         assert_eq!(rules.len(), 2);
@@ -323,11 +2039,30 @@ mod tests {
         assert_eq!(rules[1].pattern, "cargo *");
     }
 
+    #[test]
+    fn test_approval_rule_records_example_command() {
+        let db = Database::open_in_memory().unwrap();
+        db.save_approval_rule("cargo *", "cargo test --workspace")
+            .unwrap();
+        // a later command matching the same pattern keeps the first example
+        db.save_approval_rule("cargo *", "cargo build").unwrap();
+
+        let rules = db.list_approval_rules().unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(
+            rules[0].example_command.as_deref(),
+            Some("cargo test --workspace")
+        );
+        // sqlite's `YYYY-MM-DD HH:MM:SS`
+        assert_eq!(rules[0].created_at.len(), 19);
+        assert_eq!(&rules[0].created_at[10..11], " ");
+    }
+
     #[test]
     fn test_save_approval_rule_ignores_duplicate() {
         let db = Database::open_in_memory().unwrap();
-        db.save_approval_rule("ls *").unwrap();
-        db.save_approval_rule("ls *").unwrap();
+        db.save_approval_rule("ls *", "ls -la").unwrap();
+        db.save_approval_rule("ls *", "ls -la").unwrap();
 
         let rules = db.list_approval_rules().unwrap();
         assert_eq!(rules.len(), 1);
@@ -336,7 +2071,7 @@ mod tests {
     #[test]
     fn test_delete_approval_rule() {
         let db = Database::open_in_memory().unwrap();
-        db.save_approval_rule("ls *").unwrap();
+        db.save_approval_rule("ls *", "ls -la").unwrap();
 
         let rules = db.list_approval_rules().unwrap();
         assert!(db.delete_approval_rule(rules[0].id).unwrap());
@@ -346,7 +2081,7 @@ mod tests {
     #[test]
     fn test_find_matching_rule() {
         let db = Database::open_in_memory().unwrap();
-        db.save_approval_rule("ls *").unwrap();
+        db.save_approval_rule("ls *", "ls -la").unwrap();
 
         assert!(db.find_matching_rule("ls -la").unwrap().is_some());
         assert!(db.find_matching_rule("ls").unwrap().is_some());
@@ -0,0 +1,329 @@
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, EmbeddingSettings};
+use crate::db::{Fact, Store};
+use crate::error::Error;
+
+/// turns text into a vector, so facts can be compared by meaning rather than
+/// by the words they share
+pub trait Embedder: Send + Sync {
+    fn embed(&self, texts: &[String]) -> impl Future<Output = Result<Vec<Vec<f32>>, Error>> + Send;
+}
+
+/// an embedder for openai style `/embeddings` endpoints. anthropic doesn't
+/// offer embeddings, but openai does and ollama serves the same API.
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    settings: EmbeddingSettings,
+    api_key: Option<String>,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(settings: EmbeddingSettings, api_key: Option<String>) -> Self {
+        Self {
+            client: crate::http::client(),
+            settings,
+            api_key,
+        }
+    }
+
+    /// `None` unless AVA_EMBEDDING_URL is set. the key comes from
+    /// AVA_EMBEDDING_API_KEY, which a local ollama doesn't need.
+    pub fn from_env() -> Option<Self> {
+        let settings = config::embedding_settings()?;
+        let api_key = std::env::var("AVA_EMBEDDING_API_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty());
+        Some(Self::new(settings, api_key))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Embedder for OpenAiEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+        let mut request = self
+            .client
+            .post(&self.settings.url)
+            .json(&EmbeddingRequest {
+                model: &self.settings.model,
+                input: texts,
+            });
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(match status.as_u16() {
+                401 => Error::Unauthorized(message),
+                403 => Error::Forbidden(message),
+                429 => Error::RateLimited(message),
+                _ => Error::Provider(format!("embedding request failed ({status}): {message}")),
+            });
+        }
+
+        let mut response: EmbeddingResponse = response.json().await?;
+        if response.data.len() != texts.len() {
+            return Err(Error::Provider(format!(
+                "asked for {} embeddings, got {}",
+                texts.len(),
+                response.data.len()
+            )));
+        }
+        response.data.sort_by_key(|data| data.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect())
+    }
+}
+
+/// the facts most relevant to `query`, best first. with an embedder, facts
+/// are ranked by how similar their values are to the query, embedding any
+/// that haven't been yet. without one, facts sharing a word with the query
+/// are returned, most recently updated first. private facts are left out
+/// unless `include_private`.
+pub async fn recall_facts(
+    store: &impl Store,
+    embedder: Option<&impl Embedder>,
+    query: &str,
+    limit: usize,
+    include_private: bool,
+) -> Result<Vec<Fact>, Error> {
+    let mut facts = store.facts_with_embeddings()?;
+    if !include_private {
+        facts.retain(|(fact, _)| !fact.private);
+    }
+    let Some(embedder) = embedder else {
+        return Ok(keyword_matches(
+            facts.into_iter().map(|(fact, _)| fact),
+            query,
+            limit,
+        ));
+    };
+
+    let missing: Vec<&Fact> = facts
+        .iter()
+        .filter(|(_, vector)| vector.is_none())
+        .map(|(fact, _)| fact)
+        .collect();
+    let mut texts: Vec<String> = missing.iter().map(|fact| embedding_text(fact)).collect();
+    texts.push(query.to_string());
+    let mut vectors = embedder.embed(&texts).await?;
+    let query_vector = vectors.pop().unwrap_or_default();
+    for (fact, vector) in missing.iter().zip(&vectors) {
+        store.save_fact_embedding(fact, vector)?;
+    }
+
+    let mut new_vectors = vectors.into_iter();
+    let candidates = facts.into_iter().filter_map(|(fact, vector)| {
+        let vector = vector.or_else(|| new_vectors.next())?;
+        Some((fact, vector))
+    });
+    Ok(rank_by_similarity(&query_vector, candidates, limit))
+}
+
+/// embeds facts as they're remembered, so recall only has to embed the
+/// query
+pub async fn embed_facts(
+    store: &impl Store,
+    embedder: &impl Embedder,
+    facts: &[Fact],
+) -> Result<(), Error> {
+    let texts: Vec<String> = facts.iter().map(embedding_text).collect();
+    let vectors = embedder.embed(&texts).await?;
+    for (fact, vector) in facts.iter().zip(&vectors) {
+        store.save_fact_embedding(fact, vector)?;
+    }
+    Ok(())
+}
+
+/// what gets embedded for a fact. the key gives short values their meaning,
+/// e.g. `timezone: CET`.
+fn embedding_text(fact: &Fact) -> String {
+    format!("{}: {}", fact.key, fact.value)
+}
+
+fn rank_by_similarity(
+    query: &[f32],
+    candidates: impl Iterator<Item = (Fact, Vec<f32>)>,
+    limit: usize,
+) -> Vec<Fact> {
+    let mut scored: Vec<(f32, Fact)> = candidates
+        .map(|(fact, vector)| (cosine_similarity(query, &vector), fact))
+        .collect();
+    // the sort is stable, so ties keep the most recently updated first
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, fact)| fact)
+        .collect()
+}
+
+/// 1 for vectors pointing the same way, 0 for unrelated ones. vectors of
+/// different lengths, from different models, count as unrelated.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+fn keyword_matches(facts: impl Iterator<Item = Fact>, query: &str, limit: usize) -> Vec<Fact> {
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+        .collect();
+    facts
+        .filter(|fact| {
+            let text = format!("{} {} {}", fact.category, fact.key, fact.value).to_lowercase();
+            words.iter().any(|word| text.contains(word.as_str()))
+        })
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use std::sync::Mutex;
+
+    /// maps each text to a vector by the topic words it contains
+    struct MockEmbedder {
+        calls: Mutex<usize>,
+        texts: Mutex<usize>,
+    }
+
+    impl Embedder for MockEmbedder {
+        async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+            *self.calls.lock().unwrap() += 1;
+            *self.texts.lock().unwrap() += texts.len();
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    let has = |word: &str| if text.contains(word) { 1.0 } else { 0.0 };
+                    vec![has("cat"), has("pasta"), has("berlin")]
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recall_ranks_by_cosine_similarity() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "pet", "a cat named miso", None, false)
+            .unwrap();
+        db.remember_fact(
+            "user",
+            "food",
+            "pasta, also cat-shaped cookies",
+            None,
+            false,
+        )
+        .unwrap();
+        db.remember_fact("user", "city", "berlin", None, false)
+            .unwrap();
+        let embedder = MockEmbedder {
+            calls: Mutex::new(0),
+            texts: Mutex::new(0),
+        };
+
+        let facts = recall_facts(&db, Some(&embedder), "what's my cat called?", 2, true)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = facts.iter().map(|fact| fact.key.as_str()).collect();
+        assert_eq!(keys, ["pet", "food"]);
+
+        // stored vectors are reused, only the query is embedded again
+        let facts = recall_facts(&db, Some(&embedder), "berlin", 1, true)
+            .await
+            .unwrap();
+        assert_eq!(facts[0].key, "city");
+        assert_eq!(*embedder.calls.lock().unwrap(), 2);
+        let embedded = db
+            .facts_with_embeddings()
+            .unwrap()
+            .iter()
+            .filter(|(_, vector)| vector.is_some())
+            .count();
+        assert_eq!(embedded, 3);
+    }
+
+    #[tokio::test]
+    async fn test_recall_without_embedder_matches_keywords() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "pet", "a cat named miso", None, false)
+            .unwrap();
+        db.remember_fact("user", "city", "berlin", None, false)
+            .unwrap();
+        db.remember_fact("health", "clinic", "berlin mitte", None, true)
+            .unwrap();
+
+        let facts = recall_facts(&db, None::<&MockEmbedder>, "Berlin weather", 5, false)
+            .await
+            .unwrap();
+
+        assert_eq!(facts.len(), 1);
+        assert_eq!(facts[0].key, "city");
+    }
+
+    #[tokio::test]
+    async fn test_remembered_facts_are_embedded_up_front() {
+        let db = Database::open_in_memory().unwrap();
+        db.remember_fact("user", "pet", "a cat named miso", None, false)
+            .unwrap();
+        let fact = db.recent_facts().unwrap().remove(0);
+        let embedder = MockEmbedder {
+            calls: Mutex::new(0),
+            texts: Mutex::new(0),
+        };
+
+        embed_facts(&db, &embedder, &[fact]).await.unwrap();
+        recall_facts(&db, Some(&embedder), "cat", 1, true)
+            .await
+            .unwrap();
+
+        let (_, vector) = db.facts_with_embeddings().unwrap().remove(0);
+        assert_eq!(vector, Some(vec![1.0, 0.0, 0.0]));
+        // recall only had the query left to embed
+        assert_eq!(*embedder.texts.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
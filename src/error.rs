@@ -20,9 +20,28 @@ pub enum Error {
     #[error("provider error: {0}")]
     Provider(String),
 
+    /// the prompt didn't fit in the model's context window
+    #[error("prompt too long: {0}")]
+    ContextTooLong(String),
+
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+
+    /// the API rejected our credentials
+    #[error("authentication failed: {0}")]
+    Unauthorized(String),
+
+    /// the credentials are fine, but they aren't allowed to do this
+    #[error("permission denied: {0}")]
+    Forbidden(String),
+
     #[error("telegram error: {0}")]
     Telegram(String),
 
+    /// telegram failed on its end (5xx), worth trying again
+    #[error("telegram server error: {0}")]
+    TelegramServer(String),
+
     #[allow(dead_code)]
     #[error("command timed out after {0}s")]
     ExecTimeout(u64),
@@ -33,4 +52,82 @@ pub enum Error {
 
     #[error("approval timed out")]
     ApprovalTimeout,
+
+    #[error("turn cancelled")]
+    Cancelled,
+
+    /// the whole turn ran past `AVA_TURN_TIMEOUT`
+    #[error("turn timed out after {0}s")]
+    TurnTimeout(u64),
+
+    /// a config file that can't be used as it is
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+}
+
+impl Error {
+    /// a short, friendly explanation for the person chatting with the bot.
+    /// the details stay in the logs.
+    pub fn user_message(&self) -> String {
+        match self {
+            Self::Database(_) => "something went wrong with my memory, try again in a bit".into(),
+            Self::Http(e) if e.is_timeout() => "that took too long, try again shortly".into(),
+            Self::Http(_) => "i couldn't reach a service i need, try again shortly".into(),
+            Self::Io(_) => "something went wrong on my end, try again in a bit".into(),
+            Self::MissingApiKey(_) | Self::Unauthorized(_) => {
+                "my api key looks misconfigured".into()
+            }
+            Self::Forbidden(_) => "my api key isn't allowed to do that".into(),
+            Self::MissingEnvVar(name) => format!("i'm missing some configuration ({name})"),
+            Self::RateLimited(_) => "i'm being throttled, try again shortly".into(),
+            Self::Provider(_) => "the model ran into an error, try again".into(),
+            Self::ContextTooLong(_) => {
+                "this conversation got too long for me, try starting a new one".into()
+            }
+            Self::Telegram(_) | Self::TelegramServer(_) => {
+                "telegram ran into an error, try again".into()
+            }
+            Self::ExecTimeout(secs) => format!("the command timed out after {secs}s"),
+            Self::ExecDenied => "the command was denied".into(),
+            Self::ApprovalTimeout => {
+                "nobody answered the approval request in time, send your message again to retry"
+                    .into()
+            }
+            Self::Cancelled => "cancelled".into(),
+            Self::TurnTimeout(_) => "that took too long, so i stopped. try again".into(),
+            Self::InvalidConfig(_) => "my configuration has a mistake in it".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_message_hides_details() {
+        let error = Error::RateLimited("number of request tokens has exceeded your limit".into());
+        assert_eq!(
+            error.user_message(),
+            "i'm being throttled, try again shortly"
+        );
+
+        let error = Error::Unauthorized("invalid x-api-key".into());
+        assert_eq!(error.user_message(), "my api key looks misconfigured");
+
+        let error = Error::MissingApiKey("ANTHROPIC_API_KEY");
+        assert_eq!(error.user_message(), "my api key looks misconfigured");
+
+        let error = Error::Database(rusqlite::Error::InvalidQuery);
+        assert!(!error.user_message().contains("query"));
+    }
+
+    #[test]
+    fn test_user_message_keeps_useful_specifics() {
+        assert_eq!(
+            Error::ExecTimeout(30).user_message(),
+            "the command timed out after 30s"
+        );
+        assert_eq!(Error::Cancelled.user_message(), "cancelled");
+    }
 }
@@ -0,0 +1,45 @@
+use std::sync::OnceLock;
+
+use crate::config::{self, HttpProxy};
+use crate::error::Error;
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// builds the shared client, so a bad AVA_HTTP_PROXY stops ava at startup
+/// instead of quietly connecting without the proxy
+pub fn init() -> Result<(), Error> {
+    if CLIENT.get().is_none() {
+        let client = build_client()?;
+        let _ = CLIENT.set(client);
+    }
+    Ok(())
+}
+
+/// one client for everything that talks HTTP, so connections are reused and
+/// AVA_HTTP_PROXY applies everywhere
+pub fn client() -> reqwest::Client {
+    CLIENT
+        .get_or_init(|| build_client().expect("the http client is checked by `init` at startup"))
+        .clone()
+}
+
+fn build_client() -> Result<reqwest::Client, Error> {
+    Ok(client_builder(config::http_proxy().as_ref())?.build()?)
+}
+
+/// a client builder that sends every request through `proxy`, except to
+/// hosts in NO_PROXY. an invalid proxy URL is an error, since the proxy may
+/// be the only way out that's allowed.
+pub fn client_builder(proxy: Option<&HttpProxy>) -> Result<reqwest::ClientBuilder, Error> {
+    let builder = reqwest::Client::builder();
+    let Some(proxy) = proxy else {
+        return Ok(builder);
+    };
+    let mut configured = reqwest::Proxy::all(&proxy.url)
+        .map_err(|e| Error::InvalidConfig(format!("invalid http proxy url: {e}")))?
+        .no_proxy(reqwest::NoProxy::from_env());
+    if let Some((user, password)) = &proxy.auth {
+        configured = configured.basic_auth(user, password);
+    }
+    Ok(builder.proxy(configured))
+}
@@ -0,0 +1,93 @@
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::{Format, Json, JsonFields};
+
+use crate::channel::Verbosity;
+use crate::config::LogFormat;
+
+/// sets up the global subscriber. RUST_LOG still adds its directives on top
+/// of `level`.
+pub fn init(level: Level, format: LogFormat) {
+    let filter = EnvFilter::from_default_env().add_directive(level.into());
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .event_format(json_format())
+            .fmt_fields(JsonFields::new())
+            .init(),
+    }
+}
+
+/// the level to log at: --quiet only logs errors and --verbose logs at
+/// least debug, whatever AVA_LOG_LEVEL says
+pub fn level_for(verbosity: Verbosity, configured: Level) -> Level {
+    match verbosity {
+        Verbosity::Quiet => Level::ERROR,
+        Verbosity::Normal => configured,
+        Verbosity::Verbose => configured.max(Level::DEBUG),
+    }
+}
+
+/// one JSON object per event:
+/// `{"timestamp":..,"level":..,"fields":{..},"target":..,"spans":[..]}`
+fn json_format() -> Format<Json> {
+    tracing_subscriber::fmt::format()
+        .json()
+        .with_current_span(false)
+        .with_span_list(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_json_format_writes_one_object_per_event() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || WriteInto(writer.clone()))
+            .event_format(json_format())
+            .fmt_fields(JsonFields::new())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("turn");
+            let _entered = span.enter();
+            tracing::warn!(chat_id = 7, retry = true, "send failed");
+        });
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["spans"], serde_json::json!([{"name": "turn"}]));
+        assert_eq!(line["fields"]["message"], "send failed");
+        assert_eq!(line["fields"]["chat_id"], 7);
+        assert_eq!(line["fields"]["retry"], true);
+        assert!(line["timestamp"].as_str().is_some_and(|t| !t.is_empty()));
+    }
+
+    #[test]
+    fn test_verbosity_flags_set_the_log_level() {
+        assert_eq!(level_for(Verbosity::Quiet, Level::DEBUG), Level::ERROR);
+        assert_eq!(level_for(Verbosity::Normal, Level::WARN), Level::WARN);
+        assert_eq!(level_for(Verbosity::Verbose, Level::INFO), Level::DEBUG);
+        assert_eq!(level_for(Verbosity::Verbose, Level::TRACE), Level::TRACE);
+    }
+
+    struct WriteInto(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for WriteInto {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -1,30 +1,55 @@
 mod agent;
 mod approver;
 mod channel;
+mod completions;
 mod config;
 mod db;
+mod embedding;
 mod error;
+mod http;
+mod log;
 mod message;
+mod policy;
 mod provider;
 mod telegram;
+mod text;
 mod tool;
+mod transcript;
+mod webhook;
 
+use std::collections::HashSet;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use futures_util::StreamExt;
 
-use crate::agent::Agent;
+use crate::agent::{Agent, HistoryOptions, KnownFactsOptions};
 use crate::approver::{PendingApprovals, TelegramApprover};
 use crate::channel::Channel;
-use crate::db::Database;
-use crate::message::{ChannelKind, InboundMessage};
-use crate::provider::AnthropicProvider;
-use crate::telegram::TelegramBot;
-use crate::tool::CliApprover;
+use crate::channel::telegram::{
+    self as telegram_channel, CancelTokens, ChatLocks, LastReplies, RecentErrors,
+};
+use crate::db::{ApprovalRule, Database, Fact, Store};
+use crate::message::{ChannelKind, InboundMessage, Message};
+use crate::policy::{PolicyApprover, PolicyFile, SharedPolicy};
+use crate::provider::{AnthropicProvider, Provider, ToolChoice};
+use crate::telegram::{HttpTransport, TelegramBot, TelegramTransport, Update};
+use crate::tool::trace::{self, ReplayOutcome};
+use crate::tool::{Approver, CliApprover, UnattendedApprover};
 
 #[derive(Parser)]
 #[command(name = "ava", about = "a personal ai assistant")]
 struct Cli {
+    /// don't print the reply, e.g. when only the exit code matters, and
+    /// only log errors
+    #[arg(long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// print each tool call as it's made, on stderr, and log at debug level
+    #[arg(long, global = true)]
+    verbose: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -37,25 +62,183 @@ enum Commands {
     Status,
     /// send a message to the assistant
     Message {
-        /// the message to send
-        content: String,
+        /// the message to send. `-`, or leaving it out while piping, reads it
+        /// from stdin.
+        content: Option<String>,
+        /// only offer these tools, comma-separated (e.g. web_search,web_fetch)
+        #[arg(long, value_delimiter = ',', conflicts_with = "no_tools")]
+        tools: Option<Vec<String>>,
+        /// answer without any tools
+        #[arg(long)]
+        no_tools: bool,
+        /// continue the most recent conversation instead of starting a new one
+        #[arg(long = "continue")]
+        continue_session: bool,
+        /// use this system prompt instead of the default one, for this
+        /// message only. known facts are still added.
+        #[arg(long = "system", value_name = "PROMPT")]
+        system_prompt: Option<String>,
+        /// make the first response use tools: auto, any, or tool:NAME (e.g.
+        /// tool:web_search)
+        #[arg(long, value_parser = parse_tool_choice)]
+        tool_choice: Option<ToolChoice>,
     },
     /// start the telegram bot
     Telegram,
+    /// inspect the effective configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// inspect stored facts
+    Facts {
+        #[command(subcommand)]
+        command: FactsCommand,
+    },
+    /// inspect the tools the model is offered
+    Tools {
+        #[command(subcommand)]
+        command: ToolsCommand,
+    },
+    /// inspect the commands that are always allowed
+    Approvals {
+        #[command(subcommand)]
+        command: ApprovalsCommand,
+    },
+    /// work with past conversations
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+    /// run every line of a file as its own prompt and write the replies to
+    /// another file, as JSON lines
+    Batch {
+        /// one prompt per line. blank lines are skipped.
+        input: PathBuf,
+        /// where the replies go, one JSON object per prompt
+        output: PathBuf,
+        /// how many prompts run at once
+        #[arg(long, default_value_t = DEFAULT_BATCH_CONCURRENCY)]
+        concurrency: usize,
+        /// run tool calls that need approval, like exec, without asking.
+        /// without this they're denied, unless the approval policy allows them.
+        #[arg(long)]
+        auto_approve: bool,
+    },
+    /// inspect or migrate the database schema
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// copy the database to a new file, safely even while ava is running
+    Backup {
+        /// where to write the copy. must not exist yet.
+        path: PathBuf,
+    },
+    /// run the tool calls recorded in a trace file again and report any whose
+    /// output changed. uses a throwaway database.
+    Replay {
+        /// a trace written with AVA_TOOL_TRACE_FILE
+        file: PathBuf,
+    },
+    /// print a shell completion script
+    Completions {
+        /// the shell to complete for
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// print the resolved configuration. secret values are never shown.
+    Show,
+}
+
+#[derive(Subcommand)]
+enum FactsCommand {
+    /// list facts, most recently updated first
+    List {
+        /// page to show, starting at 1
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+        /// facts per page
+        #[arg(long, default_value_t = 50)]
+        per_page: usize,
+    },
+    /// restore the value a fact had before its last change
+    Undo { category: String, key: String },
+    /// delete a fact
+    Forget { category: String, key: String },
+    /// only show a fact on the command line, never in chats
+    Private { category: String, key: String },
+    /// show a private fact in chats again
+    Public { category: String, key: String },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// run the migrations the database hasn't had yet
+    Migrate {
+        /// stop at this schema version instead of the latest, e.g. to test a
+        /// migration. migrations only go forward.
+        #[arg(long, value_name = "VERSION")]
+        to: Option<i32>,
+    },
+    /// show the schema version and how many facts and sessions there are
+    Version,
+}
+
+#[derive(Subcommand)]
+enum ApprovalsCommand {
+    /// list saved approval rules, with when and for which command each was
+    /// saved
+    List,
+}
+
+#[derive(Subcommand)]
+enum ToolsCommand {
+    /// list the tools a channel offers, with their required fields and
+    /// whether they need approval
+    List {
+        /// the channel whose allow-list applies
+        #[arg(long, value_enum, default_value = "cli")]
+        channel: ChannelKind,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionsCommand {
+    /// write a conversation to a Markdown file
+    Export {
+        /// the session's ID
+        id: i64,
+        /// where to write the Markdown
+        file: PathBuf,
+    },
+    /// delete sessions without messages, keeping each channel's latest
+    Prune {
+        /// also delete untitled sessions started more than this many days ago
+        #[arg(long, value_name = "DAYS")]
+        older_than: Option<u64>,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .init();
-
     let cli = Cli::parse();
+    let verbosity = channel::Verbosity::from_flags(cli.quiet, cli.verbose);
+    log::init(
+        log::level_for(verbosity, config::log_level()),
+        config::log_format(),
+    );
+
+    if let Err(e) = http::init() {
+        tracing::error!(%e, "failed to set up http");
+        std::process::exit(1);
+    }
 
     match cli.command {
         Commands::Version => {
@@ -65,8 +248,30 @@ async fn main() {
             println!("ava {}", env!("CARGO_PKG_VERSION"));
             println!("db: {}", config::default_db_path().display());
         }
-        Commands::Message { content } => {
-            if let Err(e) = run_message(content).await {
+        Commands::Message {
+            content,
+            tools,
+            no_tools,
+            continue_session,
+            system_prompt,
+            tool_choice,
+        } => {
+            let enabled_tools = if no_tools {
+                Some(HashSet::new())
+            } else {
+                tools.map(|names| names.into_iter().collect())
+            };
+
+            if let Err(e) = run_message(
+                content,
+                enabled_tools,
+                continue_session,
+                system_prompt,
+                tool_choice,
+                verbosity,
+            )
+            .await
+            {
                 tracing::error!(%e, "message command failed");
                 std::process::exit(1);
             }
@@ -77,24 +282,494 @@ async fn main() {
                 std::process::exit(1);
             }
         }
+        Commands::Config {
+            command: ConfigCommand::Show,
+        } => {
+            println!("{}", config::Config::resolve());
+        }
+        Commands::Facts { command } => {
+            if let Err(e) = run_facts(command) {
+                tracing::error!(%e, "facts command failed");
+                std::process::exit(1);
+            }
+        }
+        Commands::Tools {
+            command: ToolsCommand::List { channel },
+        } => {
+            print!("{}", tool_listing(channel));
+        }
+        Commands::Approvals {
+            command: ApprovalsCommand::List,
+        } => match Database::open().and_then(|db| db.list_approval_rules()) {
+            Ok(rules) => print!("{}", approval_rule_listing(&rules)),
+            Err(e) => {
+                tracing::error!(%e, "approvals command failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Sessions { command } => {
+            if let Err(e) = run_sessions(command) {
+                tracing::error!(%e, "sessions command failed");
+                std::process::exit(1);
+            }
+        }
+        Commands::Batch {
+            input,
+            output,
+            concurrency,
+            auto_approve,
+        } => match run_batch(&input, &output, concurrency, auto_approve).await {
+            Ok(0) => {}
+            Ok(failed) => {
+                eprintln!("{failed} prompt(s) failed");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                tracing::error!(%e, "batch failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Db { command } => {
+            if let Err(e) = run_db(command) {
+                tracing::error!(%e, "db command failed");
+                std::process::exit(1);
+            }
+        }
+        // the live database is copied as it is, even if it's due a migration
+        Commands::Backup { path } => match Database::open_existing_at(config::default_db_path())
+            .and_then(|db| db.backup_to(&path))
+        {
+            Ok(pages) => println!("backed up {pages} pages to {}", path.display()),
+            Err(e) => {
+                tracing::error!(%e, "backup failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Replay { file } => match run_replay(&file).await {
+            Ok(0) => {}
+            Ok(changed) => {
+                eprintln!("{changed} tool call(s) changed");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                tracing::error!(%e, "replay failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Completions { shell } => {
+            print!("{}", completions::generate(shell, Cli::command()));
+        }
     }
 }
 
-async fn run_message(content: String) -> Result<(), error::Error> {
+async fn run_message(
+    content: Option<String>,
+    enabled_tools: Option<HashSet<String>>,
+    continue_session: bool,
+    system_prompt: Option<String>,
+    tool_choice: Option<ToolChoice>,
+    verbosity: channel::Verbosity,
+) -> Result<(), error::Error> {
+    let stdin = std::io::stdin();
+    let is_terminal = stdin.is_terminal();
+    let content = message_content(content, stdin.lock(), is_terminal)?;
     let provider = AnthropicProvider::from_env()?;
     let db = Database::open()?;
-    let agent = Agent::new(provider, CliApprover, db);
+    let inbound =
+        InboundMessage::new(ChannelKind::Cli, content).with_system_prompt_override(system_prompt);
+    let session = if continue_session {
+        db.resume_or_create_session(&inbound.session_channel(), config::session_idle_timeout())?
+    } else {
+        db.create_session(&inbound.session_channel())?
+    };
+    let approver = PolicyApprover::new(CliApprover, SharedPolicy::new(PolicyFile::from_env()?));
+    let agent = Agent::new(provider, approver, db)
+        .with_enabled_tools(enabled_tools)
+        .with_tool_choice(tool_choice)
+        .with_assistant_name(config::assistant_name())
+        .with_response_language(config::response_language())
+        .with_known_facts(known_facts_options())
+        .with_system_facts(config::system_facts()?)
+        .with_system_prompt_max_chars(config::system_prompt_max_chars())
+        .with_session(session)
+        .with_history(history_options())
+        .with_turn_timeout(config::turn_timeout())
+        .with_actions_footer(config::show_actions());
 
-    let inbound = InboundMessage {
-        channel: ChannelKind::Cli,
-        content,
+    let cli = Arc::new(channel::CliChannel::new(verbosity));
+    // a pipe only gets the reply, without what the model says on the way
+    let agent = stream_to_cli(agent, &cli, verbosity, std::io::stdout().is_terminal());
+
+    let outbound = match agent.process(inbound).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            // the error isn't left on the end of half a reply
+            cli.end_round();
+            return Err(e);
+        }
     };
+    cli.send(outbound)?;
+    Ok(())
+}
+
+/// with `stream`, prints the model's text to `cli` as it's generated. with
+/// --verbose, prints each tool call as it's made.
+fn stream_to_cli<P: Provider, A: Approver, S: Store, W: std::io::Write + Send + 'static>(
+    agent: Agent<P, A, S>,
+    cli: &Arc<channel::CliChannel<W>>,
+    verbosity: channel::Verbosity,
+    stream: bool,
+) -> Agent<P, A, S> {
+    let rounds = Arc::clone(cli);
+    let agent = agent.with_progress(move |calls| {
+        rounds.end_round();
+        if verbosity == channel::Verbosity::Verbose {
+            for call in calls {
+                eprintln!("{}", channel::describe_invocation(call));
+            }
+        }
+    });
+    if !stream {
+        return agent;
+    }
+    let streamed = Arc::clone(cli);
+    agent.with_text_stream(move |text| streamed.stream(text))
+}
+
+/// the message to send: the argument, or stdin when the argument is `-` or
+/// missing while something's piped in
+fn message_content(
+    arg: Option<String>,
+    mut stdin: impl Read,
+    stdin_is_terminal: bool,
+) -> Result<String, error::Error> {
+    match arg {
+        Some(content) if content != "-" => return Ok(content),
+        None if stdin_is_terminal => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no message given, pass it as an argument or pipe it in",
+            )
+            .into());
+        }
+        _ => {}
+    }
+
+    let mut content = String::new();
+    stdin.read_to_string(&mut content)?;
+    let content = content.trim_end();
+    if content.trim().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the message read from stdin is empty",
+        )
+        .into());
+    }
+    Ok(content.to_string())
+}
+
+fn parse_tool_choice(value: &str) -> Result<ToolChoice, String> {
+    ToolChoice::parse(value).ok_or_else(|| format!("expected auto, any or tool:NAME, got {value}"))
+}
+
+/// how many batch prompts run at once unless --concurrency says otherwise
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+
+/// runs each prompt in `input` as an isolated turn and appends each reply
+/// to `output` as soon as it's ready, so a crash keeps what's done. a prompt
+/// that fails is recorded with its error and the rest carry on. tool calls
+/// that need approval are denied unless `auto_approve`. returns how many
+/// prompts failed.
+async fn run_batch(
+    input: &Path,
+    output: &Path,
+    concurrency: usize,
+    auto_approve: bool,
+) -> Result<usize, error::Error> {
+    let prompts: Vec<(usize, String)> = std::fs::read_to_string(input)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, line.to_string()))
+        .collect();
+    let provider = AnthropicProvider::from_env()?;
+    let system_facts = config::system_facts()?;
+    let policy = SharedPolicy::new(PolicyFile::from_env()?);
+    let db = Database::open()?;
+    let mut file = std::fs::File::create(output)?;
+
+    let failed = batch_replies(
+        &prompts,
+        concurrency,
+        || {
+            let approver = PolicyApprover::new(
+                UnattendedApprover {
+                    approve: auto_approve,
+                },
+                policy.clone(),
+            );
+            Agent::new(provider.clone(), approver, db.clone())
+                .with_assistant_name(config::assistant_name())
+                .with_response_language(config::response_language())
+                .with_known_facts(known_facts_options())
+                .with_system_facts(system_facts.clone())
+                .with_system_prompt_max_chars(config::system_prompt_max_chars())
+                .with_turn_timeout(config::turn_timeout())
+        },
+        |line, prompt, reply| {
+            file.write_all(format!("{}\n", batch_line(line, prompt, reply)).as_bytes())?;
+            Ok(())
+        },
+    )
+    .await?;
+    println!(
+        "ran {} prompt(s), {failed} failed, replies in {}",
+        prompts.len(),
+        output.display()
+    );
+    Ok(failed)
+}
+
+/// runs each `(line, prompt)` with an agent from `agent`, at most
+/// `concurrency` at a time, and hands each reply to `on_reply` as it
+/// finishes, which may be out of order. returns how many failed.
+async fn batch_replies<P: Provider, A: Approver, S: Store>(
+    prompts: &[(usize, String)],
+    concurrency: usize,
+    agent: impl Fn() -> Agent<P, A, S>,
+    mut on_reply: impl FnMut(usize, &str, &Result<String, error::Error>) -> Result<(), error::Error>,
+) -> Result<usize, error::Error> {
+    let mut replies = futures_util::stream::iter(prompts)
+        .map(|(line, prompt)| {
+            let agent = agent();
+            async move {
+                let inbound = InboundMessage::new(ChannelKind::Cli, prompt.clone());
+                let reply = agent
+                    .process(inbound)
+                    .await
+                    .map(|outbound| outbound.content);
+                if let Err(e) = &reply {
+                    tracing::warn!(line, %e, "batch prompt failed");
+                }
+                (*line, prompt.as_str(), reply)
+            }
+        })
+        .buffer_unordered(concurrency.max(1));
+
+    let mut failed = 0;
+    while let Some((line, prompt, reply)) = replies.next().await {
+        if reply.is_err() {
+            failed += 1;
+        }
+        on_reply(line, prompt, &reply)?;
+    }
+    Ok(failed)
+}
+
+/// one line of batch output: the prompt with its reply or error
+fn batch_line(line: usize, prompt: &str, reply: &Result<String, error::Error>) -> String {
+    let mut record = serde_json::json!({"line": line, "prompt": prompt});
+    match reply {
+        Ok(reply) => record["reply"] = reply.as_str().into(),
+        Err(e) => record["error"] = e.to_string().into(),
+    }
+    record.to_string()
+}
+
+fn run_facts(command: FactsCommand) -> Result<(), error::Error> {
+    let db = Database::open()?;
+
+    match command {
+        FactsCommand::List { page, per_page } => {
+            let page = page.max(1);
+            let facts = db.recent_facts_limited(per_page, (page - 1) * per_page)?;
+            if facts.is_empty() {
+                println!("no facts on page {page}");
+                return Ok(());
+            }
+            for fact in facts {
+                let marker = if fact.private { " (private)" } else { "" };
+                println!("{}.{}: {}{marker}", fact.category, fact.key, fact.value);
+            }
+        }
+        FactsCommand::Undo { category, key } => match db.undo_fact(&category, &key)? {
+            Some(value) => println!("{category}.{key}: {value}"),
+            None => println!("no earlier value for {category}.{key}"),
+        },
+        FactsCommand::Forget { category, key } => {
+            if db.forget_fact(&category, &key)? {
+                println!("forgot {category}.{key}");
+            } else {
+                println!("no fact {category}.{key}");
+            }
+        }
+        FactsCommand::Private { category, key } => {
+            if db.set_fact_private(&category, &key, true)? {
+                println!("{category}.{key} is now private");
+            } else {
+                println!("no fact {category}.{key}");
+            }
+        }
+        FactsCommand::Public { category, key } => {
+            if db.set_fact_private(&category, &key, false)? {
+                println!("{category}.{key} is now shown in chats");
+            } else {
+                println!("no fact {category}.{key}");
+            }
+        }
+    }
 
-    let outbound = agent.process(inbound).await?;
-    channel::CliChannel.send(outbound)?;
     Ok(())
 }
 
+/// the tools `channel` offers after its allow-list and safe mode, one block
+/// per tool
+fn tool_listing(channel: ChannelKind) -> String {
+    let enabled = tool::channel_enabled_tools(channel, None);
+    let definitions = tool::enabled_tool_definitions(enabled.as_ref());
+    if definitions.is_empty() {
+        return "no tools enabled\n".to_string();
+    }
+
+    let mut output = String::new();
+    for definition in definitions {
+        let call = tool::ToolCall {
+            id: String::new(),
+            name: definition.name.to_string(),
+            input: serde_json::json!({}),
+        };
+        let approval = if tool::requires_approval(&call) {
+            " (needs approval)"
+        } else {
+            ""
+        };
+        let required: Vec<&str> = definition.input_schema["required"]
+            .as_array()
+            .map(|fields| fields.iter().filter_map(|field| field.as_str()).collect())
+            .unwrap_or_default();
+        let required = if required.is_empty() {
+            "none".to_string()
+        } else {
+            required.join(", ")
+        };
+        output.push_str(&format!(
+            "{}{approval}\n  {}\n  required: {required}\n\n",
+            definition.name, definition.description
+        ));
+    }
+    output
+}
+
+/// one line per rule: id, pattern, when it was saved and the command it was
+/// saved for
+fn approval_rule_listing(rules: &[ApprovalRule]) -> String {
+    if rules.is_empty() {
+        return "no approval rules\n".to_string();
+    }
+    rules
+        .iter()
+        .map(|rule| {
+            let example = rule
+                .example_command
+                .as_deref()
+                .map(|command| format!(", for `{command}`"))
+                .unwrap_or_default();
+            format!(
+                "{}. {} (saved {} UTC{example})\n",
+                rule.id, rule.pattern, rule.created_at
+            )
+        })
+        .collect()
+}
+
+fn run_sessions(command: SessionsCommand) -> Result<(), error::Error> {
+    let db = Database::open()?;
+
+    match command {
+        SessionsCommand::Export { id, file } => {
+            let Some(session) = db.get_session(id)? else {
+                println!("no session {id}");
+                return Ok(());
+            };
+            let messages: Vec<Message> = db
+                .session_messages(id)?
+                .into_iter()
+                .map(|stored| stored.message)
+                .collect();
+            std::fs::write(&file, transcript::to_markdown(&session, &messages))?;
+            println!(
+                "exported {} messages from session {id} to {}",
+                messages.len(),
+                file.display()
+            );
+        }
+        SessionsCommand::Prune { older_than } => {
+            let max_age = older_than.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let pruned = db.prune_empty_sessions(max_age)?;
+            println!("pruned {pruned} session(s)");
+        }
+    }
+
+    Ok(())
+}
+
+fn run_db(command: DbCommand) -> Result<(), error::Error> {
+    match command {
+        DbCommand::Migrate { to } => {
+            let db = Database::open_unmigrated_at(config::default_db_path())?;
+            let (before, after) = db.migrate(to)?;
+            if before == after {
+                println!("schema already at version {after}");
+            } else {
+                println!("migrated schema from version {before} to {after}");
+            }
+        }
+        DbCommand::Version => {
+            // opened without migrating, so the version shown is the one on
+            // disk, and only if it exists, so a missing one isn't created empty
+            let db = Database::open_existing_at(config::default_db_path())?;
+            let version = db.schema_version()?;
+            let latest = db::latest_schema_version();
+            println!("schema version: {version} (latest {latest})");
+            if db.has_table("facts")? {
+                println!("facts: {}", db.fact_count()?);
+            }
+            if db.has_table("sessions")? {
+                println!("sessions: {}", db.session_count()?);
+            }
+            if version < latest {
+                println!("run `ava db migrate` to update it");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// replays a trace file and returns how many calls came out different
+async fn run_replay(file: &Path) -> Result<usize, error::Error> {
+    let records = trace::read(file)?;
+    let db = Database::open_in_memory()?;
+    let mut changed = 0;
+
+    for record in &records {
+        let name = &record.call.name;
+        match trace::replay(&db, &CliApprover, record).await? {
+            ReplayOutcome::Same => println!("same     {name}"),
+            ReplayOutcome::Skipped => println!("skipped  {name}"),
+            ReplayOutcome::Changed { recorded, replayed } => {
+                changed += 1;
+                println!("changed  {name}");
+                println!("  recorded: {}", recorded.replace('\n', "\n            "));
+                println!("  replayed: {}", replayed.replace('\n', "\n            "));
+            }
+        }
+    }
+
+    println!("replayed {} tool call(s), {changed} changed", records.len());
+    Ok(changed)
+}
+
 fn allowed_telegram_ids() -> Vec<i64> {
     std::env::var("TELEGRAM_ALLOWED_IDS")
         .unwrap_or_default()
@@ -103,8 +778,42 @@ fn allowed_telegram_ids() -> Vec<i64> {
         .collect()
 }
 
+/// what the telegram update loop shares with the agent turns it spawns
+struct TelegramState<T = HttpTransport> {
+    bot: Arc<TelegramBot<T>>,
+    allowed_ids: Vec<i64>,
+    // shared pending approvals — keyed by nonce
+    pending: Arc<PendingApprovals>,
+    // one agent turn at a time per chat
+    chat_locks: ChatLocks,
+    last_replies: LastReplies,
+    // holds back repeats of the same error reply
+    recent_errors: RecentErrors,
+    // lets /cancel stop a chat's running turn
+    cancel_tokens: CancelTokens,
+    // loaded once at startup
+    system_facts: Vec<Fact>,
+    // reloaded on SIGHUP
+    policy: SharedPolicy,
+}
+
+impl<T: TelegramTransport> TelegramState<T> {
+    fn new(bot: TelegramBot<T>, allowed_ids: Vec<i64>) -> Self {
+        Self {
+            bot: Arc::new(bot),
+            allowed_ids,
+            pending: Arc::new(PendingApprovals::new()),
+            chat_locks: ChatLocks::new(),
+            last_replies: LastReplies::new(),
+            recent_errors: RecentErrors::new(),
+            cancel_tokens: CancelTokens::new(),
+            system_facts: Vec::new(),
+            policy: SharedPolicy::default(),
+        }
+    }
+}
+
 async fn run_telegram() -> Result<(), error::Error> {
-    let bot = Arc::new(TelegramBot::from_env()?);
     let allowed_ids = allowed_telegram_ids();
 
     if allowed_ids.is_empty() {
@@ -113,15 +822,27 @@ async fn run_telegram() -> Result<(), error::Error> {
         tracing::info!(?allowed_ids, "loaded user whitelist");
     }
 
+    let mut state = TelegramState::new(TelegramBot::from_env()?, allowed_ids);
+    state.system_facts = config::system_facts()?;
+    state.policy = SharedPolicy::new(PolicyFile::from_env()?);
+    #[cfg(unix)]
+    if let Some(path) = config::approval_policy_file() {
+        tokio::spawn(policy::reload_on_hangup(state.policy.clone(), path));
+    }
+    let state = Arc::new(state);
+
     tracing::info!("starting telegram bot");
 
+    // messages that waited too long while the bot was offline are dropped
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+    let startup_max_age = config::telegram_startup_max_age();
     let mut offset: Option<i64> = None;
 
-    // shared pending approvals — keyed by nonce
-    let pending = Arc::new(PendingApprovals::new());
-
     loop {
-        let updates = match bot.get_updates(offset).await {
+        let updates = match state.bot.get_updates(offset).await {
             Ok(u) => u,
             Err(e) => {
                 tracing::error!(%e, "failed to fetch updates");
@@ -133,94 +854,766 @@ async fn run_telegram() -> Result<(), error::Error> {
         for update in updates {
             offset = Some(update.update_id + 1);
 
-            // handle callback queries (approval button presses)
-            if let Some(callback) = update.callback_query {
-                if let Some(data) = &callback.data {
-                    let chat_id = callback
-                        .message
-                        .as_ref()
-                        .map(|m| m.chat.id)
-                        .unwrap_or_default();
-
-                    TelegramApprover::handle_callback(&pending, &bot, &callback.id, data, chat_id)
-                        .await;
-                }
+            let sent_at = update
+                .message
+                .as_ref()
+                .or(update.edited_message.as_ref())
+                .map(|msg| msg.sent_at());
+            if sent_at
+                .is_some_and(|date| telegram_channel::is_stale(date, started_at, startup_max_age))
+            {
+                tracing::info!(
+                    update_id = update.update_id,
+                    "dropping message from before startup"
+                );
                 continue;
             }
 
-            // handle text messages
-            let Some(msg) = update.message else {
-                continue;
-            };
+            if let Some(inbound) = handle_update(&state, update).await {
+                // spawn agent processing so we can continue polling for callback queries
+                tokio::spawn(handle_telegram_message(Arc::clone(&state), inbound));
+            }
+        }
+    }
+}
 
-            let Some(text) = msg.text else {
-                continue;
+/// handles button presses and commands right away. returns the message to
+/// run an agent turn for, if the update is one.
+async fn handle_update<T: TelegramTransport>(
+    state: &TelegramState<T>,
+    update: Update,
+) -> Option<InboundMessage> {
+    let bot = &state.bot;
+
+    // handle callback queries (approval button presses)
+    if let Some(callback) = update.callback_query {
+        if let Some(data) = &callback.data {
+            let chat_id = callback
+                .message
+                .as_ref()
+                .map(|m| m.chat.id)
+                .unwrap_or_default();
+
+            TelegramApprover::handle_callback(&state.pending, bot, &callback.id, data, chat_id)
+                .await;
+        }
+        return None;
+    }
+
+    // handle text messages, and edits to them
+    let (msg, edited) = match (update.message, update.edited_message) {
+        (Some(msg), _) => (msg, false),
+        (None, Some(msg)) => (msg, true),
+        (None, None) => return None,
+    };
+
+    let links = msg.links();
+    let text = msg.text?;
+
+    let chat_id = msg.chat.id;
+    let user_id = msg.from.map(|u| u.id);
+
+    // check whitelist
+    let is_allowed = user_id
+        .map(|id| state.allowed_ids.contains(&id))
+        .unwrap_or(false);
+    if !is_allowed {
+        tracing::warn!(?user_id, "ignoring message from unauthorized user");
+        return None;
+    }
+
+    let Some(text) = telegram_channel::turn_text(&text, edited, config::telegram_edits()) else {
+        tracing::debug!(chat_id, "ignoring edited message");
+        if let Err(e) = bot
+            .send_message(chat_id, telegram_channel::IGNORED_EDIT_NOTE)
+            .await
+        {
+            tracing::error!(%e, "failed to send edit note");
+        }
+        return None;
+    };
+
+    if telegram_channel::is_command(&text, "help") {
+        let help = telegram_channel::help_text(&config::assistant_name());
+        if let Err(e) = bot.send_message(chat_id, &help).await {
+            tracing::error!(%e, "failed to send help");
+        }
+        return None;
+    }
+
+    // handled here rather than in a spawned task, which would queue
+    // behind the very turn it's meant to cancel
+    if telegram_channel::is_command(&text, "cancel") {
+        let reply = if state.cancel_tokens.cancel(chat_id) {
+            "cancelling…"
+        } else {
+            "nothing to cancel"
+        };
+        if let Err(e) = bot.send_message(chat_id, reply).await {
+            tracing::error!(%e, "failed to send cancel reply");
+        }
+        return None;
+    }
+
+    if telegram_channel::is_command(&text, "denyall") {
+        let denied = TelegramApprover::deny_all(&state.pending, bot).await;
+        let reply = match denied {
+            0 => "nothing waiting for approval".to_string(),
+            1 => "denied 1 pending approval".to_string(),
+            n => format!("denied {n} pending approvals"),
+        };
+        if let Err(e) = bot.send_message(chat_id, &reply).await {
+            tracing::error!(%e, "failed to send deny-all reply");
+        }
+        return None;
+    }
+
+    if telegram_channel::is_command(&text, "pending") {
+        let cleared = state
+            .pending
+            .clear_expired(config::approval_timeout())
+            .await;
+        if cleared > 0 {
+            tracing::info!(cleared, "cleared stale approvals");
+        }
+        let reply = match state.pending.len().await {
+            0 => "nothing waiting for approval".to_string(),
+            1 => "1 approval pending".to_string(),
+            n => format!("{n} approvals pending"),
+        };
+        if let Err(e) = bot.send_message(chat_id, &reply).await {
+            tracing::error!(%e, "failed to send pending reply");
+        }
+        return None;
+    }
+
+    Some(
+        InboundMessage::new(ChannelKind::Telegram, text)
+            .with_sender(chat_id, user_id)
+            .with_links(links),
+    )
+}
+
+fn known_facts_options() -> KnownFactsOptions {
+    KnownFactsOptions {
+        category_priority: config::fact_category_priority(),
+        max_chars: config::fact_prompt_max_chars(),
+    }
+}
+
+fn history_options() -> HistoryOptions {
+    HistoryOptions {
+        max_messages: config::summarize_after_messages(),
+        max_tokens: config::summarize_after_tokens(),
+        ..Default::default()
+    }
+}
+
+/// sets up the provider and database for an inbound telegram message, then
+/// runs its turn
+async fn handle_telegram_message(state: Arc<TelegramState>, inbound: InboundMessage) {
+    let Some(chat_id) = inbound.chat_id else {
+        return;
+    };
+
+    let provider = match AnthropicProvider::from_env() {
+        Ok(p) => p,
+        Err(e) => {
+            tracing::error!(%e, "provider init failed");
+            send_error(&state, chat_id, &e).await;
+            return;
+        }
+    };
+
+    let db = match Database::open() {
+        Ok(db) => db,
+        Err(e) => {
+            tracing::error!(%e, "database open failed");
+            send_error(&state, chat_id, &e).await;
+            return;
+        }
+    };
+
+    run_telegram_turn(&state, provider, db, chat_id, inbound).await;
+}
+
+/// runs one agent turn for an inbound telegram message and sends the reply
+async fn run_telegram_turn<T: TelegramTransport + 'static, P: Provider>(
+    state: &TelegramState<T>,
+    provider: P,
+    db: Database,
+    chat_id: i64,
+    inbound: InboundMessage,
+) {
+    let bot = &state.bot;
+    let _turn = match state.chat_locks.try_lock(chat_id) {
+        Some(guard) => guard,
+        None => {
+            let _ = bot
+                .send_message(
+                    chat_id,
+                    "still working on your last message, i'll get to this one next",
+                )
+                .await;
+            state.chat_locks.lock(chat_id).await
+        }
+    };
+
+    if let Err(e) = bot.send_typing(chat_id).await {
+        tracing::debug!(%e, "failed to send typing action");
+    }
+
+    let idle_timeout = config::session_idle_timeout();
+    let session = match db.resume_or_create_session(&inbound.session_channel(), idle_timeout) {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::error!(%e, "session load failed");
+            send_error(state, chat_id, &e).await;
+            return;
+        }
+    };
+
+    let approver = TelegramApprover::new(Arc::clone(bot), chat_id, Arc::clone(&state.pending))
+        .with_timeout(config::approval_timeout());
+    let approver = PolicyApprover::new(approver, state.policy.clone());
+
+    let cancel = state.cancel_tokens.start(chat_id);
+    let mut agent = Agent::new(provider, approver, db)
+        .with_assistant_name(config::assistant_name())
+        .with_response_language(config::response_language())
+        .with_known_facts(known_facts_options())
+        .with_system_facts(state.system_facts.clone())
+        .with_system_prompt_max_chars(config::system_prompt_max_chars())
+        .with_session(session)
+        .with_history(history_options())
+        .with_turn_timeout(config::turn_timeout())
+        .with_actions_footer(config::show_actions())
+        .with_cancellation(cancel);
+
+    let mut progress = None;
+    if config::telegram_progress() {
+        let (sender, task) = spawn_progress_message(Arc::clone(bot), chat_id);
+        agent = agent.with_progress(move |calls| {
+            let _ = sender.send(tool::describe_progress(calls));
+        });
+        progress = Some(task);
+    }
+
+    let mut exec_output = None;
+    if config::telegram_exec_output() {
+        let (sink, outputs) = tool::OutputSink::channel();
+        agent = agent.with_exec_output(sink);
+        exec_output = Some(spawn_exec_output(Arc::clone(bot), chat_id, outputs));
+    }
+
+    let result = agent.process(inbound).await;
+    state.cancel_tokens.finish(chat_id);
+    // the agent is gone, so the progress message is done with; let it
+    // settle before the reply goes out below it
+    if let Some(task) = progress {
+        let _ = task.await;
+    }
+    if let Some(task) = exec_output {
+        let _ = task.await;
+    }
+
+    match result {
+        Ok(outbound) => {
+            state.recent_errors.clear(chat_id);
+            send_reply(bot, &state.last_replies, chat_id, &outbound.content).await
+        }
+        Err(error::Error::Cancelled) => {
+            tracing::info!(chat_id, "turn cancelled");
+            let _ = bot.send_message(chat_id, "cancelled").await;
+        }
+        Err(e) => {
+            tracing::error!(%e, chat_id, "agent processing failed");
+            send_error(state, chat_id, &e).await;
+        }
+    }
+}
+
+/// tells the chat about an error, unless the same one was just sent there
+async fn send_error<T: TelegramTransport>(
+    state: &TelegramState<T>,
+    chat_id: i64,
+    error: &error::Error,
+) {
+    match state.recent_errors.reply(chat_id, &error.user_message()) {
+        Some(text) => {
+            let _ = state.bot.send_message(chat_id, &text).await;
+        }
+        None => tracing::debug!(chat_id, "repeated error reply held back"),
+    }
+}
+
+/// shows progress in one message: the first update is sent, later ones edit
+/// it. the task ends once the returned sender is dropped.
+fn spawn_progress_message<T: TelegramTransport + 'static>(
+    bot: Arc<TelegramBot<T>>,
+    chat_id: i64,
+) -> (
+    tokio::sync::mpsc::UnboundedSender<String>,
+    tokio::task::JoinHandle<()>,
+) {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let task = tokio::spawn(async move {
+        let mut message_id = None;
+        while let Some(text) = receiver.recv().await {
+            let result = match message_id {
+                Some(id) => bot.edit_message_text(chat_id, id, &text).await,
+                None => bot.send_message(chat_id, &text).await.map(|id| {
+                    message_id = Some(id);
+                }),
             };
+            if let Err(e) = result {
+                tracing::debug!(%e, chat_id, "failed to show progress");
+            }
+        }
+    });
+    (sender, task)
+}
 
-            let chat_id = msg.chat.id;
-            let user_id = msg.from.map(|u| u.id);
+/// sends each command's output to the chat as it comes in, split over as
+/// many messages as it takes. the task ends once every sink is dropped.
+fn spawn_exec_output<T: TelegramTransport + 'static>(
+    bot: Arc<TelegramBot<T>>,
+    chat_id: i64,
+    mut outputs: tokio::sync::mpsc::UnboundedReceiver<String>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(output) = outputs.recv().await {
+            for message in telegram_channel::output_messages(&output) {
+                if let Err(e) = bot.send_message(chat_id, &message).await {
+                    tracing::warn!(%e, chat_id, "failed to send command output");
+                    break;
+                }
+            }
+        }
+    })
+}
 
-            // check whitelist
-            let is_allowed = user_id.map(|id| allowed_ids.contains(&id)).unwrap_or(false);
-            if !is_allowed {
-                tracing::warn!(?user_id, "ignoring message from unauthorized user");
-                continue;
+/// how recent the previous reply must be to get edited in AVA_TELEGRAM_EDIT_LAST mode
+const EDIT_LAST_WINDOW: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// sends a reply, or edits the previous one in AVA_TELEGRAM_EDIT_LAST mode
+async fn send_reply<T: TelegramTransport>(
+    bot: &TelegramBot<T>,
+    last_replies: &LastReplies,
+    chat_id: i64,
+    text: &str,
+) {
+    if config::telegram_edit_last()
+        && let Some(message_id) = last_replies.recent(chat_id, EDIT_LAST_WINDOW)
+    {
+        match bot.edit_message_text(chat_id, message_id, text).await {
+            Ok(()) => {
+                last_replies.record(chat_id, message_id);
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(%e, chat_id, message_id, "failed to edit last reply, sending new one");
             }
+        }
+    }
 
-            // spawn agent processing so we can continue polling for callback queries
-            let bot_clone = Arc::clone(&bot);
-            let pending_clone = Arc::clone(&pending);
-
-            tokio::spawn(async move {
-                let provider = match AnthropicProvider::from_env() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        tracing::error!(%e, "provider init failed");
-                        let _ = bot_clone
-                            .send_message(chat_id, &format!("error: {e}"))
-                            .await;
-                        return;
-                    }
-                };
-
-                let db = match Database::open() {
-                    Ok(db) => db,
-                    Err(e) => {
-                        tracing::error!(%e, "database open failed");
-                        let _ = bot_clone
-                            .send_message(chat_id, &format!("error: {e}"))
-                            .await;
-                        return;
-                    }
-                };
-
-                let approver = TelegramApprover::new(
-                    Arc::clone(&bot_clone),
-                    chat_id,
-                    Arc::clone(&pending_clone),
+    match bot.send_message(chat_id, text).await {
+        Ok(message_id) => last_replies.record(chat_id, message_id),
+        // keep the reply in the logs, so the turn's work isn't lost
+        Err(e) => tracing::error!(%e, chat_id, reply = text, "failed to send telegram reply"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageContent};
+    use crate::provider::{ProviderResponse, StopReason};
+    use crate::telegram::mock::MockTransport;
+    use crate::tool::ToolDefinition;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_content_from_stdin() {
+        let piped = || "line one\nline $two\n".as_bytes();
+
+        assert_eq!(
+            message_content(Some("-".into()), piped(), false).unwrap(),
+            "line one\nline $two"
+        );
+        assert_eq!(
+            message_content(None, piped(), false).unwrap(),
+            "line one\nline $two"
+        );
+        assert_eq!(
+            message_content(Some("hi".into()), piped(), false).unwrap(),
+            "hi"
+        );
+        assert!(message_content(None, piped(), true).is_err());
+        assert!(message_content(Some("-".into()), " \n".as_bytes(), false).is_err());
+    }
+
+    #[test]
+    fn test_tool_listing_marks_approval() {
+        let listing = tool_listing(ChannelKind::Cli);
+
+        // search_files is only listed once AVA_SANDBOX_ROOT is set
+        for definition in tool::tool_definitions()
+            .into_iter()
+            .filter(|definition| definition.name != tool::SEARCH_FILES_TOOL_NAME)
+        {
+            assert!(
+                listing.contains(&format!("\n{}", definition.name))
+                    || listing.starts_with(definition.name),
+                "{} missing from the listing",
+                definition.name
+            );
+        }
+        assert!(listing.contains("exec (needs approval)\n"));
+        assert!(listing.contains("web_search\n"));
+        assert!(listing.contains("required: command"));
+    }
+
+    #[test]
+    fn test_approval_rule_listing_shows_origin() {
+        let rules = [
+            ApprovalRule {
+                id: 1,
+                pattern: "ls *".into(),
+                example_command: None,
+                created_at: "2024-06-01 09:30:00".into(),
+            },
+            ApprovalRule {
+                id: 2,
+                pattern: "cargo *".into(),
+                example_command: Some("cargo test".into()),
+                created_at: "2024-06-02 10:00:00".into(),
+            },
+        ];
+        assert_eq!(
+            approval_rule_listing(&rules),
+            "1. ls * (saved 2024-06-01 09:30:00 UTC)\n2. cargo * (saved 2024-06-02 10:00:00 UTC, for `cargo test`)\n"
+        );
+        assert_eq!(approval_rule_listing(&[]), "no approval rules\n");
+    }
+
+    #[test]
+    fn test_completions_cover_every_subcommand_and_flag() {
+        fn collect_words(command: &clap::Command, words: &mut Vec<String>) {
+            for arg in command.get_arguments() {
+                words.extend(arg.get_long().map(str::to_string));
+            }
+            for subcommand in command.get_subcommands() {
+                words.push(subcommand.get_name().to_string());
+                collect_words(subcommand, words);
+            }
+        }
+        let mut expected = Vec::new();
+        collect_words(&Cli::command(), &mut expected);
+        assert!(expected.contains(&"continue".to_string()));
+
+        for shell in [
+            completions::Shell::Bash,
+            completions::Shell::Zsh,
+            completions::Shell::Fish,
+        ] {
+            let script = completions::generate(shell, Cli::command());
+            for word in &expected {
+                assert!(
+                    script.contains(word.as_str()),
+                    "{word} missing from {shell} completions"
                 );
+            }
+        }
+    }
 
-                let agent = Agent::new(provider, approver, db);
-
-                let inbound = InboundMessage {
-                    channel: ChannelKind::Telegram,
-                    content: text,
-                };
-
-                match agent.process(inbound).await {
-                    Ok(outbound) => {
-                        if let Err(e) = bot_clone.send_message(chat_id, &outbound.content).await {
-                            tracing::error!(%e, chat_id, "failed to send telegram message");
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!(%e, chat_id, "agent processing failed");
-                        let _ = bot_clone
-                            .send_message(chat_id, &format!("error: {e}"))
-                            .await;
-                    }
-                }
-            });
+    /// greets whoever sent the last message
+    struct GreetingProvider;
+
+    impl Provider for GreetingProvider {
+        async fn complete(
+            &self,
+            _system_prompt: &str,
+            messages: &[Message],
+            _tools: &[ToolDefinition],
+        ) -> Result<ProviderResponse, error::Error> {
+            let last = match messages.last().map(|m| &m.content[0]) {
+                Some(MessageContent::Text { text }) => text.clone(),
+                _ => String::new(),
+            };
+            Ok(ProviderResponse {
+                content: vec![MessageContent::text(format!("you said: {last}"))],
+                stop_reason: StopReason::EndTurn,
+            })
+        }
+
+        /// hands out the greeting a few chars at a time
+        #[cfg(feature = "streaming")]
+        async fn complete_streaming_text(
+            &self,
+            system_prompt: &str,
+            messages: &[Message],
+            tools: &[ToolDefinition],
+            _on_tool_call: &(dyn Fn(&tool::ToolCall) + Send + Sync),
+            on_text: &(dyn Fn(&str) + Send + Sync),
+        ) -> Result<ProviderResponse, error::Error> {
+            let response = self.complete(system_prompt, messages, tools).await?;
+            let text: Vec<char> = response.text().chars().collect();
+            for piece in text.chunks(4) {
+                on_text(&piece.iter().collect::<String>());
+            }
+            Ok(response)
         }
     }
+
+    /// a writer whose output can be read after it's handed off
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_cli_output_matches_the_reply() {
+        let output = SharedOutput::default();
+        let cli = Arc::new(channel::CliChannel::with_writer(
+            channel::Verbosity::Normal,
+            output.clone(),
+        ));
+        let agent = Agent::new(
+            GreetingProvider,
+            CliApprover,
+            Database::open_in_memory().unwrap(),
+        );
+        let agent = stream_to_cli(agent, &cli, channel::Verbosity::Normal, true);
+
+        let outbound = agent
+            .process(InboundMessage::new(ChannelKind::Cli, "hello there"))
+            .await
+            .unwrap();
+        assert_eq!(outbound.content, "you said: hello there");
+        let printed = || String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        // already on screen before the reply is sent
+        assert_eq!(printed(), "you said: hello there");
+
+        cli.send(outbound).unwrap();
+        assert_eq!(printed(), "you said: hello there\n");
+
+        // not streamed, e.g. into a pipe, the reply is all there is
+        let output = SharedOutput::default();
+        let cli = Arc::new(channel::CliChannel::with_writer(
+            channel::Verbosity::Normal,
+            output.clone(),
+        ));
+        let agent = Agent::new(
+            GreetingProvider,
+            CliApprover,
+            Database::open_in_memory().unwrap(),
+        );
+        let agent = stream_to_cli(agent, &cli, channel::Verbosity::Normal, false);
+        let outbound = agent
+            .process(InboundMessage::new(ChannelKind::Cli, "hello there"))
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            ""
+        );
+        cli.send(outbound).unwrap();
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "you said: hello there\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_runs_each_prompt() {
+        let prompts = vec![(1, "hello".to_string()), (3, "goodbye".to_string())];
+        let db = Database::open_in_memory().unwrap();
+        let mut lines = Vec::new();
+        let failed = batch_replies(
+            &prompts,
+            2,
+            || Agent::new(GreetingProvider, CliApprover, db.clone()),
+            |line, prompt, reply| {
+                lines.push(batch_line(line, prompt, reply));
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(failed, 0);
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                r#"{"line":1,"prompt":"hello","reply":"you said: hello"}"#,
+                r#"{"line":3,"prompt":"goodbye","reply":"you said: goodbye"}"#,
+            ]
+        );
+
+        // a failed prompt is written out with its error
+        let mut lines = Vec::new();
+        let failed = batch_replies(
+            &prompts[..1],
+            1,
+            || Agent::new(DownProvider, CliApprover, db.clone()),
+            |line, prompt, reply| {
+                lines.push(batch_line(line, prompt, reply));
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(failed, 1);
+        assert_eq!(
+            lines,
+            [r#"{"error":"provider error: overloaded","line":1,"prompt":"hello"}"#]
+        );
+    }
+
+    fn text_update(update_id: i64, user_id: i64, text: &str) -> serde_json::Value {
+        json!({
+            "update_id": update_id,
+            "message": {
+                "message_id": update_id,
+                "from": {"id": user_id, "is_bot": false, "first_name": "alex"},
+                "chat": {"id": user_id, "type": "private"},
+                "date": 1700000000,
+                "text": text
+            }
+        })
+    }
+
+    fn mock_state(allowed_ids: Vec<i64>) -> TelegramState<MockTransport> {
+        TelegramState::new(
+            TelegramBot::with_transport(MockTransport::new()),
+            allowed_ids,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_telegram_message_gets_reply() {
+        let state = mock_state(vec![1001]);
+        let transport = state.bot.transport();
+        transport.push_updates(json!([text_update(1, 1001, "hello")]));
+
+        let mut updates = state.bot.get_updates(None).await.unwrap();
+        let inbound = handle_update(&state, updates.remove(0)).await.unwrap();
+        let db = Database::open_in_memory().unwrap();
+        run_telegram_turn(&state, GreetingProvider, db, 1001, inbound).await;
+
+        assert_eq!(transport.sent_texts(), vec!["you said: hello"]);
+        assert_eq!(transport.sent.lock().unwrap()[0].chat_id, 1001);
+        assert_eq!(
+            *transport.chat_actions.lock().unwrap(),
+            vec![(1001, "typing".to_string())]
+        );
+    }
+
+    /// fails every turn, like an API that is down
+    struct DownProvider;
+
+    impl Provider for DownProvider {
+        async fn complete(
+            &self,
+            _system_prompt: &str,
+            _messages: &[Message],
+            _tools: &[ToolDefinition],
+        ) -> Result<ProviderResponse, error::Error> {
+            Err(error::Error::Provider("overloaded".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_errors_get_one_reply() {
+        let state = mock_state(vec![1001]);
+        let transport = state.bot.transport();
+        transport.push_updates(json!([
+            text_update(1, 1001, "hello"),
+            text_update(2, 1001, "hello?")
+        ]));
+
+        for update in state.bot.get_updates(None).await.unwrap() {
+            let inbound = handle_update(&state, update).await.unwrap();
+            let db = Database::open_in_memory().unwrap();
+            run_telegram_turn(&state, DownProvider, db, 1001, inbound).await;
+        }
+
+        assert_eq!(transport.sent_texts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_exec_output_is_sent_in_chunks() {
+        let bot = Arc::new(TelegramBot::with_transport(MockTransport::new()));
+        let (sink, outputs) = tool::OutputSink::channel();
+        let task = spawn_exec_output(Arc::clone(&bot), 1001, outputs);
+
+        let output: String = (0..1200).map(|i| format!("line {i:04}\n")).collect();
+        let context = tool::ToolContext {
+            channel: ChannelKind::Telegram,
+            chat_id: Some(1001),
+            user_id: Some(1001),
+            session_id: None,
+            exec_output: Some(sink),
+        };
+        let call = tool::ToolCall {
+            id: "call_1".into(),
+            name: tool::EXEC_TOOL_NAME.into(),
+            input: json!({"command": format!("printf '{}'", output.replace('\n', "\\n"))}),
+        };
+        let db = Database::open_in_memory().unwrap();
+        let result = tool::handle_tool_call(&db, &call, &context, None)
+            .await
+            .unwrap();
+        drop(context);
+        task.await.unwrap();
+
+        let sent = bot.transport().sent_texts();
+        assert!(
+            sent.len() > 1,
+            "expected several messages, got {}",
+            sent.len()
+        );
+        assert!(sent.iter().all(|text| text.chars().count() <= 4096));
+        let delivered: String = sent
+            .iter()
+            .map(|text| text.split_once('\n').unwrap().1)
+            .collect();
+        assert!(delivered.contains(output.trim_end()));
+        assert!(matches!(
+            result,
+            MessageContent::ToolResult { ref content, .. } if content.contains("full output")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_telegram_commands_and_strangers_skip_the_agent() {
+        let state = mock_state(vec![1001]);
+        let transport = state.bot.transport();
+        transport.push_updates(json!([
+            text_update(1, 2002, "hello"),
+            text_update(2, 1001, "/help"),
+            text_update(3, 1001, "/cancel"),
+            text_update(4, 1001, "/pending"),
+        ]));
+
+        for update in state.bot.get_updates(None).await.unwrap() {
+            assert!(handle_update(&state, update).await.is_none());
+        }
+
+        let sent = transport.sent_texts();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].contains("/help shows this message"));
+        assert_eq!(sent[1], "nothing to cancel");
+        assert_eq!(sent[2], "nothing waiting for approval");
+    }
 }
@@ -29,6 +29,11 @@ pub enum MessageContent {
         tool_use_id: String,
         content: String,
     },
+    /// a block the API handles itself, like `server_tool_use` or
+    /// `web_search_tool_result`. it's kept as it came, so it can be sent
+    /// back unchanged.
+    #[serde(untagged)]
+    Server(Value),
 }
 
 impl MessageContent {
@@ -50,6 +55,12 @@ impl MessageContent {
             content: content.into(),
         }
     }
+
+    /// a tool result holding structured data, as JSON text the model can
+    /// read directly instead of re-parsing prose
+    pub fn tool_result_json(tool_use_id: impl Into<String>, value: &Value) -> Self {
+        Self::tool_result(tool_use_id, value.to_string())
+    }
 }
 
 impl Message {
@@ -57,6 +68,7 @@ impl Message {
         Self::user_with_content(vec![MessageContent::text(content)])
     }
 
+    #[allow(dead_code)]
     pub fn assistant(content: impl Into<String>) -> Self {
         Self::assistant_with_content(vec![MessageContent::text(content)])
     }
@@ -77,7 +89,8 @@ impl Message {
 }
 
 /// where the message came from
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum ChannelKind {
     Cli,
     Telegram,
@@ -88,6 +101,53 @@ pub enum ChannelKind {
 pub struct InboundMessage {
     pub channel: ChannelKind,
     pub content: String,
+    /// the chat the message was sent in, for channels that have chats
+    pub chat_id: Option<i64>,
+    /// the sender, for channels that identify users
+    pub user_id: Option<i64>,
+    /// links the user sent, verbatim, for channels that mark them up
+    pub links: Vec<String>,
+    /// replaces the base system prompt for this message only. facts are
+    /// still added after it.
+    pub system_prompt_override: Option<String>,
+}
+
+impl InboundMessage {
+    pub fn new(channel: ChannelKind, content: impl Into<String>) -> Self {
+        Self {
+            channel,
+            content: content.into(),
+            chat_id: None,
+            user_id: None,
+            links: Vec::new(),
+            system_prompt_override: None,
+        }
+    }
+
+    pub fn with_sender(mut self, chat_id: i64, user_id: Option<i64>) -> Self {
+        self.chat_id = Some(chat_id);
+        self.user_id = user_id;
+        self
+    }
+
+    pub fn with_links(mut self, links: Vec<String>) -> Self {
+        self.links = links;
+        self
+    }
+
+    pub fn with_system_prompt_override(mut self, system_prompt: Option<String>) -> Self {
+        self.system_prompt_override = system_prompt;
+        self
+    }
+
+    /// the channel key sessions are stored under: one per chat, or "cli"
+    pub fn session_channel(&self) -> String {
+        match (self.channel, self.chat_id) {
+            (ChannelKind::Telegram, Some(chat_id)) => format!("telegram:{chat_id}"),
+            (ChannelKind::Telegram, None) => "telegram".to_string(),
+            (ChannelKind::Cli, _) => "cli".to_string(),
+        }
+    }
 }
 
 /// a message going out from the agent
@@ -95,3 +155,25 @@ pub struct InboundMessage {
 pub struct OutboundMessage {
     pub content: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_blocks_round_trip_unchanged() {
+        let block = serde_json::json!({
+            "type": "web_search_tool_result",
+            "tool_use_id": "srvtoolu_1",
+            "content": [{"type": "web_search_result", "url": "https://example.com"}]
+        });
+        let content: MessageContent = serde_json::from_value(block.clone()).unwrap();
+        assert!(matches!(content, MessageContent::Server(_)));
+        assert_eq!(serde_json::to_value(&content).unwrap(), block);
+
+        // known blocks still parse as themselves
+        let text: MessageContent =
+            serde_json::from_value(serde_json::json!({"type": "text", "text": "hi"})).unwrap();
+        assert!(matches!(text, MessageContent::Text { .. }));
+    }
+}
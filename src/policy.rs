@@ -0,0 +1,218 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::db::{matches_any_subcommand, matches_rule};
+use crate::error::Error;
+use crate::tool::{ApprovalDecision, Approver, EXEC_TOOL_NAME, ToolCall, references_sensitive_env};
+
+/// exec commands that are allowed or denied without asking anyone, read from
+/// AVA_APPROVAL_POLICY_FILE. patterns work like saved approval rules, e.g.
+/// `git status` or `ls *`:
+///
+/// `{"allow": ["ls *", "git status"], "deny": ["rm *", "curl *"]}`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyFile {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl PolicyFile {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            Error::InvalidConfig(format!("approval policy in {}: {e}", path.display()))
+        })
+    }
+
+    /// the policy in AVA_APPROVAL_POLICY_FILE, or an empty one if it isn't set
+    pub fn from_env() -> Result<Self, Error> {
+        match config::approval_policy_file() {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// what the policy decides for `call`, or `None` when it's up to the
+    /// user. deny wins over allow. a command touching sensitive env vars is
+    /// never allowed by policy, the same way it can't get an "allow always".
+    pub fn decide(&self, call: &ToolCall) -> Option<ApprovalDecision> {
+        if call.name != EXEC_TOOL_NAME {
+            return None;
+        }
+        let command = call.input.get("command")?.as_str()?;
+        let matches = |patterns: &[String], matches: fn(&str, &str) -> bool| {
+            patterns
+                .iter()
+                .find(|pattern| matches(pattern, command))
+                .cloned()
+        };
+
+        // a chained command is denied if any part of it is, but only
+        // allowed if every part is
+        if let Some(pattern) = matches(&self.deny, matches_any_subcommand) {
+            tracing::info!(command, pattern, "denied by approval policy");
+            return Some(ApprovalDecision::Deny);
+        }
+        if references_sensitive_env(command) {
+            return None;
+        }
+        let pattern = matches(&self.allow, matches_rule)?;
+        tracing::info!(command, pattern, "allowed by approval policy");
+        Some(ApprovalDecision::AutoApproved)
+    }
+}
+
+/// a policy every approver reads from, which can be swapped out while they
+/// run, e.g. when the file is reloaded on SIGHUP
+#[derive(Debug, Clone, Default)]
+pub struct SharedPolicy(Arc<RwLock<PolicyFile>>);
+
+impl SharedPolicy {
+    pub fn new(policy: PolicyFile) -> Self {
+        Self(Arc::new(RwLock::new(policy)))
+    }
+
+    pub fn decide(&self, call: &ToolCall) -> Option<ApprovalDecision> {
+        self.0.read().unwrap().decide(call)
+    }
+
+    /// reads the policy file again. on failure the current policy stays.
+    pub fn reload(&self, path: &Path) -> Result<(), Error> {
+        let policy = PolicyFile::load(path)?;
+        tracing::info!(
+            allow = policy.allow.len(),
+            deny = policy.deny.len(),
+            "reloaded approval policy"
+        );
+        *self.0.write().unwrap() = policy;
+        Ok(())
+    }
+}
+
+/// consults the policy before asking `inner`
+pub struct PolicyApprover<A> {
+    inner: A,
+    policy: SharedPolicy,
+}
+
+impl<A: Approver> PolicyApprover<A> {
+    pub fn new(inner: A, policy: SharedPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl<A: Approver> Approver for PolicyApprover<A> {
+    async fn request_approval(&self, tool_call: &ToolCall) -> Result<ApprovalDecision, Error> {
+        match self.policy.decide(tool_call) {
+            Some(decision) => Ok(decision),
+            None => self.inner.request_approval(tool_call).await,
+        }
+    }
+}
+
+/// reloads the policy file whenever the process gets SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup(policy: SharedPolicy, path: std::path::PathBuf) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::warn!(%e, "can't listen for SIGHUP, the approval policy won't reload");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(e) = policy.reload(&path) {
+            tracing::error!(%e, "failed to reload the approval policy, keeping the old one");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn exec(command: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".into(),
+            name: EXEC_TOOL_NAME.into(),
+            input: json!({"command": command}),
+        }
+    }
+
+    /// asks nobody, a decision is needed for every call
+    struct NoUser;
+
+    impl Approver for NoUser {
+        async fn request_approval(&self, _tool_call: &ToolCall) -> Result<ApprovalDecision, Error> {
+            Err(Error::ApprovalTimeout)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_file_allows_and_denies() {
+        let path = std::env::temp_dir().join(format!("ava-policy-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"allow": ["ls *", "git *"], "deny": ["git push *"]}"#,
+        )
+        .unwrap();
+        let policy = PolicyFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let approver = PolicyApprover::new(NoUser, SharedPolicy::new(policy));
+
+        assert_eq!(
+            approver.request_approval(&exec("ls -la")).await.unwrap(),
+            ApprovalDecision::AutoApproved
+        );
+        // deny wins over the broader allow
+        assert_eq!(
+            approver
+                .request_approval(&exec("git push origin main"))
+                .await
+                .unwrap(),
+            ApprovalDecision::Deny
+        );
+        // a denied command chained after an allowed one is still denied
+        assert_eq!(
+            approver
+                .request_approval(&exec("ls && git push origin main"))
+                .await
+                .unwrap(),
+            ApprovalDecision::Deny
+        );
+        assert_eq!(
+            approver
+                .request_approval(&exec("git status; git log"))
+                .await
+                .unwrap(),
+            ApprovalDecision::AutoApproved
+        );
+        // anything else is still up to the user
+        assert!(approver.request_approval(&exec("whoami")).await.is_err());
+        assert!(
+            approver
+                .request_approval(&exec("ls $ANTHROPIC_API_KEY"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_policy_file_rejects_unknown_fields() {
+        let path =
+            std::env::temp_dir().join(format!("ava-policy-typo-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"alow": ["ls *"]}"#).unwrap();
+        let result = PolicyFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+}
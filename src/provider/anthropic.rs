@@ -1,36 +1,158 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{self, DEFAULT_MAX_TOKENS, DEFAULT_MODEL};
 use crate::error::Error;
-use crate::message::Message;
-use crate::provider::{Provider, ProviderResponse, StopReason, ToolCall};
-use crate::tool::{ToolDefinition, tool_definitions};
+use crate::message::{Message, MessageContent};
+#[cfg(feature = "streaming")]
+use crate::provider::ToolCall;
+#[cfg(feature = "streaming")]
+use crate::provider::stream::{SseParser, StreamAccumulator, StreamEvent};
+use crate::provider::{Provider, ProviderResponse, StopReason, ToolChoice};
+use crate::tool::ToolDefinition;
 
 const API_URL: &str = "https://api.anthropic.com/v1/messages";
-const DEFAULT_MODEL: &str = "claude-sonnet-4-5";
-const DEFAULT_MAX_TOKENS: u32 = 8192;
 
+#[derive(Clone)]
 pub struct AnthropicProvider {
     client: Client,
-    api_key: String,
+    // shared between clones, so they rotate through the same keys
+    api_keys: Arc<ApiKeys>,
     model: String,
     max_tokens: u32,
+    stop_sequences: Vec<String>,
+    beta: Vec<String>,
+    extra_headers: Vec<(String, String)>,
 }
 
 impl AnthropicProvider {
+    #[cfg(test)]
     pub fn new(api_key: String) -> Self {
+        Self::with_api_keys(vec![api_key])
+    }
+
+    /// spreads requests over `api_keys`, e.g. to stay under each key's rate
+    /// limit. there must be at least one.
+    pub fn with_api_keys(api_keys: Vec<String>) -> Self {
         Self {
-            client: Client::new(),
-            api_key,
+            client: crate::http::client(),
+            api_keys: Arc::new(ApiKeys::new(api_keys)),
             model: DEFAULT_MODEL.to_string(),
             max_tokens: DEFAULT_MAX_TOKENS,
+            stop_sequences: Vec::new(),
+            beta: Vec::new(),
+            extra_headers: Vec::new(),
         }
     }
 
+    /// stop generating as soon as the model outputs one of these
+    #[allow(dead_code)]
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    /// beta features to opt into, e.g. `prompt-caching-2024-07-31`
+    pub fn with_beta(mut self, beta: Vec<String>) -> Self {
+        self.beta = beta;
+        self
+    }
+
+    /// headers sent with every request, on top of the ones the API needs
+    #[allow(dead_code)]
+    pub fn with_extra_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    /// uses the comma-separated keys in ANTHROPIC_API_KEYS, or else
+    /// ANTHROPIC_API_KEY
     pub fn from_env() -> Result<Self, Error> {
-        let api_key = std::env::var("ANTHROPIC_API_KEY")
-            .map_err(|_| Error::MissingApiKey("ANTHROPIC_API_KEY"))?;
-        Ok(Self::new(api_key))
+        let mut api_keys = parse_api_keys(&std::env::var("ANTHROPIC_API_KEYS").unwrap_or_default());
+        if api_keys.is_empty() {
+            let api_key = std::env::var("ANTHROPIC_API_KEY")
+                .map_err(|_| Error::MissingApiKey("ANTHROPIC_API_KEY"))?;
+            api_keys.push(api_key);
+        }
+        let mut provider = Self::with_api_keys(api_keys);
+        provider.model = config::model();
+        provider.max_tokens = config::max_tokens();
+        Ok(provider.with_beta(config::anthropic_beta()))
+    }
+}
+
+fn parse_api_keys(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// the API keys requests are spread over, round-robin. a key the API
+/// rejects is taken out of rotation, unless it's the last one left.
+struct ApiKeys {
+    keys: Vec<String>,
+    next: AtomicUsize,
+    rejected: Mutex<Vec<bool>>,
+}
+
+impl ApiKeys {
+    fn new(keys: Vec<String>) -> Self {
+        assert!(!keys.is_empty(), "at least one api key is needed");
+        Self {
+            rejected: Mutex::new(vec![false; keys.len()]),
+            keys,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// the next key still in rotation, with its index. a rejected key's
+    /// turn goes to the key after it.
+    fn next(&self) -> (usize, String) {
+        let rejected = self.rejected.lock().unwrap();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = (start..start + self.keys.len())
+            .map(|i| i % self.keys.len())
+            .find(|&i| !rejected[i])
+            .expect("one key always stays in rotation");
+        (index, self.keys[index].clone())
+    }
+
+    /// takes the key out of rotation, returns false if it's the last one
+    fn reject(&self, index: usize) -> bool {
+        let mut rejected = self.rejected.lock().unwrap();
+        if rejected.iter().filter(|&&rejected| !rejected).count() <= 1 {
+            return false;
+        }
+        rejected[index] = true;
+        true
+    }
+
+    /// runs `attempt` with the next key. when the key is rejected, it's
+    /// dropped and the request is tried again with the next one.
+    async fn send_with<T, F, Fut>(&self, mut attempt: F) -> Result<T, Error>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        loop {
+            let (index, key) = self.next();
+            match attempt(key).await {
+                Err(Error::Unauthorized(message)) => {
+                    if !self.reject(index) {
+                        return Err(Error::Unauthorized(message));
+                    }
+                    tracing::warn!(key = index, %message, "api key rejected, dropping it from rotation");
+                }
+                result => return result,
+            }
+        }
     }
 }
 
@@ -40,7 +162,14 @@ struct ApiRequest<'a> {
     max_tokens: u32,
     system: &'a str,
     messages: &'a [Message],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
     tools: &'a [ToolDefinition],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'a ToolChoice>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -60,6 +189,30 @@ enum ContentBlock {
         name: String,
         input: serde_json::Value,
     },
+    /// e.g. `server_tool_use` and `web_search_tool_result`
+    #[serde(untagged)]
+    Server(serde_json::Value),
+}
+
+impl From<ApiResponse> for ProviderResponse {
+    fn from(response: ApiResponse) -> Self {
+        let content = response
+            .content
+            .into_iter()
+            .map(|block| match block {
+                ContentBlock::Text { text } => MessageContent::text(text),
+                ContentBlock::ToolUse { id, name, input } => {
+                    MessageContent::tool_use(id, name, input)
+                }
+                ContentBlock::Server(block) => MessageContent::Server(block),
+            })
+            .collect();
+
+        ProviderResponse {
+            content,
+            stop_reason: response.stop_reason,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,67 +225,207 @@ struct ApiErrorDetail {
     message: String,
 }
 
-impl Provider for AnthropicProvider {
+impl AnthropicProvider {
+    fn request(&self, api_key: &str, request: &ApiRequest<'_>) -> reqwest::RequestBuilder {
+        let mut builder = self
+            .client
+            .post(API_URL)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("content-type", "application/json");
+        if !self.beta.is_empty() {
+            builder = builder.header("anthropic-beta", self.beta.join(","));
+        }
+        for (name, value) in &self.extra_headers {
+            builder = builder.header(name, value);
+        }
+        builder.json(request)
+    }
+
+    async fn send(&self, request: &ApiRequest<'_>) -> Result<reqwest::Response, Error> {
+        self.api_keys
+            .send_with(|api_key| self.send_with_key(api_key, request))
+            .await
+    }
+
+    async fn send_with_key(
+        &self,
+        api_key: String,
+        request: &ApiRequest<'_>,
+    ) -> Result<reqwest::Response, Error> {
+        let response = self.request(&api_key, request).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error: ApiError = response.json().await?;
+            return Err(api_error(status.as_u16(), error.error.message));
+        }
+
+        Ok(response)
+    }
+
+    /// one non-streaming request to the messages API
     #[tracing::instrument(skip_all, fields(model = %self.model))]
-    async fn complete(
+    async fn create(
         &self,
         system_prompt: &str,
         messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: Option<&ToolChoice>,
     ) -> Result<ProviderResponse, Error> {
-        let tools = tool_definitions();
         let request = ApiRequest {
             model: &self.model,
             max_tokens: self.max_tokens,
             system: system_prompt,
             messages,
-            tools: &tools,
+            tools,
+            stop_sequences: &self.stop_sequences,
+            tool_choice,
+            stream: false,
         };
 
-        let response = self
-            .client
-            .post(API_URL)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error: ApiError = response.json().await?;
-            return Err(Error::Provider(error.error.message));
-        }
-
+        let response = self.send(&request).await?;
         let api_response: ApiResponse = response.json().await?;
+        Ok(api_response.into())
+    }
+
+    /// one streaming request to the messages API
+    #[cfg(feature = "streaming")]
+    #[tracing::instrument(skip_all, fields(model = %self.model))]
+    async fn stream(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: Option<&ToolChoice>,
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> Result<ProviderResponse, Error> {
+        let request = ApiRequest {
+            model: &self.model,
+            max_tokens: self.max_tokens,
+            system: system_prompt,
+            messages,
+            tools,
+            stop_sequences: &self.stop_sequences,
+            tool_choice,
+            stream: true,
+        };
 
-        let mut content = String::new();
-        let mut tool_calls = Vec::new();
+        let mut response = self.send(&request).await?;
+        let mut parser = SseParser::new();
+        let mut accumulator = StreamAccumulator::new();
 
-        for block in api_response.content {
-            match block {
-                ContentBlock::Text { text } => {
-                    if !content.is_empty() {
-                        content.push('\n');
-                    }
-                    content.push_str(&text);
+        while let Some(chunk) = response.chunk().await? {
+            for data in parser.push(&chunk) {
+                let event: StreamEvent = serde_json::from_str(&data)
+                    .map_err(|e| Error::Provider(format!("invalid stream event: {e}")))?;
+                if let Some(text) = accumulator.text_delta(&event) {
+                    on_text(&text);
                 }
-                ContentBlock::ToolUse { id, name, input } => {
-                    tool_calls.push(ToolCall { id, name, input });
+                if let Some(call) = accumulator.push(event)? {
+                    on_tool_call(&call);
                 }
             }
         }
 
-        Ok(ProviderResponse {
-            content,
-            stop_reason: api_response.stop_reason,
-            tool_calls,
-        })
+        accumulator.finish()
+    }
+}
+
+/// only a 401 means the key itself is bad. a 403 is about what the key may
+/// do, so it doesn't get the key dropped from rotation.
+fn api_error(status: u16, message: String) -> Error {
+    match status {
+        401 => Error::Unauthorized(message),
+        403 => Error::Forbidden(message),
+        429 => Error::RateLimited(message),
+        400 if is_context_length_error(&message) => Error::ContextTooLong(message),
+        _ => Error::Provider(message),
+    }
+}
+
+/// whether a 400 is about the prompt not fitting, e.g. `prompt is too long:
+/// 210000 tokens > 200000 maximum`. only the wording anthropic uses counts, so
+/// other bad requests aren't retried.
+fn is_context_length_error(message: &str) -> bool {
+    message.to_ascii_lowercase().contains("prompt is too long")
+}
+
+impl Provider for AnthropicProvider {
+    async fn complete(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<ProviderResponse, Error> {
+        self.create(system_prompt, messages, tools, None).await
+    }
+
+    async fn complete_with_tool_choice(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: &ToolChoice,
+    ) -> Result<ProviderResponse, Error> {
+        self.create(system_prompt, messages, tools, Some(tool_choice))
+            .await
+    }
+
+    #[cfg(feature = "streaming")]
+    #[tracing::instrument(skip_all, fields(model = %self.model))]
+    async fn complete_streaming(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+    ) -> Result<ProviderResponse, Error> {
+        self.complete_streaming_text(system_prompt, messages, tools, on_tool_call, &|_| {})
+            .await
+    }
+
+    #[cfg(feature = "streaming")]
+    async fn complete_streaming_text(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> Result<ProviderResponse, Error> {
+        self.stream(system_prompt, messages, tools, None, on_tool_call, on_text)
+            .await
+    }
+
+    #[cfg(feature = "streaming")]
+    async fn complete_streaming_with_tool_choice(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: &ToolChoice,
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> Result<ProviderResponse, Error> {
+        self.stream(
+            system_prompt,
+            messages,
+            tools,
+            Some(tool_choice),
+            on_tool_call,
+            on_text,
+        )
+        .await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tool::tool_definitions;
+    use serde_json::json;
 
     #[test]
     fn test_parse_text_response() {
@@ -154,20 +447,57 @@ mod tests {
 
         assert_eq!(response.content.len(), 2);
 
-        // verify the joining logic works as expected
-        let mut content = String::new();
-        for block in &response.content {
-            match block {
-                ContentBlock::Text { text } => {
-                    if !content.is_empty() {
-                        content.push('\n');
-                    }
-                    content.push_str(text);
-                }
-                _ => {}
-            }
-        }
-        assert_eq!(content, "hello\nworld");
+        let response = ProviderResponse::from(response);
+        assert_eq!(response.text(), "hello\nworld");
+    }
+
+    #[test]
+    fn test_server_blocks_are_kept_as_sent() {
+        let json = r#"{"content":[
+            {"type":"text","text":"let me look"},
+            {"type":"server_tool_use","id":"srvtoolu_1","name":"web_search","input":{"query":"rust"}},
+            {"type":"web_search_tool_result","tool_use_id":"srvtoolu_1","content":[]}
+        ],"stop_reason":"pause_turn"}"#;
+        let response = ProviderResponse::from(serde_json::from_str::<ApiResponse>(json).unwrap());
+
+        assert_eq!(response.stop_reason, StopReason::PauseTurn);
+        assert_eq!(response.text(), "let me look");
+        // sent back exactly as they came
+        let sent = serde_json::to_value(&response.content).unwrap();
+        assert_eq!(
+            sent[1],
+            json!({"type":"server_tool_use","id":"srvtoolu_1","name":"web_search","input":{"query":"rust"}})
+        );
+        assert_eq!(
+            sent[2],
+            json!({"type":"web_search_tool_result","tool_use_id":"srvtoolu_1","content":[]})
+        );
+    }
+
+    #[test]
+    fn test_interleaved_blocks_keep_their_order() {
+        let json = r#"{"content":[
+            {"type":"text","text":"first"},
+            {"type":"tool_use","id":"toolu_1","name":"whoami","input":{}},
+            {"type":"text","text":"then"},
+            {"type":"tool_use","id":"toolu_2","name":"web_search","input":{"query":"rust"}}
+        ],"stop_reason":"tool_use"}"#;
+        let response: ApiResponse = serde_json::from_str(json).unwrap();
+
+        let response = ProviderResponse::from(response);
+
+        let order: Vec<&str> = response
+            .content
+            .iter()
+            .map(|block| match block {
+                MessageContent::Text { text } => text.as_str(),
+                MessageContent::ToolUse { id, .. } => id.as_str(),
+                MessageContent::ToolResult { .. } => "result",
+                MessageContent::Server(_) => "server",
+            })
+            .collect();
+        assert_eq!(order, vec!["first", "toolu_1", "then", "toolu_2"]);
+        assert_eq!(response.tool_calls().len(), 2);
     }
 
     #[test]
@@ -187,6 +517,18 @@ mod tests {
         assert_eq!(response.stop_reason, StopReason::ToolUse);
     }
 
+    #[test]
+    fn test_parse_refusal_and_pause_turn() {
+        let json = r#"{"content":[{"type":"text","text":"i can't help with that"}],"stop_reason":"refusal"}"#;
+        let response = ProviderResponse::from(serde_json::from_str::<ApiResponse>(json).unwrap());
+        assert_eq!(response.stop_reason, StopReason::Refusal);
+        assert_eq!(response.text(), "i can't help with that");
+
+        let json = r#"{"content":[],"stop_reason":"pause_turn"}"#;
+        let response: ApiResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.stop_reason, StopReason::PauseTurn);
+    }
+
     #[test]
     fn test_parse_api_error() {
         let json = r#"{"error":{"message":"invalid api key"}}"#;
@@ -195,6 +537,68 @@ mod tests {
         assert_eq!(error.error.message, "invalid api key");
     }
 
+    #[tokio::test]
+    async fn test_api_keys_rotate_and_drop_rejected_ones() {
+        let keys = ApiKeys::new(parse_api_keys(" key-a, key-b,,key-c "));
+        let used = Mutex::new(Vec::new());
+        let send = |key: String| {
+            used.lock().unwrap().push(key.clone());
+            async move {
+                match key.as_str() {
+                    "key-b" => Err(Error::Unauthorized("invalid x-api-key".into())),
+                    _ => Ok(key),
+                }
+            }
+        };
+
+        let mut answered = Vec::new();
+        for _ in 0..4 {
+            answered.push(keys.send_with(send).await.unwrap());
+        }
+        // the request that got key-b's 401 was retried with key-c, and key-b
+        // wasn't used again
+        assert_eq!(answered, vec!["key-a", "key-c", "key-a", "key-c"]);
+        assert_eq!(
+            *used.lock().unwrap(),
+            vec!["key-a", "key-b", "key-c", "key-a", "key-c"]
+        );
+
+        // the last key standing is kept, so the error comes through
+        let only = ApiKeys::new(vec!["key-b".into()]);
+        assert!(matches!(
+            only.send_with(send).await,
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            only.send_with(send).await,
+            Err(Error::Unauthorized(_))
+        ));
+
+        // a 403 is about permissions, the key stays in rotation
+        assert!(matches!(
+            api_error(403, "not allowed".into()),
+            Error::Forbidden(_)
+        ));
+        let forbidden = ApiKeys::new(parse_api_keys("key-a,key-b"));
+        let deny = |key: String| async move { Err::<String, _>(Error::Forbidden(key)) };
+        for expected in ["key-a", "key-b", "key-a"] {
+            assert!(matches!(
+                forbidden.send_with(deny).await,
+                Err(Error::Forbidden(key)) if key == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn test_context_length_errors_are_recognized() {
+        assert!(is_context_length_error(
+            "prompt is too long: 210463 tokens > 200000 maximum"
+        ));
+        assert!(!is_context_length_error(
+            "max_tokens: 100000 > 64000, which is the maximum allowed"
+        ));
+    }
+
     #[test]
     fn test_request_serialization() {
         let messages = vec![Message::user("hello")];
@@ -205,6 +609,9 @@ mod tests {
             system: "test system prompt",
             messages: &messages,
             tools: &tools,
+            stop_sequences: &[],
+            tool_choice: None,
+            stream: false,
         };
 
         let json = serde_json::to_value(&request).unwrap();
@@ -217,4 +624,120 @@ mod tests {
         assert_eq!(json["messages"][0]["content"][0]["text"], "hello");
         assert_eq!(json["tools"][0]["name"], "remember_fact");
     }
+
+    #[test]
+    fn test_tool_choice_serialization() {
+        let messages = vec![Message::user("hello")];
+        let tools = tool_definitions();
+        let serialized = |tool_choice: Option<&ToolChoice>| {
+            let request = ApiRequest {
+                model: "claude-sonnet-4-5",
+                max_tokens: 1024,
+                system: "",
+                messages: &messages,
+                tools: &tools,
+                stop_sequences: &[],
+                tool_choice,
+                stream: false,
+            };
+            serde_json::to_value(&request).unwrap()
+        };
+
+        assert!(serialized(None).get("tool_choice").is_none());
+        assert_eq!(
+            serialized(Some(&ToolChoice::Auto))["tool_choice"],
+            json!({"type": "auto"})
+        );
+        assert_eq!(
+            serialized(Some(&ToolChoice::Any))["tool_choice"],
+            json!({"type": "any"})
+        );
+        let search = ToolChoice::parse("tool:web_search").unwrap();
+        assert_eq!(
+            serialized(Some(&search))["tool_choice"],
+            json!({"type": "tool", "name": "web_search"})
+        );
+        assert_eq!(ToolChoice::parse("any"), Some(ToolChoice::Any));
+        assert_eq!(ToolChoice::parse("tool:"), None);
+        assert_eq!(ToolChoice::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn test_beta_flags_and_extra_headers_are_sent() {
+        let provider = AnthropicProvider::new("key".into())
+            .with_beta(vec![
+                "prompt-caching-2024-07-31".into(),
+                "token-efficient-tools-2025-02-19".into(),
+            ])
+            .with_extra_headers(vec![("x-trace".into(), "ava".into())]);
+        let messages = vec![Message::user("hello")];
+        let request = ApiRequest {
+            model: "claude-sonnet-4-5",
+            max_tokens: 1024,
+            system: "",
+            messages: &messages,
+            tools: &[],
+            stop_sequences: &[],
+            tool_choice: None,
+            stream: false,
+        };
+
+        let built = provider.request("key", &request).build().unwrap();
+
+        assert_eq!(
+            built.headers()["anthropic-beta"],
+            "prompt-caching-2024-07-31,token-efficient-tools-2025-02-19"
+        );
+        assert_eq!(built.headers()["x-trace"], "ava");
+        assert_eq!(built.headers()["anthropic-version"], "2023-06-01");
+        assert_eq!(built.headers()["x-api-key"], "key");
+
+        let plain = AnthropicProvider::new("key".into());
+        let built = plain.request("key", &request).build().unwrap();
+        assert!(built.headers().get("anthropic-beta").is_none());
+    }
+
+    #[test]
+    fn test_request_serialization_omits_empty_tools() {
+        let messages = vec![Message::user("hello")];
+        let request = ApiRequest {
+            model: "claude-sonnet-4-5",
+            max_tokens: 1024,
+            system: "test system prompt",
+            messages: &messages,
+            tools: &[],
+            stop_sequences: &[],
+            tool_choice: None,
+            stream: false,
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert!(json.get("tools").is_none());
+        assert!(json.get("stream").is_none());
+        assert!(json.get("stop_sequences").is_none());
+    }
+
+    #[test]
+    fn test_request_serialization_includes_stop_sequences() {
+        let messages = vec![Message::user("hello")];
+        let stop_sequences = vec!["</answer>".to_string(), "\n\nhuman:".to_string()];
+        let request = ApiRequest {
+            model: "claude-sonnet-4-5",
+            max_tokens: 1024,
+            system: "test system prompt",
+            messages: &messages,
+            tools: &[],
+            stop_sequences: &stop_sequences,
+            tool_choice: None,
+            stream: false,
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(
+            json["stop_sequences"],
+            serde_json::json!(["</answer>", "\n\nhuman:"])
+        );
+    }
 }
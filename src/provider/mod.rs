@@ -1,4 +1,11 @@
 mod anthropic;
+// translates tools to and from openai's chat completions format, which ollama
+// and most compatible servers also speak. nothing uses it until an openai
+// provider lands.
+#[allow(dead_code)]
+mod openai;
+#[cfg(feature = "streaming")]
+mod stream;
 
 pub use crate::tool::ToolCall;
 pub use anthropic::AnthropicProvider;
@@ -8,9 +15,15 @@ use std::future::Future;
 use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
-use crate::message::Message;
+use crate::message::{Message, MessageContent};
+use crate::tool::ToolDefinition;
 
-pub const DEFAULT_SYSTEM_PROMPT: &str = "you are ava, a personal ai assistant. be helpful, concise, and friendly. avoid unnecessary verbosity.";
+/// the base system prompt, introducing the assistant by `name`
+pub fn default_system_prompt(name: &str) -> String {
+    format!(
+        "you are {name}, a personal ai assistant. be helpful, concise, and friendly. avoid unnecessary verbosity."
+    )
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -19,13 +32,77 @@ pub enum StopReason {
     MaxTokens,
     StopSequence,
     ToolUse,
+    /// the model declined to answer
+    Refusal,
+    /// a long-running server tool paused the turn. sending the response back
+    /// lets the model carry on.
+    PauseTurn,
+}
+
+/// whether and which tool the model must call in its response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// the model decides, the default
+    Auto,
+    /// some tool must be called
+    Any,
+    /// this tool must be called
+    Tool { name: String },
+}
+
+impl ToolChoice {
+    /// parses `auto`, `any` or `tool:<name>`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "auto" => Some(Self::Auto),
+            "any" => Some(Self::Any),
+            other => other
+                .strip_prefix("tool:")
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Self::Tool {
+                    name: name.to_string(),
+                }),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ProviderResponse {
-    pub content: String,
+    /// text and tool_use blocks, in the order the model produced them.
+    /// sending them back in that order keeps the model's reasoning intact.
+    pub content: Vec<MessageContent>,
     pub stop_reason: StopReason,
-    pub tool_calls: Vec<ToolCall>,
+}
+
+impl ProviderResponse {
+    /// the text blocks, joined by newlines
+    pub fn text(&self) -> String {
+        let texts: Vec<&str> = self
+            .content
+            .iter()
+            .filter_map(|block| match block {
+                MessageContent::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        texts.join("\n")
+    }
+
+    pub fn tool_calls(&self) -> Vec<ToolCall> {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                MessageContent::ToolUse { id, name, input } => Some(ToolCall {
+                    id: id.clone(),
+                    name: name.clone(),
+                    input: input.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 pub trait Provider: Send + Sync {
@@ -33,5 +110,91 @@ pub trait Provider: Send + Sync {
         &self,
         system_prompt: &str,
         messages: &[Message],
+        tools: &[ToolDefinition],
     ) -> impl Future<Output = Result<ProviderResponse, Error>> + Send;
+
+    /// like `complete`, with `tool_choice` deciding whether and which tool
+    /// the model must call. providers that can't force tools ignore it.
+    fn complete_with_tool_choice(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: &ToolChoice,
+    ) -> impl Future<Output = Result<ProviderResponse, Error>> + Send {
+        let _ = tool_choice;
+        self.complete(system_prompt, messages, tools)
+    }
+
+    /// like `complete`, but calls `on_tool_call` as soon as each tool call's
+    /// input is complete, while the rest of the response may still be arriving.
+    /// providers that can't stream report every call once the response is in.
+    #[cfg(feature = "streaming")]
+    fn complete_streaming(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+    ) -> impl Future<Output = Result<ProviderResponse, Error>> + Send {
+        async move {
+            let response = self.complete(system_prompt, messages, tools).await?;
+            for call in response.tool_calls() {
+                on_tool_call(&call);
+            }
+            Ok(response)
+        }
+    }
+
+    /// like `complete_streaming`, and also calls `on_text` with each piece of
+    /// text as it arrives. the pieces add up to the response's `text()`.
+    /// providers that can't stream text report it all once the response is in.
+    #[cfg(feature = "streaming")]
+    fn complete_streaming_text(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> impl Future<Output = Result<ProviderResponse, Error>> + Send {
+        async move {
+            let response = self
+                .complete_streaming(system_prompt, messages, tools, on_tool_call)
+                .await?;
+            let text = response.text();
+            if !text.is_empty() {
+                on_text(&text);
+            }
+            Ok(response)
+        }
+    }
+
+    /// like `complete_streaming_text`, with `tool_choice` forcing tool use
+    /// like in `complete_with_tool_choice`. providers that can't stream it
+    /// report everything once the response is in.
+    #[cfg(feature = "streaming")]
+    fn complete_streaming_with_tool_choice(
+        &self,
+        system_prompt: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        tool_choice: &ToolChoice,
+        on_tool_call: &(dyn Fn(&ToolCall) + Send + Sync),
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> impl Future<Output = Result<ProviderResponse, Error>> + Send {
+        async move {
+            let response = self
+                .complete_with_tool_choice(system_prompt, messages, tools, tool_choice)
+                .await?;
+            let text = response.text();
+            if !text.is_empty() {
+                on_text(&text);
+            }
+            for call in response.tool_calls() {
+                on_tool_call(&call);
+            }
+            Ok(response)
+        }
+    }
 }
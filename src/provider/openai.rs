@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::provider::ToolCall;
+use crate::tool::ToolDefinition;
+
+/// a tool as openai expects it: `{"type": "function", "function": {...}}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAiTool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: OpenAiFunction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAiFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+/// a tool call in an assistant message. `arguments` is a JSON-encoded string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAiToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: OpenAiFunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAiFunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+pub fn to_openai_tools(tools: &[ToolDefinition]) -> Vec<OpenAiTool> {
+    tools
+        .iter()
+        .map(|tool| OpenAiTool {
+            kind: "function".into(),
+            function: OpenAiFunction {
+                name: tool.name.into(),
+                description: tool.description.into(),
+                parameters: tool.input_schema.clone(),
+            },
+        })
+        .collect()
+}
+
+pub fn from_openai_tool_calls(calls: Vec<OpenAiToolCall>) -> Result<Vec<ToolCall>, Error> {
+    calls.into_iter().map(from_openai_tool_call).collect()
+}
+
+fn from_openai_tool_call(call: OpenAiToolCall) -> Result<ToolCall, Error> {
+    // some servers send an empty string for tools without arguments
+    let input = if call.function.arguments.trim().is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_str(&call.function.arguments).map_err(|e| {
+            Error::Provider(format!(
+                "invalid arguments for tool call {}: {e}",
+                call.function.name
+            ))
+        })?
+    };
+
+    Ok(ToolCall {
+        id: call.id,
+        name: call.function.name,
+        input,
+    })
+}
+
+/// the reverse of `from_openai_tool_call`, for replaying history to openai
+pub fn to_openai_tool_call(call: &ToolCall) -> OpenAiToolCall {
+    OpenAiToolCall {
+        id: call.id.clone(),
+        kind: "function".into(),
+        function: OpenAiFunctionCall {
+            name: call.name.clone(),
+            arguments: call.input.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool::{EXEC_TOOL_NAME, tool_definitions};
+    use serde_json::json;
+
+    #[test]
+    fn test_tool_definitions_to_openai_format() {
+        let tools = to_openai_tools(&tool_definitions());
+        let json = serde_json::to_value(&tools).unwrap();
+
+        let exec = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|tool| tool["function"]["name"] == EXEC_TOOL_NAME)
+            .unwrap();
+        assert_eq!(exec["type"], "function");
+        assert_eq!(exec["function"]["parameters"]["type"], "object");
+        assert_eq!(
+            exec["function"]["parameters"]["required"],
+            json!(["command"])
+        );
+        assert!(exec.get("input_schema").is_none());
+    }
+
+    #[test]
+    fn test_openai_tool_calls_to_canonical() {
+        let json = r#"[{
+            "id": "call_abc",
+            "type": "function",
+            "function": {"name": "exec", "arguments": "{\"command\":\"ls -la\"}"}
+        }, {
+            "id": "call_def",
+            "type": "function",
+            "function": {"name": "whoami", "arguments": ""}
+        }]"#;
+        let calls: Vec<OpenAiToolCall> = serde_json::from_str(json).unwrap();
+
+        let calls = from_openai_tool_calls(calls).unwrap();
+
+        assert_eq!(calls[0].id, "call_abc");
+        assert_eq!(calls[0].name, "exec");
+        assert_eq!(calls[0].input, json!({"command": "ls -la"}));
+        assert_eq!(calls[1].input, json!({}));
+    }
+
+    #[test]
+    fn test_openai_tool_call_with_invalid_arguments() {
+        let call = OpenAiToolCall {
+            id: "call_abc".into(),
+            kind: "function".into(),
+            function: OpenAiFunctionCall {
+                name: "exec".into(),
+                arguments: "{\"command\":".into(),
+            },
+        };
+
+        let result = from_openai_tool_calls(vec![call]);
+
+        assert!(matches!(result, Err(Error::Provider(msg)) if msg.contains("exec")));
+    }
+
+    #[test]
+    fn test_tool_call_round_trip() {
+        let call = ToolCall {
+            id: "call_abc".into(),
+            name: "remember_fact".into(),
+            input: json!({"category": "user", "key": "name", "value": "alex"}),
+        };
+
+        let openai = to_openai_tool_call(&call);
+        assert_eq!(openai.kind, "function");
+        let back = from_openai_tool_calls(vec![openai]).unwrap();
+
+        assert_eq!(back[0].id, call.id);
+        assert_eq!(back[0].name, call.name);
+        assert_eq!(back[0].input, call.input);
+    }
+}
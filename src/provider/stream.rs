@@ -0,0 +1,423 @@
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::message::MessageContent;
+use crate::provider::{ProviderResponse, StopReason, ToolCall};
+
+/// splits a server-sent events byte stream into event data payloads.
+/// chunks may end anywhere, including mid-line.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    data: String,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// feeds a chunk and returns the data of every event it completed
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(std::mem::take(&mut self.data));
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+            }
+            // event names, ids, and comments aren't needed, the data carries its type
+        }
+
+        events
+    }
+}
+
+/// anthropic streaming events. see https://docs.anthropic.com/en/api/messages-streaming
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    ContentBlockStart {
+        index: usize,
+        content_block: StartBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDeltaBody,
+    },
+    Error {
+        error: StreamError,
+    },
+    /// message_start, message_stop, ping
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StartBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+    },
+    /// a block the API handles itself, like `server_tool_use`
+    #[serde(untagged)]
+    Server(serde_json::Value),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageDeltaBody {
+    pub stop_reason: Option<StopReason>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamError {
+    pub message: String,
+}
+
+#[derive(Debug)]
+enum PartialBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        json: String,
+    },
+    /// a tool_use block whose input is complete
+    Call(ToolCall),
+    /// a server block, with the input it streams, if any
+    Server {
+        block: serde_json::Value,
+        json: String,
+    },
+    Ignored,
+}
+
+/// assembles streamed events into a response.
+/// a tool call is handed out as soon as its block stops, so it can be
+/// shown for approval while the rest of the response is still streaming.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    blocks: Vec<PartialBlock>,
+    stop_reason: Option<StopReason>,
+}
+
+impl StreamAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// applies an event. returns the tool call it completed, if any.
+    pub fn push(&mut self, event: StreamEvent) -> Result<Option<ToolCall>, Error> {
+        match event {
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                let block = match content_block {
+                    StartBlock::Text { text } => PartialBlock::Text(text),
+                    StartBlock::ToolUse { id, name } => PartialBlock::ToolUse {
+                        id,
+                        name,
+                        json: String::new(),
+                    },
+                    StartBlock::Server(block) => PartialBlock::Server {
+                        block,
+                        json: String::new(),
+                    },
+                };
+                if index >= self.blocks.len() {
+                    self.blocks.resize_with(index + 1, || PartialBlock::Ignored);
+                }
+                self.blocks[index] = block;
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                match (self.blocks.get_mut(index), delta) {
+                    (Some(PartialBlock::Text(text)), BlockDelta::TextDelta { text: more }) => {
+                        text.push_str(&more);
+                    }
+                    (
+                        Some(
+                            PartialBlock::ToolUse { json, .. } | PartialBlock::Server { json, .. },
+                        ),
+                        BlockDelta::InputJsonDelta { partial_json },
+                    ) => {
+                        json.push_str(&partial_json);
+                    }
+                    _ => {}
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                if let Some(PartialBlock::Server { block, json }) = self.blocks.get_mut(index)
+                    && !json.trim().is_empty()
+                {
+                    block["input"] = serde_json::from_str(json).map_err(|e| {
+                        Error::Provider(format!("invalid streamed server tool input: {e}"))
+                    })?;
+                    json.clear();
+                }
+                if let Some(PartialBlock::ToolUse { id, name, json }) = self.blocks.get(index) {
+                    // a tool without arguments streams no input at all
+                    let input = if json.trim().is_empty() {
+                        serde_json::json!({})
+                    } else {
+                        serde_json::from_str(json).map_err(|e| {
+                            Error::Provider(format!("invalid streamed tool input: {e}"))
+                        })?
+                    };
+                    let call = ToolCall {
+                        id: id.clone(),
+                        name: name.clone(),
+                        input,
+                    };
+                    self.blocks[index] = PartialBlock::Call(call.clone());
+                    return Ok(Some(call));
+                }
+            }
+            StreamEvent::MessageDelta { delta } => {
+                if delta.stop_reason.is_some() {
+                    self.stop_reason = delta.stop_reason;
+                }
+            }
+            StreamEvent::Error { error } => return Err(Error::Provider(error.message)),
+            StreamEvent::Other => {}
+        }
+
+        Ok(None)
+    }
+
+    /// the text `event` adds to the response's `text()`, including the
+    /// newline that joins a new text block to earlier ones. call it before
+    /// pushing the event.
+    pub fn text_delta(&self, event: &StreamEvent) -> Option<String> {
+        match event {
+            StreamEvent::ContentBlockStart {
+                content_block: StartBlock::Text { text },
+                ..
+            } => {
+                let joined = self
+                    .blocks
+                    .iter()
+                    .any(|block| matches!(block, PartialBlock::Text(_)));
+                match (joined, text.is_empty()) {
+                    (true, _) => Some(format!("\n{text}")),
+                    (false, true) => None,
+                    (false, false) => Some(text.clone()),
+                }
+            }
+            StreamEvent::ContentBlockDelta {
+                index,
+                delta: BlockDelta::TextDelta { text },
+            } if matches!(self.blocks.get(*index), Some(PartialBlock::Text(_))) => {
+                Some(text.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn finish(self) -> Result<ProviderResponse, Error> {
+        let stop_reason = self
+            .stop_reason
+            .ok_or_else(|| Error::Provider("stream ended without a stop reason".into()))?;
+
+        let content = self
+            .blocks
+            .into_iter()
+            .filter_map(|block| match block {
+                PartialBlock::Text(text) => Some(MessageContent::text(text)),
+                PartialBlock::Call(call) => {
+                    Some(MessageContent::tool_use(call.id, call.name, call.input))
+                }
+                PartialBlock::Server { block, .. } => Some(MessageContent::Server(block)),
+                // a tool_use block that never stopped has incomplete input
+                PartialBlock::ToolUse { .. } | PartialBlock::Ignored => None,
+            })
+            .collect();
+
+        Ok(ProviderResponse {
+            content,
+            stop_reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(data: &str) -> StreamEvent {
+        serde_json::from_str(data).unwrap()
+    }
+
+    #[test]
+    fn test_sse_parser_handles_split_chunks() {
+        let mut parser = SseParser::new();
+
+        let mut events = parser.push(b"event: ping\ndata: {\"type\":");
+        assert!(events.is_empty());
+
+        events.extend(parser.push(b"\"ping\"}\r\n\r\nevent: message_stop\n"));
+        events.extend(parser.push(b"data: {\"type\":\"message_stop\"}\n\n"));
+
+        assert_eq!(
+            events,
+            vec![r#"{"type":"ping"}"#, r#"{"type":"message_stop"}"#]
+        );
+    }
+
+    #[test]
+    fn test_accumulator_emits_tool_call_before_stream_ends() {
+        let mut acc = StreamAccumulator::new();
+
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_1"}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"let me check"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"exec","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"comm"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"and\": \"ls -la\"}"}}"#,
+        ];
+        for data in events {
+            assert!(acc.push(event(data)).unwrap().is_none());
+        }
+
+        // the call is complete once its block stops, before message_delta arrives
+        let call = acc
+            .push(event(r#"{"type":"content_block_stop","index":1}"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.id, "toolu_1");
+        assert_eq!(call.name, "exec");
+        assert_eq!(call.input["command"], "ls -la");
+
+        acc.push(event(
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"}}"#,
+        ))
+        .unwrap();
+        acc.push(event(r#"{"type":"message_stop"}"#)).unwrap();
+
+        let response = acc.finish().unwrap();
+        assert_eq!(response.text(), "let me check");
+        assert_eq!(response.stop_reason, StopReason::ToolUse);
+        assert_eq!(response.tool_calls().len(), 1);
+        assert!(matches!(
+            response.content[1],
+            MessageContent::ToolUse { .. }
+        ));
+    }
+
+    #[test]
+    fn test_text_deltas_add_up_to_the_text() {
+        let mut acc = StreamAccumulator::new();
+        let mut streamed = String::new();
+
+        let events = [
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"one, "}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"two"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"whoami","input":{}}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"content_block_start","index":2,"content_block":{"type":"text","text":"three"}}"#,
+            r#"{"type":"content_block_stop","index":2}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"}}"#,
+        ];
+        for data in events {
+            let event = event(data);
+            if let Some(text) = acc.text_delta(&event) {
+                streamed.push_str(&text);
+            }
+            acc.push(event).unwrap();
+        }
+
+        assert_eq!(streamed, "one, two\nthree");
+        assert_eq!(acc.finish().unwrap().text(), streamed);
+    }
+
+    #[test]
+    fn test_accumulator_tool_without_input() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(event(
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"toolu_1","name":"whoami","input":{}}}"#,
+        ))
+        .unwrap();
+
+        let call = acc
+            .push(event(r#"{"type":"content_block_stop","index":0}"#))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(call.input, serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_accumulator_keeps_server_blocks() {
+        let mut acc = StreamAccumulator::new();
+        let events = [
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"server_tool_use","id":"srvtoolu_1","name":"web_search","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"{\"query\": "}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"\"rust\"}"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"web_search_tool_result","tool_use_id":"srvtoolu_1","content":[{"type":"web_search_result","url":"https://www.rust-lang.org","title":"Rust"}]}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"pause_turn"}}"#,
+        ];
+        for data in events {
+            assert!(acc.push(event(data)).unwrap().is_none());
+        }
+
+        let response = acc.finish().unwrap();
+        assert!(response.tool_calls().is_empty());
+        assert!(matches!(
+            &response.content[..],
+            [MessageContent::Server(search), MessageContent::Server(results)]
+                if search["input"] == serde_json::json!({"query": "rust"})
+                    && results["type"] == "web_search_tool_result"
+                    && results["content"][0]["title"] == "Rust"
+        ));
+    }
+
+    #[test]
+    fn test_accumulator_surfaces_stream_error() {
+        let mut acc = StreamAccumulator::new();
+        let result = acc.push(event(
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+        ));
+
+        assert!(matches!(result, Err(Error::Provider(msg)) if msg == "Overloaded"));
+    }
+}
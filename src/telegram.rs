@@ -1,53 +1,102 @@
+use std::future::Future;
+use std::time::Duration;
+
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tracing::warn;
 
+use crate::config::{self, TelegramParseMode};
 use crate::error::Error;
 
 const API_BASE: &str = "https://api.telegram.org/bot";
 
-pub struct TelegramBot {
+/// the bot API calls the bot makes. `HttpTransport` talks to telegram,
+/// tests swap in a fake to drive the bot without a network.
+pub trait TelegramTransport: Send + Sync {
+    fn get_updates(
+        &self,
+        offset: Option<i64>,
+    ) -> impl Future<Output = Result<Vec<Update>, Error>> + Send;
+
+    /// returns the ID of the sent message
+    fn send_message(
+        &self,
+        chat_id: i64,
+        text: &str,
+        parse_mode: Option<&str>,
+    ) -> impl Future<Output = Result<i64, Error>> + Send;
+
+    /// returns the ID of the sent message
+    fn send_message_with_keyboard(
+        &self,
+        chat_id: i64,
+        text: &str,
+        reply_markup: &InlineKeyboardMarkup,
+    ) -> impl Future<Output = Result<i64, Error>> + Send;
+
+    fn send_chat_action(
+        &self,
+        chat_id: i64,
+        action: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn answer_callback_query(
+        &self,
+        callback_query_id: &str,
+        text: Option<&str>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn edit_message_text(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        text: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// the real bot API, over HTTPS
+pub struct HttpTransport {
     client: Client,
     token: String,
 }
 
-impl TelegramBot {
+impl HttpTransport {
     pub fn new(token: String) -> Self {
         Self {
-            client: Client::new(),
+            client: crate::http::client(),
             token,
         }
     }
 
-    pub fn from_env() -> Result<Self, Error> {
-        let token =
-            std::env::var("TELOXIDE_TOKEN").map_err(|_| Error::MissingEnvVar("TELOXIDE_TOKEN"))?;
-        Ok(Self::new(token))
-    }
-
     fn api_url(&self, method: &str) -> String {
         format!("{}{}/{}", API_BASE, self.token, method)
     }
 
-    #[tracing::instrument(skip(self))]
-    pub async fn get_updates(&self, offset: Option<i64>) -> Result<Vec<Update>, Error> {
-        let params = GetUpdatesParams {
-            timeout: 30,
-            offset,
-            allowed_updates: Some(vec!["message", "callback_query"]),
-        };
-
-        let response: ApiResponse<Vec<Update>> = self
+    /// calls a bot API method. a response that isn't ok becomes
+    /// `Error::Telegram` with telegram's description.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &impl Serialize,
+    ) -> Result<Option<T>, Error> {
+        let response = self
             .client
-            .post(self.api_url("getUpdates"))
-            .json(&params)
+            .post(self.api_url(method))
+            .json(params)
             .send()
-            .await?
-            .json()
             .await?;
 
+        // the body of a 5xx may not even be JSON, e.g. from a proxy
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(Error::TelegramServer(status.to_string()));
+        }
+
+        let response: ApiResponse<T> = response.json().await?;
+
         if response.ok {
-            Ok(response.result.unwrap_or_default())
+            Ok(response.result)
         } else {
             Err(Error::Telegram(
                 response
@@ -56,69 +105,40 @@ impl TelegramBot {
             ))
         }
     }
+}
 
-    #[tracing::instrument(skip(self, text), fields(chat_id))]
-    pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), Error> {
-        // try HTML parse mode first
-        let params = SendMessageParams {
-            chat_id,
-            text,
-            parse_mode: Some("HTML"),
-            reply_markup: None,
+impl TelegramTransport for HttpTransport {
+    async fn get_updates(&self, offset: Option<i64>) -> Result<Vec<Update>, Error> {
+        let params = GetUpdatesParams {
+            timeout: 30,
+            offset,
+            allowed_updates: Some(vec!["message", "edited_message", "callback_query"]),
         };
+        let updates: Option<Vec<Update>> = self.call("getUpdates", &params).await?;
+        Ok(updates.unwrap_or_default())
+    }
 
-        let response: ApiResponse<serde_json::Value> = self
-            .client
-            .post(self.api_url("sendMessage"))
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        if response.ok {
-            return Ok(());
-        }
-
-        // if HTML parsing failed, resend as plain text
-        warn!(
-            error = response.description.as_deref().unwrap_or("unknown error"),
-            "telegram HTML parse failed, falling back to plain text"
-        );
-
-        let fallback = SendMessageParams {
+    async fn send_message(
+        &self,
+        chat_id: i64,
+        text: &str,
+        parse_mode: Option<&str>,
+    ) -> Result<i64, Error> {
+        let params = SendMessageParams {
             chat_id,
             text,
-            parse_mode: None,
+            parse_mode,
             reply_markup: None,
         };
-
-        let response: ApiResponse<serde_json::Value> = self
-            .client
-            .post(self.api_url("sendMessage"))
-            .json(&fallback)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        if response.ok {
-            Ok(())
-        } else {
-            Err(Error::Telegram(
-                response
-                    .description
-                    .unwrap_or_else(|| "unknown error".into()),
-            ))
-        }
+        let sent: Option<SentMessage> = self.call("sendMessage", &params).await?;
+        Ok(sent.map(|m| m.message_id).unwrap_or_default())
     }
 
-    #[tracing::instrument(skip(self, text, reply_markup), fields(chat_id))]
-    pub async fn send_message_with_keyboard(
+    async fn send_message_with_keyboard(
         &self,
         chat_id: i64,
         text: &str,
-        reply_markup: InlineKeyboardMarkup,
+        reply_markup: &InlineKeyboardMarkup,
     ) -> Result<i64, Error> {
         let params = SendMessageParams {
             chat_id,
@@ -126,29 +146,17 @@ impl TelegramBot {
             parse_mode: None,
             reply_markup: Some(reply_markup),
         };
+        let sent: Option<SentMessage> = self.call("sendMessage", &params).await?;
+        Ok(sent.map(|m| m.message_id).unwrap_or_default())
+    }
 
-        let response: ApiResponse<SentMessage> = self
-            .client
-            .post(self.api_url("sendMessage"))
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        if response.ok {
-            Ok(response.result.map(|m| m.message_id).unwrap_or_default())
-        } else {
-            Err(Error::Telegram(
-                response
-                    .description
-                    .unwrap_or_else(|| "unknown error".into()),
-            ))
-        }
+    async fn send_chat_action(&self, chat_id: i64, action: &str) -> Result<(), Error> {
+        let params = SendChatActionParams { chat_id, action };
+        self.call::<bool>("sendChatAction", &params).await?;
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self))]
-    pub async fn answer_callback_query(
+    async fn answer_callback_query(
         &self,
         callback_query_id: &str,
         text: Option<&str>,
@@ -157,29 +165,11 @@ impl TelegramBot {
             callback_query_id,
             text,
         };
-
-        let response: ApiResponse<bool> = self
-            .client
-            .post(self.api_url("answerCallbackQuery"))
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        if response.ok {
-            Ok(())
-        } else {
-            Err(Error::Telegram(
-                response
-                    .description
-                    .unwrap_or_else(|| "unknown error".into()),
-            ))
-        }
+        self.call::<bool>("answerCallbackQuery", &params).await?;
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self, text), fields(chat_id, message_id))]
-    pub async fn edit_message_text(
+    async fn edit_message_text(
         &self,
         chat_id: i64,
         message_id: i64,
@@ -190,28 +180,174 @@ impl TelegramBot {
             message_id,
             text,
         };
-
-        let response: ApiResponse<serde_json::Value> = self
-            .client
-            .post(self.api_url("editMessageText"))
-            .json(&params)
-            .send()
-            .await?
-            .json()
+        self.call::<serde_json::Value>("editMessageText", &params)
             .await?;
+        Ok(())
+    }
+}
 
-        if response.ok {
-            Ok(())
-        } else {
-            Err(Error::Telegram(
-                response
-                    .description
-                    .unwrap_or_else(|| "unknown error".into()),
-            ))
+/// how often a failed send is tried again, for failures that may be
+/// temporary: connection errors, timeouts, and 5xx responses.
+/// a timed out request may still have been delivered, so a retry after one
+/// can duplicate a message. errors telegram reports on a request it did
+/// handle are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendRetry {
+    /// attempts in total, including the first
+    pub attempts: u32,
+    /// delay before the first retry, doubling for each one after
+    pub backoff: Duration,
+}
+
+impl Default for SendRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
         }
     }
 }
 
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::TelegramServer(_) => true,
+        Error::Http(e) => e.is_connect() || e.is_timeout(),
+        _ => false,
+    }
+}
+
+pub struct TelegramBot<T = HttpTransport> {
+    transport: T,
+    send_retry: SendRetry,
+    parse_mode: TelegramParseMode,
+}
+
+impl TelegramBot {
+    pub fn new(token: String) -> Self {
+        Self::with_transport(HttpTransport::new(token))
+    }
+
+    pub fn from_env() -> Result<Self, Error> {
+        let token =
+            std::env::var("TELOXIDE_TOKEN").map_err(|_| Error::MissingEnvVar("TELOXIDE_TOKEN"))?;
+        Ok(Self::new(token).with_parse_mode(config::telegram_parse_mode()))
+    }
+}
+
+impl<T: TelegramTransport> TelegramBot<T> {
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            transport,
+            send_retry: SendRetry::default(),
+            parse_mode: TelegramParseMode::default(),
+        }
+    }
+
+    /// how `send_message` formats text
+    pub fn with_parse_mode(mut self, parse_mode: TelegramParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_send_retry(mut self, send_retry: SendRetry) -> Self {
+        self.send_retry = send_retry;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn get_updates(&self, offset: Option<i64>) -> Result<Vec<Update>, Error> {
+        self.transport.get_updates(offset).await
+    }
+
+    #[tracing::instrument(skip(self, text), fields(chat_id))]
+    /// returns the ID of the sent message
+    pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<i64, Error> {
+        let Some(parse_mode) = self.parse_mode.api_value() else {
+            return self.send_with_retry(chat_id, text, None).await;
+        };
+        match self.send_with_retry(chat_id, text, Some(parse_mode)).await {
+            Err(Error::Telegram(description)) => {
+                // if parsing failed, resend as plain text
+                warn!(
+                    error = description,
+                    parse_mode, "telegram parse failed, falling back to plain text"
+                );
+                self.send_with_retry(chat_id, text, None).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_with_retry(
+        &self,
+        chat_id: i64,
+        text: &str,
+        parse_mode: Option<&str>,
+    ) -> Result<i64, Error> {
+        let mut backoff = self.send_retry.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.transport.send_message(chat_id, text, parse_mode).await {
+                Err(e) if is_transient(&e) && attempt < self.send_retry.attempts => {
+                    warn!(%e, attempt, "telegram send failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, text, reply_markup), fields(chat_id))]
+    pub async fn send_message_with_keyboard(
+        &self,
+        chat_id: i64,
+        text: &str,
+        reply_markup: InlineKeyboardMarkup,
+    ) -> Result<i64, Error> {
+        self.transport
+            .send_message_with_keyboard(chat_id, text, &reply_markup)
+            .await
+    }
+
+    /// shows "<bot name> is typing…" in the chat for a few seconds, or until
+    /// the next message is sent
+    #[tracing::instrument(skip(self))]
+    pub async fn send_typing(&self, chat_id: i64) -> Result<(), Error> {
+        self.transport.send_chat_action(chat_id, "typing").await
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn answer_callback_query(
+        &self,
+        callback_query_id: &str,
+        text: Option<&str>,
+    ) -> Result<(), Error> {
+        self.transport
+            .answer_callback_query(callback_query_id, text)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, text), fields(chat_id, message_id))]
+    pub async fn edit_message_text(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        text: &str,
+    ) -> Result<(), Error> {
+        self.transport
+            .edit_message_text(chat_id, message_id, text)
+            .await
+    }
+}
+
 // --- API request/response types ---
 
 #[derive(Debug, Deserialize)]
@@ -237,7 +373,13 @@ struct SendMessageParams<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     parse_mode: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    reply_markup: Option<InlineKeyboardMarkup>,
+    reply_markup: Option<&'a InlineKeyboardMarkup>,
+}
+
+#[derive(Debug, Serialize)]
+struct SendChatActionParams<'a> {
+    chat_id: i64,
+    action: &'a str,
 }
 
 #[derive(Debug, Serialize)]
@@ -276,6 +418,7 @@ pub struct SentMessage {
 pub struct Update {
     pub update_id: i64,
     pub message: Option<Message>,
+    pub edited_message: Option<Message>,
     pub callback_query: Option<CallbackQuery>,
 }
 
@@ -285,7 +428,53 @@ pub struct Message {
     pub message_id: i64,
     pub from: Option<User>,
     pub chat: Chat,
+    /// when the message was sent, in unix seconds
+    pub date: i64,
+    /// when the message was last edited, in unix seconds
+    pub edit_date: Option<i64>,
     pub text: Option<String>,
+    #[serde(default)]
+    pub entities: Vec<MessageEntity>,
+}
+
+impl Message {
+    /// when the message got its current text: the last edit, or else when
+    /// it was sent
+    pub fn sent_at(&self) -> i64 {
+        self.edit_date.unwrap_or(self.date)
+    }
+
+    /// links in the message as the user wrote them: the text of `url`
+    /// entities and the targets of `text_link` ones
+    pub fn links(&self) -> Vec<String> {
+        let Some(text) = &self.text else {
+            return Vec::new();
+        };
+        // entity offsets and lengths count UTF-16 code units
+        let utf16: Vec<u16> = text.encode_utf16().collect();
+
+        self.entities
+            .iter()
+            .filter_map(|entity| match entity.kind.as_str() {
+                "url" => utf16
+                    .get(entity.offset..entity.offset + entity.length)
+                    .map(String::from_utf16_lossy),
+                "text_link" => entity.url.clone(),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// a formatted span of a message, like a url, code, or mention
+#[derive(Debug, Deserialize)]
+pub struct MessageEntity {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub offset: usize,
+    pub length: usize,
+    /// the target, for `text_link` entities
+    pub url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -306,3 +495,258 @@ pub struct CallbackQuery {
     pub message: Option<Message>,
     pub data: Option<String>,
 }
+
+/// a transport that records what the bot sends and serves canned updates
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// a message the bot sent, or an edit it made
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Sent {
+        pub chat_id: i64,
+        pub message_id: i64,
+        pub text: String,
+        pub has_keyboard: bool,
+    }
+
+    #[derive(Default)]
+    pub struct MockTransport {
+        updates: Mutex<VecDeque<Vec<Update>>>,
+        send_failures: Mutex<VecDeque<Error>>,
+        pub sent: Mutex<Vec<Sent>>,
+        pub edits: Mutex<Vec<Sent>>,
+        /// the parse mode of every send_message attempt, failed ones too
+        pub parse_modes: Mutex<Vec<Option<String>>>,
+        pub chat_actions: Mutex<Vec<(i64, String)>>,
+        pub answered_callbacks: Mutex<Vec<String>>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// queues a batch for a future get_updates call. takes the updates
+        /// as JSON, the way telegram sends them.
+        pub fn push_updates(&self, updates: serde_json::Value) {
+            let updates = serde_json::from_value(updates).expect("valid updates");
+            self.updates.lock().unwrap().push_back(updates);
+        }
+
+        /// makes the next send_message call fail with `error`. failures
+        /// queue up, one per call.
+        pub fn fail_next_send(&self, error: Error) {
+            self.send_failures.lock().unwrap().push_back(error);
+        }
+
+        pub fn sent_texts(&self) -> Vec<String> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter().map(|m| m.text.clone()).collect()
+        }
+
+        fn record(&self, chat_id: i64, text: &str, has_keyboard: bool) -> i64 {
+            let mut sent = self.sent.lock().unwrap();
+            let message_id = sent.len() as i64 + 1;
+            sent.push(Sent {
+                chat_id,
+                message_id,
+                text: text.to_string(),
+                has_keyboard,
+            });
+            message_id
+        }
+    }
+
+    impl TelegramTransport for MockTransport {
+        async fn get_updates(&self, _offset: Option<i64>) -> Result<Vec<Update>, Error> {
+            Ok(self.updates.lock().unwrap().pop_front().unwrap_or_default())
+        }
+
+        async fn send_message(
+            &self,
+            chat_id: i64,
+            text: &str,
+            parse_mode: Option<&str>,
+        ) -> Result<i64, Error> {
+            self.parse_modes
+                .lock()
+                .unwrap()
+                .push(parse_mode.map(str::to_string));
+            if let Some(error) = self.send_failures.lock().unwrap().pop_front() {
+                return Err(error);
+            }
+            Ok(self.record(chat_id, text, false))
+        }
+
+        async fn send_message_with_keyboard(
+            &self,
+            chat_id: i64,
+            text: &str,
+            _reply_markup: &InlineKeyboardMarkup,
+        ) -> Result<i64, Error> {
+            Ok(self.record(chat_id, text, true))
+        }
+
+        async fn send_chat_action(&self, chat_id: i64, action: &str) -> Result<(), Error> {
+            let mut actions = self.chat_actions.lock().unwrap();
+            actions.push((chat_id, action.to_string()));
+            Ok(())
+        }
+
+        async fn answer_callback_query(
+            &self,
+            callback_query_id: &str,
+            _text: Option<&str>,
+        ) -> Result<(), Error> {
+            let mut answered = self.answered_callbacks.lock().unwrap();
+            answered.push(callback_query_id.to_string());
+            Ok(())
+        }
+
+        async fn edit_message_text(
+            &self,
+            chat_id: i64,
+            message_id: i64,
+            text: &str,
+        ) -> Result<(), Error> {
+            self.edits.lock().unwrap().push(Sent {
+                chat_id,
+                message_id,
+                text: text.to_string(),
+                has_keyboard: false,
+            });
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_edited_message_update() {
+        let json = r#"{
+            "update_id": 7,
+            "edited_message": {
+                "message_id": 42,
+                "from": {"id": 1001, "is_bot": false, "first_name": "alex"},
+                "chat": {"id": 1001, "type": "private"},
+                "date": 1700000000,
+                "edit_date": 1700000030,
+                "text": "what's the weather in amsterdam?"
+            }
+        }"#;
+
+        let update: Update = serde_json::from_str(json).unwrap();
+
+        assert!(update.message.is_none());
+        let edited = update.edited_message.unwrap();
+        assert_eq!(edited.message_id, 42);
+        assert_eq!(edited.chat.id, 1001);
+        assert_eq!(
+            edited.text.as_deref(),
+            Some("what's the weather in amsterdam?")
+        );
+        assert!(edited.entities.is_empty());
+        // an old message edited just now is a new request
+        assert_eq!(edited.sent_at(), 1700000030);
+    }
+
+    #[test]
+    fn test_parse_message_with_url_entity() {
+        let json = r#"{
+            "message_id": 43,
+            "from": {"id": 1001, "is_bot": false, "first_name": "alex"},
+            "chat": {"id": 1001, "type": "private"},
+            "date": 1700000000,
+            "text": "👋 read https://example.com/a_b?x=1&y=2 and this",
+            "entities": [
+                {"type": "url", "offset": 8, "length": 31},
+                {"type": "text_link", "offset": 44, "length": 4, "url": "https://example.org/"},
+                {"type": "bold", "offset": 3, "length": 4}
+            ]
+        }"#;
+
+        let message: Message = serde_json::from_str(json).unwrap();
+
+        assert_eq!(message.entities.len(), 3);
+        assert_eq!(message.entities[0].kind, "url");
+        // the emoji is two UTF-16 code units, which the offsets account for
+        assert_eq!(
+            message.links(),
+            vec!["https://example.com/a_b?x=1&y=2", "https://example.org/"]
+        );
+    }
+
+    fn no_backoff() -> SendRetry {
+        SendRetry {
+            attempts: 3,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message_retries_transient_failure() {
+        let transport = mock::MockTransport::new();
+        transport.fail_next_send(Error::TelegramServer("502 Bad Gateway".into()));
+        let bot = TelegramBot::with_transport(transport).with_send_retry(no_backoff());
+
+        let message_id = bot.send_message(1001, "hello").await.unwrap();
+
+        assert_eq!(message_id, 1);
+        assert_eq!(bot.transport().sent_texts(), vec!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_gives_up_after_attempts() {
+        let transport = mock::MockTransport::new();
+        for _ in 0..3 {
+            transport.fail_next_send(Error::TelegramServer("503 Service Unavailable".into()));
+        }
+        let bot = TelegramBot::with_transport(transport).with_send_retry(no_backoff());
+
+        let result = bot.send_message(1001, "hello").await;
+
+        assert!(matches!(result, Err(Error::TelegramServer(_))));
+        assert!(bot.transport().sent_texts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_message_falls_back_to_plain_text() {
+        let transport = mock::MockTransport::new();
+        transport.fail_next_send(Error::Telegram("can't parse entities".into()));
+        let bot = TelegramBot::with_transport(transport).with_send_retry(no_backoff());
+
+        bot.send_message(1001, "a <b").await.unwrap();
+
+        // a rejected request isn't retried as is, only resent without HTML
+        assert_eq!(bot.transport().sent_texts(), vec!["a <b"]);
+        assert_eq!(
+            *bot.transport().parse_modes.lock().unwrap(),
+            vec![Some("HTML".to_string()), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message_uses_configured_parse_mode() {
+        let bot = TelegramBot::with_transport(mock::MockTransport::new())
+            .with_parse_mode(TelegramParseMode::Markdown);
+        bot.send_message(1001, "*hi*").await.unwrap();
+        assert_eq!(
+            *bot.transport().parse_modes.lock().unwrap(),
+            vec![Some("Markdown".to_string())]
+        );
+
+        // plain text is sent once, with nothing to fall back from
+        let transport = mock::MockTransport::new();
+        transport.fail_next_send(Error::Telegram("bad request".into()));
+        let bot = TelegramBot::with_transport(transport).with_parse_mode(TelegramParseMode::Plain);
+        assert!(bot.send_message(1001, "a <b").await.is_err());
+        assert_eq!(*bot.transport().parse_modes.lock().unwrap(), vec![None]);
+    }
+}
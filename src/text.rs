@@ -0,0 +1,193 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// the shortest run of identical lines `collapse_repeats` folds into one
+const MIN_COLLAPSED_RUN: usize = 3;
+
+/// returns the longest prefix of `text` with at most `max_chars` chars that
+/// doesn't end inside a grapheme cluster
+pub fn safe_prefix(text: &str, max_chars: usize) -> &str {
+    let Some((limit, _)) = text.char_indices().nth(max_chars) else {
+        return text;
+    };
+
+    let cut = text
+        .grapheme_indices(true)
+        .map(|(i, _)| i)
+        .take_while(|&i| i <= limit)
+        .last()
+        .unwrap_or(0);
+
+    &text[..cut]
+}
+
+/// splits `text` into pieces of at most `max_chars` chars, preferring to cut
+/// after a newline, and never inside a grapheme cluster where avoidable
+pub fn split_chunks(text: &str, max_chars: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.chars().count() > max_chars {
+        let mut chunk = safe_prefix(rest, max_chars);
+        if chunk.is_empty() {
+            // a single cluster longer than a chunk has to be cut
+            let (cut, _) = rest
+                .char_indices()
+                .nth(max_chars.max(1))
+                .unwrap_or((rest.len(), ' '));
+            chunk = &rest[..cut];
+        }
+        // don't cut a line in two unless it takes up most of the chunk
+        if let Some(newline) = chunk.rfind('\n')
+            && newline >= chunk.len() / 2
+        {
+            chunk = &chunk[..=newline];
+        }
+        chunks.push(chunk);
+        rest = &rest[chunk.len()..];
+    }
+    if !rest.is_empty() || chunks.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+/// folds runs of identical lines, like a progress bar printed over and over,
+/// into the line once with a count. blank lines are left alone.
+pub fn collapse_repeats(text: &str) -> String {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut output = String::with_capacity(text.len());
+    let mut start = 0;
+    while start < lines.len() {
+        let line = without_line_ending(lines[start]);
+        let end = start
+            + lines[start..]
+                .iter()
+                .take_while(|next| without_line_ending(next) == line)
+                .count();
+        let run = end - start;
+        if run < MIN_COLLAPSED_RUN || line.trim().is_empty() {
+            output.extend(lines[start..end].iter().copied());
+        } else {
+            output.push_str(&format!("{line} (line repeated {run} times)"));
+            // keep the line ending the run had
+            output.push_str(&lines[end - 1][line.len()..]);
+        }
+        start = end;
+    }
+    output
+}
+
+fn without_line_ending(line: &str) -> &str {
+    line.trim_end_matches(['\n', '\r'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_prefix_short_text() {
+        assert_eq!(safe_prefix("hello", 10), "hello");
+        assert_eq!(safe_prefix("hello", 5), "hello");
+        assert_eq!(safe_prefix("hello", 3), "hel");
+    }
+
+    #[test]
+    fn test_safe_prefix_counts_chars_not_bytes() {
+        assert_eq!(safe_prefix("€€€€", 2), "€€");
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_combining_marks() {
+        // "e" + combining acute accent
+        let text = "cafe\u{301} ok";
+        assert_eq!(safe_prefix(text, 4), "caf");
+        assert_eq!(safe_prefix(text, 5), "cafe\u{301}");
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_emoji_modifiers() {
+        let text = "hi 👍🏽!";
+        assert_eq!(safe_prefix(text, 4), "hi ");
+        assert_eq!(safe_prefix(text, 5), "hi 👍🏽");
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_zwj_sequences() {
+        let family = "👨\u{200D}👩\u{200D}👧";
+        let text = format!("a{family}b");
+        for max in 1..6 {
+            assert_eq!(safe_prefix(&text, max), "a");
+        }
+        assert_eq!(safe_prefix(&text, 6), format!("a{family}"));
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_flags_whole() {
+        let text = "🇳🇱🇧🇪";
+        assert_eq!(safe_prefix(text, 1), "");
+        assert_eq!(safe_prefix(text, 2), "🇳🇱");
+        assert_eq!(safe_prefix(text, 3), "🇳🇱");
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_devanagari_clusters() {
+        // "क्षि": ka, virama, ssa and vowel sign i make one conjunct
+        let conjunct = "\u{915}\u{94D}\u{937}\u{93F}";
+        let text = format!("{conjunct}!");
+        for max in 0..4 {
+            assert_eq!(safe_prefix(&text, max), "");
+        }
+        assert_eq!(safe_prefix(&text, 4), conjunct);
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_arabic_harakat() {
+        // "بِسْم": each letter but the last carries a haraka
+        let text = "\u{628}\u{650}\u{633}\u{652}\u{645}";
+        assert_eq!(safe_prefix(text, 1), "");
+        assert_eq!(safe_prefix(text, 2), "\u{628}\u{650}");
+        assert_eq!(safe_prefix(text, 3), "\u{628}\u{650}");
+        assert_eq!(safe_prefix(text, 4), "\u{628}\u{650}\u{633}\u{652}");
+    }
+
+    #[test]
+    fn test_safe_prefix_keeps_crlf_together() {
+        let text = "a\r\nb";
+        assert_eq!(safe_prefix(text, 2), "a");
+        assert_eq!(safe_prefix(text, 3), "a\r\n");
+        assert_eq!(split_chunks(text, 2), vec!["a", "\r\n", "b"]);
+    }
+
+    #[test]
+    fn test_split_chunks_prefers_newlines() {
+        let text = format!("{}\n{}\n{}", "a".repeat(6), "b".repeat(6), "c".repeat(6));
+        assert_eq!(
+            split_chunks(&text, 10),
+            vec!["aaaaaa\n", "bbbbbb\n", "cccccc"]
+        );
+
+        let long_line = "x".repeat(25);
+        assert_eq!(
+            split_chunks(&long_line, 10),
+            vec!["x".repeat(10), "x".repeat(10), "x".repeat(5)]
+        );
+        assert_eq!(split_chunks("", 10), vec![""]);
+        assert_eq!(split_chunks("🇳🇱🇧🇪", 3), vec!["🇳🇱", "🇧🇪"]);
+    }
+
+    #[test]
+    fn test_collapse_repeats_folds_identical_lines() {
+        let text = format!("start\n{}done\n", "downloading... 42%\n".repeat(100));
+        assert_eq!(
+            collapse_repeats(&text),
+            "start\ndownloading... 42% (line repeated 100 times)\ndone\n"
+        );
+        assert_eq!(collapse_repeats("x\nx\nx"), "x (line repeated 3 times)");
+    }
+
+    #[test]
+    fn test_collapse_repeats_leaves_distinct_lines() {
+        let text = "one\ntwo\ntwo\nthree\n\n\n\nfour";
+        assert_eq!(collapse_repeats(text), text);
+    }
+}
@@ -0,0 +1,148 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Operation {
+    Base64Encode,
+    Base64Decode,
+    HexEncode,
+    HexDecode,
+    Sha256,
+    Md5,
+}
+
+/// runs an encoding or hashing operation on `input`. failures, like input
+/// that isn't valid base64, are described in the returned text.
+pub(super) fn encode(operation: Operation, input: &str) -> String {
+    match operation {
+        Operation::Base64Encode => STANDARD.encode(input),
+        Operation::Base64Decode => match decode_base64(input) {
+            Some(bytes) => decoded_text(bytes),
+            None => "not valid base64".to_string(),
+        },
+        Operation::HexEncode => to_hex(input.as_bytes()),
+        Operation::HexDecode => match from_hex(input) {
+            Some(bytes) => decoded_text(bytes),
+            None => "not valid hex".to_string(),
+        },
+        Operation::Sha256 => to_hex(&Sha256::digest(input)),
+        Operation::Md5 => to_hex(&Md5::digest(input)),
+    }
+}
+
+/// accepts standard and url-safe base64, with or without padding, since
+/// tokens (e.g. JWT parts) often come unpadded
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let cleaned: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    let trimmed = cleaned.trim_end_matches('=');
+    let padding = (4 - trimmed.len() % 4) % 4;
+    let padded = format!("{trimmed}{}", "=".repeat(padding));
+    STANDARD.decode(padded).ok()
+}
+
+/// decoded bytes as text, or as hex when they aren't utf-8
+fn decoded_text(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => format!("(not utf-8, shown as hex) {}", to_hex(e.as_bytes())),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(input: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = input
+        .trim()
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<_>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_round_trip() {
+        let text = "user:pässword";
+        let encoded = encode(Operation::Base64Encode, text);
+        assert_eq!(encoded, "dXNlcjpww6Rzc3dvcmQ=");
+        assert_eq!(encode(Operation::Base64Decode, &encoded), text);
+        // unpadded url-safe input, as in JWTs
+        assert_eq!(
+            encode(Operation::Base64Decode, "eyJhbGciOiJIUzI1NiJ9"),
+            r#"{"alg":"HS256"}"#
+        );
+        assert_eq!(
+            encode(Operation::Base64Decode, "not base64!"),
+            "not valid base64"
+        );
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(encode(Operation::HexEncode, "hi!"), "686921");
+        assert_eq!(encode(Operation::HexDecode, "0x686921"), "hi!");
+        assert_eq!(
+            encode(Operation::HexDecode, "ff00"),
+            "(not utf-8, shown as hex) ff00"
+        );
+        assert_eq!(encode(Operation::HexDecode, "abc"), "not valid hex");
+    }
+
+    #[test]
+    fn test_known_hashes() {
+        assert_eq!(
+            encode(Operation::Sha256, "abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            encode(Operation::Sha256, ""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // spans two blocks
+        assert_eq!(
+            encode(
+                Operation::Sha256,
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            encode(
+                Operation::Md5,
+                "The quick brown fox jumps over the lazy dog"
+            ),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(
+            encode(Operation::Md5, ""),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+
+use regex_automata::meta::Regex;
+
+use crate::text;
+
+/// how many matching files are listed before the search stops
+const MAX_MATCHED_FILES: usize = 50;
+/// how many matching lines are shown per file
+const MAX_LINES_PER_FILE: usize = 5;
+/// files bigger than this aren't searched for content
+const MAX_SEARCHED_FILE_BYTES: u64 = 1_000_000;
+/// how many files and directories are looked at before giving up, so a
+/// search from the top of a big tree can't run forever
+const MAX_VISITED_ENTRIES: usize = 20_000;
+/// matching lines are cut to this many chars
+const MAX_LINE_CHARS: usize = 200;
+/// how much of a file is checked for NUL bytes to tell if it's binary
+const BINARY_SNIFF_BYTES: usize = 8000;
+
+/// finds files under `path` whose names match `name_glob` and, given
+/// `content_regex`, which contain a matching line. `path` is relative to
+/// `root` and can't leave it. hidden files and directories and symlinks are
+/// skipped. problems are described in the returned text.
+pub(super) fn search_files(
+    root: &Path,
+    path: &str,
+    name_glob: &str,
+    content_regex: Option<&str>,
+) -> String {
+    let Ok(root) = root.canonicalize() else {
+        return format!("the search root {} doesn't exist", root.display());
+    };
+    let start = match root.join(path).canonicalize() {
+        Ok(start) if start.starts_with(&root) => start,
+        Ok(_) => return format!("{path} is outside the directory you can search"),
+        Err(e) => return format!("can't search {path}: {e}"),
+    };
+    let regex = match content_regex.map(Regex::new).transpose() {
+        Ok(regex) => regex,
+        Err(e) => return format!("invalid content_regex: {e}"),
+    };
+    tracing::info!(path = %start.display(), name_glob, content_regex, "searching files");
+
+    let mut search = Search::default();
+    let mut dirs = vec![start];
+    'walk: while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        // popped last-in first-out, so push in reverse to walk in name order
+        for entry in entries.into_iter().rev() {
+            search.visited += 1;
+            if search.visited > MAX_VISITED_ENTRIES {
+                search.truncated = true;
+                break 'walk;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            // file_type doesn't follow symlinks, so these are skipped too
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() && glob_matches(name_glob, &name) {
+                search.candidates.push(entry.path());
+            }
+        }
+    }
+    search.candidates.sort();
+
+    for file in std::mem::take(&mut search.candidates) {
+        if search.matches.len() == MAX_MATCHED_FILES {
+            search.truncated = true;
+            break;
+        }
+        let relative = file
+            .strip_prefix(&root)
+            .unwrap_or(&file)
+            .display()
+            .to_string();
+        match &regex {
+            None => search.matches.push(relative),
+            Some(regex) => {
+                if let Some(lines) = search.matching_lines(&file, regex) {
+                    search.matches.push(format!("{relative}\n{lines}"));
+                }
+            }
+        }
+    }
+    search.report()
+}
+
+#[derive(Default)]
+struct Search {
+    visited: usize,
+    candidates: Vec<PathBuf>,
+    matches: Vec<String>,
+    skipped: usize,
+    truncated: bool,
+}
+
+impl Search {
+    /// the file's matching lines, numbered and indented. `None` if there
+    /// are none or the file is too big or binary.
+    fn matching_lines(&mut self, file: &Path, regex: &Regex) -> Option<String> {
+        let too_big = std::fs::metadata(file)
+            .map(|metadata| metadata.len() > MAX_SEARCHED_FILE_BYTES)
+            .unwrap_or(true);
+        if too_big {
+            self.skipped += 1;
+            return None;
+        }
+        let bytes = std::fs::read(file).ok()?;
+        let sniffed = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+        let Some(contents) = (!sniffed.contains(&0))
+            .then(|| String::from_utf8(bytes).ok())
+            .flatten()
+        else {
+            self.skipped += 1;
+            return None;
+        };
+
+        let matching: Vec<(usize, &str)> = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| regex.is_match(*line))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        let mut lines: Vec<String> = matching
+            .iter()
+            .take(MAX_LINES_PER_FILE)
+            .map(|(i, line)| {
+                format!(
+                    "  {}: {}",
+                    i + 1,
+                    text::safe_prefix(line.trim(), MAX_LINE_CHARS)
+                )
+            })
+            .collect();
+        if matching.len() > MAX_LINES_PER_FILE {
+            lines.push(format!(
+                "  ({} more matching lines)",
+                matching.len() - MAX_LINES_PER_FILE
+            ));
+        }
+        Some(lines.join("\n"))
+    }
+
+    fn report(self) -> String {
+        let mut output = if self.matches.is_empty() {
+            "no files matched".to_string()
+        } else {
+            self.matches.join("\n")
+        };
+        if self.truncated {
+            output.push_str("\n(stopped early, narrow the path or pattern to see everything)");
+        }
+        if self.skipped > 0 {
+            output.push_str(&format!(
+                "\n(skipped {} binary or larger than {}KB files)",
+                self.skipped,
+                MAX_SEARCHED_FILE_BYTES / 1000
+            ));
+        }
+        output
+    }
+}
+
+/// matches a file name against a glob where `*` is any run of characters
+/// and `?` is exactly one
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was, and how much of the name it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the `*` take one more character and try again
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a throwaway tree: src/main.rs, src/lib.rs, notes.md, a hidden file,
+    /// and a binary file
+    fn temp_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ava-search-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "fn main() {\n    run();\n}\n").unwrap();
+        std::fs::write(root.join("src/lib.rs"), "pub fn run() {}\n").unwrap();
+        std::fs::write(root.join("notes.md"), "remember to run the tests\n").unwrap();
+        std::fs::write(root.join(".git/config.rs"), "fn run() {}\n").unwrap();
+        std::fs::write(root.join("src/blob.rs"), b"fn run\0\xff").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.rs", "main.rs"));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("ma?n.*", "main.rs"));
+        assert!(glob_matches("*test*.rs", "search_tests.rs"));
+        assert!(!glob_matches("*.rs", "main.rs.bak"));
+        assert!(!glob_matches("ma?n.rs", "man.rs"));
+        assert!(!glob_matches("lib.rs", "main.rs"));
+    }
+
+    #[test]
+    fn test_search_files_by_name() {
+        let root = temp_tree("name");
+        let result = search_files(&root, ".", "*.rs", None);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // hidden directories are skipped
+        assert_eq!(result, "src/blob.rs\nsrc/lib.rs\nsrc/main.rs");
+    }
+
+    #[test]
+    fn test_search_files_by_content() {
+        let root = temp_tree("content");
+        let result = search_files(&root, ".", "*", Some(r"\brun\("));
+        let escaped = search_files(&root, "..", "*", None);
+        let bad_regex = search_files(&root, ".", "*", Some("("));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            result,
+            "src/lib.rs\n  1: pub fn run() {}\nsrc/main.rs\n  2: run();\n(skipped 1 binary or larger than 1000KB files)"
+        );
+        assert_eq!(escaped, ".. is outside the directory you can search");
+        assert!(bad_regex.starts_with("invalid content_regex"));
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ApprovalDecision, Approver, ToolCall, ToolContext};
+use crate::config;
+use crate::db::Store;
+use crate::error::Error;
+use crate::message::MessageContent;
+
+/// a tool call and what it returned, one line of a trace file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// unix seconds
+    pub recorded_at: u64,
+    pub context: ToolContext,
+    pub call: ToolCall,
+    pub result: MessageContent,
+}
+
+impl TraceRecord {
+    pub fn new(context: &ToolContext, call: &ToolCall, result: &MessageContent) -> Self {
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        Self {
+            recorded_at,
+            context: context.clone(),
+            call: call.clone(),
+            result: result.clone(),
+        }
+    }
+}
+
+/// appends `record` to the trace file as one JSON line
+pub fn append(path: &Path, record: &TraceRecord) -> Result<(), Error> {
+    let mut line = serde_json::to_string(record).map_err(std::io::Error::from)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // a single write keeps lines from concurrent turns whole
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// reads every record from a trace file, skipping blank lines
+pub fn read(path: &Path) -> Result<Vec<TraceRecord>, Error> {
+    let file = std::fs::File::open(path)?;
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).map_err(std::io::Error::from)?);
+    }
+    Ok(records)
+}
+
+/// how a recorded call fared when run again
+#[derive(Debug)]
+pub enum ReplayOutcome {
+    Same,
+    Changed {
+        recorded: String,
+        replayed: String,
+    },
+    /// the call needed approval and didn't get it
+    Skipped,
+}
+
+/// runs a recorded call again. calls that need approval ask `approver` first.
+pub async fn replay(
+    store: &impl Store,
+    approver: &impl Approver,
+    record: &TraceRecord,
+) -> Result<ReplayOutcome, Error> {
+    if super::requires_approval(&record.call)
+        && approver.request_approval(&record.call).await? == ApprovalDecision::Deny
+    {
+        return Ok(ReplayOutcome::Skipped);
+    }
+
+    let replayed = super::dispatch_tool_call(
+        store,
+        &record.call,
+        &record.context,
+        None,
+        config::safe_mode(),
+    )
+    .await?;
+
+    let recorded = result_text(&record.result);
+    let replayed = result_text(&replayed);
+    if mask_timestamps(recorded) == mask_timestamps(replayed) {
+        Ok(ReplayOutcome::Same)
+    } else {
+        Ok(ReplayOutcome::Changed {
+            recorded: recorded.to_string(),
+            replayed: replayed.to_string(),
+        })
+    }
+}
+
+/// the tool output, leaving out the tool call ID
+fn result_text(result: &MessageContent) -> &str {
+    match result {
+        MessageContent::ToolResult { content, .. } => content,
+        MessageContent::Text { text } => text,
+        MessageContent::ToolUse { .. } | MessageContent::Server(_) => "",
+    }
+}
+
+/// blanks out dates like `2024-06-01` and times like `12:30:05`, which
+/// change from one run to the next
+fn mask_timestamps(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut masked = chars.clone();
+    for start in 0..chars.len() {
+        for shape in ["dddd-dd-dd", "dd:dd"] {
+            if matches_shape(&chars[start..], shape) {
+                for c in &mut masked[start..start + shape.len()] {
+                    if c.is_ascii_digit() {
+                        *c = '#';
+                    }
+                }
+            }
+        }
+    }
+    masked.into_iter().collect()
+}
+
+/// whether `chars` starts with `shape`, where `d` stands for any digit
+fn matches_shape(chars: &[char], shape: &str) -> bool {
+    shape.chars().enumerate().all(|(i, expected)| {
+        chars.get(i).is_some_and(|&c| match expected {
+            'd' => c.is_ascii_digit(),
+            _ => c == expected,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::message::ChannelKind;
+    use crate::tool::{CliApprover, WHOAMI_TOOL_NAME};
+    use serde_json::json;
+
+    fn context() -> ToolContext {
+        ToolContext {
+            channel: ChannelKind::Telegram,
+            chat_id: Some(7),
+            user_id: Some(42),
+            session_id: None,
+            exec_output: None,
+        }
+    }
+
+    #[test]
+    fn test_trace_line_round_trips() {
+        let path = std::env::temp_dir().join(format!("ava-trace-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let call = ToolCall {
+            id: "toolu_1".into(),
+            name: WHOAMI_TOOL_NAME.into(),
+            input: json!({}),
+        };
+        let result = MessageContent::tool_result("toolu_1", "channel: telegram");
+
+        append(&path, &TraceRecord::new(&context(), &call, &result)).unwrap();
+        append(&path, &TraceRecord::new(&context(), &call, &result)).unwrap();
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].context, context());
+        assert_eq!(records[0].call.name, WHOAMI_TOOL_NAME);
+        assert_eq!(result_text(&records[0].result), "channel: telegram");
+        assert!(records[0].recorded_at > 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_ignores_ids_and_flags_drift() {
+        let db = Database::open_in_memory().unwrap();
+        let call = ToolCall {
+            id: "toolu_1".into(),
+            name: WHOAMI_TOOL_NAME.into(),
+            input: json!({}),
+        };
+        let current = json!({
+            "channel": "telegram",
+            "user_id": 42,
+            "chat_id": 7,
+            "preferred_name": null,
+            "fact_category": "user:42"
+        })
+        .to_string();
+
+        let same = TraceRecord::new(
+            &context(),
+            &call,
+            &MessageContent::tool_result("toolu_other", current.as_str()),
+        );
+        let outcome = replay(&db, &CliApprover, &same).await.unwrap();
+        assert!(matches!(outcome, ReplayOutcome::Same));
+
+        let drifted = TraceRecord::new(
+            &context(),
+            &call,
+            &MessageContent::tool_result("toolu_1", "channel: telegram"),
+        );
+        let outcome = replay(&db, &CliApprover, &drifted).await.unwrap();
+        assert!(matches!(
+            outcome,
+            ReplayOutcome::Changed { ref replayed, .. } if *replayed == current
+        ));
+    }
+
+    #[test]
+    fn test_mask_timestamps() {
+        assert_eq!(
+            mask_timestamps("saved at 2024-06-01 12:30:05, id 12"),
+            "saved at ####-##-## ##:##:##, id 12"
+        );
+    }
+}
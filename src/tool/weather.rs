@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::http_client;
+
+const GEOCODING_URL: &str = "https://geocoding-api.open-meteo.com/v1/search";
+const FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+const WEATHER_TIMEOUT_SECS: u64 = 15;
+const FORECAST_DAYS: &str = "3";
+
+#[derive(Debug, Deserialize)]
+struct GeocodingResponse {
+    #[serde(default)]
+    results: Vec<Place>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Place {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    admin1: Option<String>,
+    country: Option<String>,
+}
+
+impl Place {
+    /// e.g. `Portland, Oregon, United States`
+    fn label(&self) -> String {
+        let mut parts = vec![self.name.as_str()];
+        parts.extend(self.admin1.as_deref().filter(|admin| *admin != self.name));
+        parts.extend(self.country.as_deref());
+        parts.join(", ")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ForecastResponse {
+    current: Current,
+    daily: Daily,
+}
+
+#[derive(Debug, Deserialize)]
+struct Current {
+    temperature_2m: f64,
+    apparent_temperature: f64,
+    relative_humidity_2m: f64,
+    weather_code: u8,
+    wind_speed_10m: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Daily {
+    time: Vec<String>,
+    weather_code: Vec<u8>,
+    temperature_2m_max: Vec<f64>,
+    temperature_2m_min: Vec<f64>,
+    precipitation_probability_max: Vec<Option<f64>>,
+}
+
+/// current conditions and a short forecast for a place, from open-meteo, in
+/// the shape of the tool's output schema. `Err` describes what went wrong.
+pub(super) async fn weather(location: &str) -> Result<Value, String> {
+    tracing::info!(location, "getting weather");
+
+    let place = match find_place(location).await {
+        Ok(Some(place)) => place,
+        Ok(None) => return Err(format!("couldn't find a place called {location}")),
+        Err(e) => return Err(format!("weather lookup failed: {e}")),
+    };
+
+    let latitude = place.latitude.to_string();
+    let longitude = place.longitude.to_string();
+    let response = http_client()
+        .get(FORECAST_URL)
+        .query(&[
+            ("latitude", latitude.as_str()),
+            ("longitude", longitude.as_str()),
+            (
+                "current",
+                "temperature_2m,apparent_temperature,relative_humidity_2m,weather_code,wind_speed_10m",
+            ),
+            (
+                "daily",
+                "weather_code,temperature_2m_max,temperature_2m_min,precipitation_probability_max",
+            ),
+            ("timezone", "auto"),
+            ("forecast_days", FORECAST_DAYS),
+        ])
+        .timeout(Duration::from_secs(WEATHER_TIMEOUT_SECS))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let forecast: ForecastResponse = match response {
+        Ok(response) => match response.json().await {
+            Ok(forecast) => forecast,
+            Err(e) => return Err(format!("failed to parse the forecast: {e}")),
+        },
+        Err(e) => return Err(format!("weather lookup failed: {e}")),
+    };
+
+    Ok(forecast_json(&place, &forecast))
+}
+
+/// the geocoder only knows place names, so for `portland, oregon` it falls
+/// back to the name before the comma
+async fn find_place(location: &str) -> Result<Option<Place>, reqwest::Error> {
+    match geocode(location).await? {
+        Some(place) => Ok(Some(place)),
+        None => match location.split_once(',') {
+            Some((name, _)) if !name.trim().is_empty() => geocode(name.trim()).await,
+            _ => Ok(None),
+        },
+    }
+}
+
+async fn geocode(location: &str) -> Result<Option<Place>, reqwest::Error> {
+    let response: GeocodingResponse = http_client()
+        .get(GEOCODING_URL)
+        .query(&[
+            ("name", location),
+            ("count", "1"),
+            ("language", "en"),
+            ("format", "json"),
+        ])
+        .timeout(Duration::from_secs(WEATHER_TIMEOUT_SECS))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.results.into_iter().next())
+}
+
+fn forecast_json(place: &Place, forecast: &ForecastResponse) -> Value {
+    let now = &forecast.current;
+    let daily = &forecast.daily;
+    let days: Vec<Value> = daily
+        .time
+        .iter()
+        .enumerate()
+        .map_while(|(i, date)| {
+            Some(json!({
+                "date": date,
+                "conditions": describe_code(*daily.weather_code.get(i)?),
+                "min_c": daily.temperature_2m_min.get(i)?,
+                "max_c": daily.temperature_2m_max.get(i)?,
+                "precipitation_chance_percent": daily.precipitation_probability_max.get(i).copied().flatten(),
+            }))
+        })
+        .collect();
+
+    json!({
+        "place": place.label(),
+        "current": {
+            "conditions": describe_code(now.weather_code),
+            "temperature_c": now.temperature_2m,
+            "feels_like_c": now.apparent_temperature,
+            "humidity_percent": now.relative_humidity_2m,
+            "wind_kmh": now.wind_speed_10m,
+        },
+        "daily": days,
+    })
+}
+
+/// the WMO weather interpretation codes open-meteo reports
+fn describe_code(code: u8) -> &'static str {
+    match code {
+        0 => "clear sky",
+        1 => "mainly clear",
+        2 => "partly cloudy",
+        3 => "overcast",
+        45 | 48 => "fog",
+        51 | 53 | 55 => "drizzle",
+        56 | 57 => "freezing drizzle",
+        61 => "light rain",
+        63 => "rain",
+        65 => "heavy rain",
+        66 | 67 => "freezing rain",
+        71 => "light snow",
+        73 => "snow",
+        75 => "heavy snow",
+        77 => "snow grains",
+        80..=82 => "rain showers",
+        85 | 86 => "snow showers",
+        95 => "thunderstorm",
+        96 | 99 => "thunderstorm with hail",
+        _ => "unknown conditions",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_FORECAST: &str = r#"{
+        "latitude": 52.52,
+        "longitude": 13.419998,
+        "timezone": "Europe/Berlin",
+        "current_units": {"time": "iso8601", "temperature_2m": "°C"},
+        "current": {
+            "time": "2024-06-01T12:00",
+            "interval": 900,
+            "temperature_2m": 21.3,
+            "apparent_temperature": 20.08,
+            "relative_humidity_2m": 55,
+            "weather_code": 3,
+            "wind_speed_10m": 12.4
+        },
+        "daily_units": {"time": "iso8601", "temperature_2m_max": "°C"},
+        "daily": {
+            "time": ["2024-06-01", "2024-06-02", "2024-06-03"],
+            "weather_code": [3, 61, 0],
+            "temperature_2m_max": [22.5, 18.1, 24.0],
+            "temperature_2m_min": [12.0, 11.4, 13.2],
+            "precipitation_probability_max": [10, 80, null]
+        }
+    }"#;
+
+    #[test]
+    fn test_sample_forecast_as_json() {
+        let forecast: ForecastResponse = serde_json::from_str(SAMPLE_FORECAST).unwrap();
+        let place = Place {
+            name: "Berlin".into(),
+            latitude: 52.52,
+            longitude: 13.41,
+            admin1: Some("Berlin".into()),
+            country: Some("Germany".into()),
+        };
+
+        let result = forecast_json(&place, &forecast);
+
+        assert_eq!(
+            result,
+            json!({
+                "place": "Berlin, Germany",
+                "current": {
+                    "conditions": "overcast",
+                    "temperature_c": 21.3,
+                    "feels_like_c": 20.08,
+                    "humidity_percent": 55.0,
+                    "wind_kmh": 12.4
+                },
+                "daily": [
+                    {"date": "2024-06-01", "conditions": "overcast", "min_c": 12.0, "max_c": 22.5, "precipitation_chance_percent": 10.0},
+                    {"date": "2024-06-02", "conditions": "light rain", "min_c": 11.4, "max_c": 18.1, "precipitation_chance_percent": 80.0},
+                    {"date": "2024-06-03", "conditions": "clear sky", "min_c": 13.2, "max_c": 24.0, "precipitation_chance_percent": null}
+                ]
+            })
+        );
+        assert_eq!(
+            super::super::weather_definition().output_mismatch(&result),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_geocoding_without_results() {
+        // open-meteo leaves out `results` when nothing matches
+        let response: GeocodingResponse =
+            serde_json::from_str(r#"{"generationtime_ms": 0.4}"#).unwrap();
+        assert!(response.results.is_empty());
+
+        let response: GeocodingResponse = serde_json::from_str(
+            r#"{"results": [{"id": 1, "name": "Portland", "latitude": 45.52, "longitude": -122.68,
+                "admin1": "Oregon", "country": "United States"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.results[0].label(),
+            "Portland, Oregon, United States"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use crate::db::Session;
+use crate::message::{Message, MessageContent, Role};
+
+/// renders a session as Markdown, with a section per turn. tool calls and
+/// their results go in code fences under the turn that made them.
+pub fn to_markdown(session: &Session, messages: &[Message]) -> String {
+    let mut output = format!("# session {}\n\n", session.id);
+    match &session.channel {
+        Some(channel) => {
+            output.push_str(&format!("{channel}, started {} UTC\n", session.created_at))
+        }
+        None => output.push_str(&format!("started {} UTC\n", session.created_at)),
+    }
+
+    // results only carry the call's ID, so remember which tool it was
+    let mut tool_names: HashMap<&str, &str> = HashMap::new();
+    for message in messages {
+        // tool results come back as user messages but belong to the
+        // assistant's turn
+        let only_results = !message.content.is_empty()
+            && message
+                .content
+                .iter()
+                .all(|block| matches!(block, MessageContent::ToolResult { .. }));
+        if !only_results {
+            let header = match message.role {
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+            };
+            output.push_str(&format!("\n## {header}\n"));
+        }
+
+        for block in &message.content {
+            match block {
+                MessageContent::Text { text } => {
+                    output.push_str(&format!("\n{}\n", text.trim_end()));
+                }
+                MessageContent::ToolUse { id, name, input } => {
+                    tool_names.insert(id, name);
+                    let input = serde_json::to_string_pretty(input).unwrap_or_default();
+                    output.push_str(&format!("\n**tool call: {name}**\n\n"));
+                    output.push_str(&fenced(&input, "json"));
+                }
+                MessageContent::ToolResult {
+                    tool_use_id,
+                    content,
+                } => {
+                    let name = tool_names.get(tool_use_id.as_str()).unwrap_or(&"tool");
+                    output.push_str(&format!("\n**{name} result**\n\n"));
+                    output.push_str(&fenced(content, ""));
+                }
+                MessageContent::Server(block) => {
+                    let kind = block["type"].as_str().unwrap_or("server block");
+                    let block = serde_json::to_string_pretty(block).unwrap_or_default();
+                    output.push_str(&format!("\n**{kind}**\n\n"));
+                    output.push_str(&fenced(&block, "json"));
+                }
+            }
+        }
+    }
+    output
+}
+
+/// a code block that can't be closed early by backticks in `content`
+fn fenced(content: &str, language: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in content.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{language}\n{}\n{fence}\n", content.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_small_session_to_markdown() {
+        let session = Session {
+            id: 3,
+            channel: Some("cli".into()),
+            created_at: "2024-06-01 09:30:00".into(),
+        };
+        let messages = vec![
+            Message::user("what's in here?"),
+            Message::assistant_with_content(vec![
+                MessageContent::text("let me look."),
+                MessageContent::tool_use("toolu_1", "exec", json!({"command": "ls"})),
+            ]),
+            Message::user_with_content(vec![MessageContent::tool_result(
+                "toolu_1",
+                "exit code: 0\nstdout:\nnotes.md\n```odd```\n",
+            )]),
+            Message::assistant("just notes.md"),
+        ];
+
+        assert_eq!(
+            to_markdown(&session, &messages),
+            "# session 3\n\n\
+             cli, started 2024-06-01 09:30:00 UTC\n\
+             \n## User\n\
+             \nwhat's in here?\n\
+             \n## Assistant\n\
+             \nlet me look.\n\
+             \n**tool call: exec**\n\n\
+             ```json\n{\n  \"command\": \"ls\"\n}\n```\n\
+             \n**exec result**\n\n\
+             ````\nexit code: 0\nstdout:\nnotes.md\n```odd```\n````\n\
+             \n## Assistant\n\
+             \njust notes.md\n"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use serde::Serialize;
+
+use crate::config;
+use crate::db::{FactChange, FactObserver};
+
+/// posts each fact change as JSON to a URL, e.g. to kick off automation
+/// when a calendar fact changes. the post is spawned, so a slow or failing
+/// endpoint never holds up the write.
+pub struct WebhookObserver {
+    client: reqwest::Client,
+    url: String,
+}
+
+/// the body posted for a change. private values are left out.
+#[derive(Debug, PartialEq, Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    category: &'a str,
+    key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+}
+
+impl<'a> From<&'a FactChange> for Payload<'a> {
+    fn from(change: &'a FactChange) -> Self {
+        match change {
+            FactChange::Remembered(fact) => Self {
+                event: "remembered",
+                category: &fact.category,
+                key: &fact.key,
+                value: (!fact.private).then_some(fact.value.as_str()),
+            },
+            FactChange::Forgotten { category, key } => Self {
+                event: "forgotten",
+                category,
+                key,
+                value: None,
+            },
+        }
+    }
+}
+
+impl WebhookObserver {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: crate::http::client(),
+            url,
+        }
+    }
+
+    /// `None` unless AVA_FACT_WEBHOOK is set
+    pub fn from_env() -> Option<Self> {
+        config::fact_webhook().map(Self::new)
+    }
+}
+
+impl FactObserver for WebhookObserver {
+    fn fact_changed(&self, change: &FactChange) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!("no runtime to post the fact webhook on, skipping it");
+            return;
+        };
+        let body = serde_json::to_value(Payload::from(change)).expect("payload serializes");
+        let request = self.client.post(&self.url).json(&body);
+        runtime.spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    tracing::warn!(status = %response.status(), "fact webhook failed");
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(%e, "failed to post fact webhook"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Fact;
+    use serde_json::json;
+
+    #[test]
+    fn test_payload_leaves_out_private_values() {
+        let mut fact = Fact {
+            category: "calendar".into(),
+            key: "dentist".into(),
+            value: "tuesday 10:00".into(),
+            private: false,
+            updated_at: None,
+        };
+        let change = FactChange::Remembered(fact.clone());
+        assert_eq!(
+            serde_json::to_value(Payload::from(&change)).unwrap(),
+            json!({"event": "remembered", "category": "calendar", "key": "dentist", "value": "tuesday 10:00"})
+        );
+
+        fact.private = true;
+        let change = FactChange::Remembered(fact);
+        assert_eq!(Payload::from(&change).value, None);
+
+        let change = FactChange::Forgotten {
+            category: "calendar".into(),
+            key: "dentist".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(Payload::from(&change)).unwrap(),
+            json!({"event": "forgotten", "category": "calendar", "key": "dentist"})
+        );
+    }
+}